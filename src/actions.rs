@@ -1,24 +1,30 @@
 use std::{
+    collections::HashSet,
     fmt::Display,
-    sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc,
-    },
+    sync::Arc,
     time::{Duration, Instant},
 };
 
-use log::{debug, info, warn};
+use futures_util::{
+    future::BoxFuture,
+    stream::{BoxStream, SelectAll},
+    FutureExt, StreamExt,
+};
+use log::{debug, error, info, warn};
 use logind_zbus::{
     manager::{ManagerProxy, SessionInfo},
     session::{SessionClass, SessionProxy, SessionState, SessionType},
 };
 use serde::{Deserialize, Serialize};
-use tokio::time::sleep;
-use zbus::zvariant::Type;
+use tokio::{
+    sync::{broadcast, watch},
+    time::sleep_until,
+};
+use zbus::zvariant::{OwnedObjectPath, OwnedValue, Type, Value};
 use zbus::Connection;
 
 use crate::{
-    config::{check_vulkan_icd, create_modprobe_conf, GfxConfig},
+    config::{check_vulkan_icd, create_modprobe_conf, GfxSettings},
     do_driver_action,
     error::GfxError,
     kill_nvidia_lsof,
@@ -35,14 +41,14 @@ pub enum Action {
     StagedActions(Vec<StagedAction>),
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize, Type)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Type, Value, OwnedValue)]
 /// The action required by the user after they request a supergfx action
 pub enum UserActionRequired {
-    Logout,
-    Reboot,
-    SwitchToIntegrated,
-    AsusEgpuDisable,
-    Nothing,
+    Logout = 0,
+    Reboot = 1,
+    SwitchToIntegrated = 2,
+    AsusEgpuDisable = 3,
+    Nothing = 4,
 }
 
 impl UserActionRequired {
@@ -133,7 +139,7 @@ impl From<&UserActionRequired> for &str {
 
 /// All the possible actions supergfx can perform. These should be chucked in
 /// a vector in the order required to perform them.
-#[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy, Serialize, Deserialize, Type)]
 pub enum StagedAction {
     /// Wait for the user to logout
     WaitLogout,
@@ -167,6 +173,7 @@ pub enum StagedAction {
     UnloadVfioDrivers,
     /// A none-action marker to specify an intent, in this case not using ASUS or hotplug device removal and only dev-tree unbind/remove
     DevTreeManaged,
+    /// Rescan the PCI bus so removed devices are added back
     RescanPci,
     /// Unbind and fully remove the device from a driver using sysfs
     UnbindRemoveGpu,
@@ -197,10 +204,61 @@ pub enum StagedAction {
     None,
 }
 
+impl Display for StagedAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl From<StagedAction> for &str {
+    /// Convert the action to a verbose string
+    fn from(action: StagedAction) -> &'static str {
+        match action {
+            StagedAction::WaitLogout => "Wait for all graphical sessions to log out",
+            StagedAction::StopDisplayManager => "Stop the display manager",
+            StagedAction::StartDisplayManager => "Start the display manager",
+            StagedAction::NoLogind => "Skipped, logind is not used",
+            StagedAction::SendDetachEvent => "Tell applications the dGPU is being removed",
+            StagedAction::LoadGpuDrivers => "Load the dGPU drivers",
+            StagedAction::UnloadGpuDrivers => "Unload the dGPU drivers",
+            StagedAction::KillNvidia => "Kill all processes using the Nvidia device",
+            StagedAction::KillAmd => "Kill all processes using the AMD device",
+            StagedAction::EnableNvidiaPersistenced => "Enable nvidia-persistenced.service",
+            StagedAction::DisableNvidiaPersistenced => "Disable nvidia-persistenced.service",
+            StagedAction::EnableNvidiaPowerd => "Enable nvidia-powerd.service",
+            StagedAction::DisableNvidiaPowerd => "Disable nvidia-powerd.service",
+            StagedAction::LoadVfioDrivers => "Load the vfio drivers",
+            StagedAction::UnloadVfioDrivers => "Unload the vfio drivers",
+            StagedAction::DevTreeManaged => "Skipped, the dGPU is managed by unbind/remove only",
+            StagedAction::RescanPci => "Rescan the PCI bus",
+            StagedAction::UnbindRemoveGpu => "Unbind the dGPU from its driver and remove it",
+            StagedAction::UnbindGpu => "Unbind the dGPU from its driver",
+            StagedAction::HotplugUnplug => "Power off the dGPU hotplug slot",
+            StagedAction::HotplugPlug => "Power on the dGPU hotplug slot",
+            StagedAction::AsusDgpuDisable => "Disable the dGPU with ASUS dgpu_disable",
+            StagedAction::AsusDgpuEnable => "Enable the dGPU with ASUS dgpu_disable",
+            StagedAction::AsusEgpuDisable => "Disable the ASUS eGPU",
+            StagedAction::AsusEgpuEnable => "Enable the ASUS eGPU",
+            StagedAction::AsusMuxIgpu => "Set the ASUS GPU MUX to iGPU",
+            StagedAction::AsusMuxDgpu => "Set the ASUS GPU MUX to dGPU",
+            StagedAction::WriteModprobeConf => "Write the modprobe config for the mode",
+            StagedAction::CheckVulkanIcd => "Check the Nvidia Vulkan ICD for the mode",
+            StagedAction::NotNvidia => "Skipped, the dGPU is not Nvidia",
+            StagedAction::None => "Nothing",
+        }
+    }
+}
+
+impl From<&StagedAction> for &str {
+    fn from(action: &StagedAction) -> &'static str {
+        (*action).into()
+    }
+}
+
 impl StagedAction {
     /// Generate a series of initial mode steps, these are specific to booting the system only, not changing modes
     pub fn action_list_for_boot(
-        config: &GfxConfig,
+        config: &GfxSettings,
         vendor: GfxVendor,
         mode: GfxMode,
     ) -> Vec<StagedAction> {
@@ -298,7 +356,7 @@ impl StagedAction {
     //
     // There might be some redundancy in this list but it is preferred so as to force checking of all conditions for from/to combos
     pub fn action_list_for_switch(
-        config: &GfxConfig,
+        config: &GfxSettings,
         vendor: GfxVendor,
         from: GfxMode,
         to: GfxMode,
@@ -558,59 +616,118 @@ impl StagedAction {
         }
     }
 
+    /// The action that undoes this one when a switch is rolled back, `None` if there is
+    /// nothing to undo or it can't be undone (e.g, killed processes). The inverse is always
+    /// performed with the mode being switched *from*, which is how `WriteModprobeConf` and
+    /// `CheckVulkanIcd` undo themselves.
+    pub fn inverse(self) -> Option<StagedAction> {
+        match self {
+            Self::StopDisplayManager => Some(Self::StartDisplayManager),
+            Self::StartDisplayManager => Some(Self::StopDisplayManager),
+            Self::LoadGpuDrivers => Some(Self::UnloadGpuDrivers),
+            Self::UnloadGpuDrivers => Some(Self::LoadGpuDrivers),
+            Self::EnableNvidiaPersistenced => Some(Self::DisableNvidiaPersistenced),
+            Self::DisableNvidiaPersistenced => Some(Self::EnableNvidiaPersistenced),
+            Self::EnableNvidiaPowerd => Some(Self::DisableNvidiaPowerd),
+            Self::DisableNvidiaPowerd => Some(Self::EnableNvidiaPowerd),
+            Self::LoadVfioDrivers => Some(Self::UnloadVfioDrivers),
+            Self::UnloadVfioDrivers => Some(Self::LoadVfioDrivers),
+            Self::UnbindRemoveGpu => Some(Self::RescanPci),
+            Self::HotplugUnplug => Some(Self::HotplugPlug),
+            Self::HotplugPlug => Some(Self::HotplugUnplug),
+            Self::AsusDgpuDisable => Some(Self::AsusDgpuEnable),
+            Self::AsusDgpuEnable => Some(Self::AsusDgpuDisable),
+            Self::AsusEgpuDisable => Some(Self::AsusEgpuEnable),
+            Self::AsusEgpuEnable => Some(Self::AsusEgpuDisable),
+            Self::AsusMuxIgpu => Some(Self::AsusMuxDgpu),
+            Self::AsusMuxDgpu => Some(Self::AsusMuxIgpu),
+            Self::WriteModprobeConf => Some(Self::WriteModprobeConf),
+            Self::CheckVulkanIcd => Some(Self::CheckVulkanIcd),
+            Self::WaitLogout
+            | Self::NoLogind
+            | Self::SendDetachEvent
+            | Self::KillNvidia
+            | Self::KillAmd
+            | Self::DevTreeManaged
+            | Self::RescanPci
+            | Self::UnbindGpu
+            | Self::NotNvidia
+            | Self::None => None,
+        }
+    }
+
+    /// False for the steps that only wait or mark an intent. A switch can be cancelled
+    /// up until the first step that changes the system.
+    pub fn changes_system(self) -> bool {
+        !matches!(
+            self,
+            Self::WaitLogout | Self::NoLogind | Self::DevTreeManaged | Self::NotNvidia | Self::None
+        )
+    }
+
     /// Do the work required by the action
     pub async fn perform(
         &self,
         changing_to: GfxMode,
         device: &mut DiscreetGpu,
-        loop_exit: Arc<AtomicBool>,
+        ctx: &SwitchContext,
     ) -> Result<(), GfxError> {
         match self {
-            StagedAction::WaitLogout => wait_logout(loop_exit).await,
+            StagedAction::WaitLogout => wait_logout(ctx).await,
             StagedAction::StopDisplayManager => {
-                do_systemd_unit_action(SystemdUnitAction::Stop, DISPLAY_MANAGER)?;
-                wait_systemd_unit_state(SystemdUnitState::Inactive, DISPLAY_MANAGER)
+                do_systemd_unit_action(device.runner(), SystemdUnitAction::Stop, DISPLAY_MANAGER)?;
+                wait_systemd_unit_state(
+                    device.runner(),
+                    SystemdUnitState::Inactive,
+                    DISPLAY_MANAGER,
+                )
             }
             StagedAction::StartDisplayManager => {
-                do_systemd_unit_action(SystemdUnitAction::Start, DISPLAY_MANAGER)
+                do_systemd_unit_action(device.runner(), SystemdUnitAction::Start, DISPLAY_MANAGER)
             }
             StagedAction::SendDetachEvent => device.send_detach_event(),
             StagedAction::LoadGpuDrivers => device.do_driver_action(DriverAction::Load),
             StagedAction::UnloadGpuDrivers => device.do_driver_action(DriverAction::Remove),
-            StagedAction::LoadVfioDrivers => do_driver_action("vfio-pci", DriverAction::Load),
+            StagedAction::LoadVfioDrivers => {
+                do_driver_action(device.runner(), "vfio-pci", DriverAction::Load)
+            }
             StagedAction::UnloadVfioDrivers => {
                 for driver in VFIO_DRIVERS.iter() {
-                    do_driver_action(driver, DriverAction::Remove)?;
+                    do_driver_action(device.runner(), driver, DriverAction::Remove)?;
                 }
                 Ok(())
             }
-            StagedAction::KillNvidia => kill_nvidia_lsof(),
+            StagedAction::KillNvidia => kill_nvidia_lsof(device.paths(), device.runner()),
             StagedAction::KillAmd => {
                 // TODO: do this
                 Ok(())
             }
             StagedAction::EnableNvidiaPersistenced => {
-                toggle_nvidia_persistenced(true, device.vendor())
+                toggle_nvidia_persistenced(device.runner(), true, device.vendor())
             }
             StagedAction::DisableNvidiaPersistenced => {
-                toggle_nvidia_persistenced(false, device.vendor())
+                toggle_nvidia_persistenced(device.runner(), false, device.vendor())
+            }
+            StagedAction::EnableNvidiaPowerd => {
+                toggle_nvidia_powerd(device.runner(), true, device.vendor())
+            }
+            StagedAction::DisableNvidiaPowerd => {
+                toggle_nvidia_powerd(device.runner(), false, device.vendor())
             }
-            StagedAction::EnableNvidiaPowerd => toggle_nvidia_powerd(true, device.vendor()),
-            StagedAction::DisableNvidiaPowerd => toggle_nvidia_powerd(false, device.vendor()),
             StagedAction::RescanPci => rescan_pci(device),
             StagedAction::UnbindRemoveGpu => device.unbind_remove(),
             StagedAction::UnbindGpu => device.unbind(),
             StagedAction::HotplugUnplug => device.set_hotplug(HotplugState::Off),
             StagedAction::HotplugPlug => device.set_hotplug(HotplugState::On),
-            StagedAction::AsusDgpuDisable => asus_dgpu_set_disabled(true),
-            StagedAction::AsusDgpuEnable => asus_dgpu_set_disabled(false),
-            StagedAction::AsusEgpuDisable => asus_egpu_set_enabled(false),
-            StagedAction::AsusEgpuEnable => asus_egpu_set_enabled(true),
-            StagedAction::AsusMuxIgpu => asus_gpu_mux_set_igpu(true),
-            StagedAction::AsusMuxDgpu => asus_gpu_mux_set_igpu(false),
+            StagedAction::AsusDgpuDisable => asus_dgpu_set_disabled(device.paths(), true),
+            StagedAction::AsusDgpuEnable => asus_dgpu_set_disabled(device.paths(), false),
+            StagedAction::AsusEgpuDisable => asus_egpu_set_enabled(device.paths(), false),
+            StagedAction::AsusEgpuEnable => asus_egpu_set_enabled(device.paths(), true),
+            StagedAction::AsusMuxIgpu => asus_gpu_mux_set_igpu(device.paths(), true),
+            StagedAction::AsusMuxDgpu => asus_gpu_mux_set_igpu(device.paths(), false),
             StagedAction::WriteModprobeConf => create_modprobe_conf(changing_to, device),
             StagedAction::CheckVulkanIcd => {
-                check_vulkan_icd(changing_to)
+                check_vulkan_icd(device.paths(), changing_to)
                     .map_err(|e| warn!("Vulkan ICD failed: {e:?}"))
                     .ok();
                 Ok(())
@@ -623,11 +740,208 @@ impl StagedAction {
     }
 }
 
-/// Check if the user has any graphical uiser sessions that are active or online
-async fn graphical_user_sessions_exist(
+/// How the last mode switch ended
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Type)]
+pub enum SwitchOutcome {
+    /// Every step completed and the new mode was saved
+    Committed,
+    /// A step failed and every completed step was undone, the mode is unchanged
+    RolledBack,
+    /// A step failed and undoing the completed steps also failed, the dGPU may be in an unknown state
+    RollbackFailed,
+    /// The switch was cancelled before it changed anything
+    Cancelled,
+    /// No switch has been done since the daemon started
+    #[default]
+    None,
+    /// The daemon stopped in the middle of the switch, the saved mode was set up again
+    /// when it restarted
+    Interrupted,
+}
+
+impl Display for SwitchOutcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl From<SwitchOutcome> for &str {
+    /// Convert the outcome to a verbose string
+    fn from(outcome: SwitchOutcome) -> &'static str {
+        match outcome {
+            SwitchOutcome::Committed => "Mode change completed",
+            SwitchOutcome::RolledBack => "Mode change failed and was rolled back",
+            SwitchOutcome::RollbackFailed => {
+                "Mode change failed and could not be rolled back, a reboot is recommended"
+            }
+            SwitchOutcome::Cancelled => "Mode change was cancelled",
+            SwitchOutcome::None => "No mode change done",
+            SwitchOutcome::Interrupted => {
+                "Mode change was interrupted by the daemon stopping, the previous mode was restored"
+            }
+        }
+    }
+}
+
+/// The state of a single step in a mode switch
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Type)]
+pub enum SwitchStepStatus {
+    Started,
+    Finished,
+    Failed,
+}
+
+/// Emitted by the controller as a mode switch runs
+#[derive(Debug, Clone, PartialEq)]
+pub enum SwitchEvent {
+    /// A step started or ended, `step_index` counts from 0
+    Progress {
+        step_index: u32,
+        total_steps: u32,
+        action: StagedAction,
+        status: SwitchStepStatus,
+    },
+    /// Sent periodically while `WaitLogout` is blocked by these sessions
+    WaitingForLogout { sessions: Vec<BlockingSession> },
+    /// The switch ended. `mode` is the mode now in use, which is the mode switched
+    /// from if the switch failed. `error_text` is empty on success.
+    Result {
+        success: bool,
+        mode: GfxMode,
+        error_text: String,
+        outcome: SwitchOutcome,
+    },
+}
+
+/// A logind session that is blocking a switch until it logs out
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Type)]
+pub struct BlockingSession {
+    /// The logind session id
+    pub id: String,
+    pub user: String,
+    pub seat: String,
+}
+
+impl Display for BlockingSession {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({} on {})", self.id, self.user, self.seat)
+    }
+}
+
+/// Everything the steps of one switch share
+#[derive(Clone)]
+pub struct SwitchContext {
+    /// The config as it was when the switch was requested
+    pub config: GfxSettings,
+    /// Stops a waiting step early, each switch has its own
+    pub stop: SwitchStop,
+    /// Where steps report what they are waiting on
+    pub events: broadcast::Sender<SwitchEvent>,
+    /// Where the sessions blocking the switch are watched from
+    pub sessions: Arc<dyn SessionSource>,
+}
+
+impl SwitchContext {
+    pub fn new(config: GfxSettings, events: broadcast::Sender<SwitchEvent>) -> Self {
+        Self {
+            config,
+            stop: SwitchStop::default(),
+            events,
+            sessions: Arc::new(LogindSessionSource),
+        }
+    }
+}
+
+/// Stops one switch. A step waiting on it is woken as soon as it is stopped.
+#[derive(Debug, Clone)]
+pub struct SwitchStop(Arc<watch::Sender<bool>>);
+
+impl Default for SwitchStop {
+    fn default() -> Self {
+        Self(Arc::new(watch::channel(false).0))
+    }
+}
+
+impl SwitchStop {
+    pub fn stop(&self) {
+        self.0.send_replace(true);
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    /// Resolves once the switch is stopped
+    pub async fn stopped(&self) {
+        let mut rx = self.0.subscribe();
+        // The sender is held by `self` so `changed` can't fail while this waits
+        while !*rx.borrow_and_update() && rx.changed().await.is_ok() {}
+    }
+}
+
+/// Performs the steps of a mode switch, recording each one that completes so that
+/// on failure only those are undone, in reverse order.
+pub struct SwitchTransaction {
+    from: GfxMode,
+    to: GfxMode,
+    completed: Vec<StagedAction>,
+}
+
+impl SwitchTransaction {
+    pub fn new(from: GfxMode, to: GfxMode) -> Self {
+        Self {
+            from,
+            to,
+            completed: Vec::new(),
+        }
+    }
+
+    /// The steps completed so far, in the order they were done
+    pub fn completed(&self) -> &[StagedAction] {
+        &self.completed
+    }
+
+    /// Perform one step of the switch, it is recorded only if it succeeds
+    pub async fn perform(
+        &mut self,
+        action: StagedAction,
+        device: &mut DiscreetGpu,
+        ctx: &SwitchContext,
+    ) -> Result<(), GfxError> {
+        action.perform(self.to, device, ctx).await?;
+        self.completed.push(action);
+        Ok(())
+    }
+
+    /// Undo every completed step in reverse order. Undo keeps going past a failed step
+    /// to restore as much as possible, but the outcome is then `RollbackFailed`.
+    pub async fn rollback(self, device: &mut DiscreetGpu, ctx: &SwitchContext) -> SwitchOutcome {
+        // The switch may have been stopped, undo must not be
+        let ctx = SwitchContext {
+            stop: SwitchStop::default(),
+            ..ctx.clone()
+        };
+        let mut outcome = SwitchOutcome::RolledBack;
+        for action in self.completed.iter().rev() {
+            let Some(inverse) = action.inverse() else {
+                continue;
+            };
+            debug!("Rolling back {action:?} with {inverse:?}");
+            if let Err(e) = inverse.perform(self.from, device, &ctx).await {
+                error!("Rollback of {action:?} failed: {e}");
+                outcome = SwitchOutcome::RollbackFailed;
+            }
+        }
+        outcome
+    }
+}
+
+/// Get the graphical user sessions that are active or online
+async fn graphical_user_sessions(
     connection: &Connection,
     sessions: &[SessionInfo],
-) -> Result<bool, GfxError> {
+) -> Result<Vec<BlockingSession>, GfxError> {
+    let mut blocking = Vec::new();
     for session in sessions {
         // should ignore error such as:
         // Zbus error: org.freedesktop.DBus.Error.UnknownObject: Unknown object '/org/freedesktop/login1/session/c2'
@@ -635,75 +949,196 @@ async fn graphical_user_sessions_exist(
             .path(session.path())?
             .build()
             .await
-            .map_err(|e| warn!("graphical_user_sessions_exist: builder: {e:?}"))
+            .map_err(|e| warn!("graphical_user_sessions: builder: {e:?}"))
         {
             if let Ok(class) = session_proxy.class().await.map_err(|e| {
-                warn!("graphical_user_sessions_exist: class: {e:?}");
+                warn!("graphical_user_sessions: class: {e:?}");
                 e
             }) {
                 if class == SessionClass::User {
-                    if let Ok(type_) = session_proxy.type_().await.map_err(|e| {
-                        warn!("graphical_user_sessions_exist: type_: {e:?}");
-                        e
-                    }) {
-                        match type_ {
-                            SessionType::X11 | SessionType::Wayland | SessionType::MIR => {
-                                if let Ok(state) = session_proxy.state().await.map_err(|e| {
-                                    warn!("graphical_user_sessions_exist: state: {e:?}");
-                                    e
-                                }) {
-                                    match state {
-                                        SessionState::Online | SessionState::Active => {
-                                            return Ok(true)
-                                        }
-                                        SessionState::Closing => {}
-                                    }
+                    if let Ok(SessionType::X11 | SessionType::Wayland | SessionType::MIR) =
+                        session_proxy.type_().await.map_err(|e| {
+                            warn!("graphical_user_sessions: type_: {e:?}");
+                            e
+                        })
+                    {
+                        if let Ok(state) = session_proxy.state().await.map_err(|e| {
+                            warn!("graphical_user_sessions: state: {e:?}");
+                            e
+                        }) {
+                            match state {
+                                SessionState::Online | SessionState::Active => {
+                                    blocking.push(BlockingSession {
+                                        id: session.sid().to_string(),
+                                        user: session.user().to_string(),
+                                        seat: session.seat().to_string(),
+                                    })
                                 }
+                                SessionState::Closing => {}
                             }
-                            _ => {}
                         }
                     }
                 }
             }
         }
     }
-    Ok(false)
+    Ok(blocking)
 }
 
-/// It's async because of inner calls, but is a blocking loop
-// TODO: make it a Future
-async fn wait_logout(loop_exit: Arc<AtomicBool>) -> Result<(), GfxError> {
-    loop_exit.store(false, Ordering::Release);
+/// A source of the sessions blocking a switch, and of notice when they may have changed
+pub trait SessionWatch: Send {
+    /// The sessions blocking the switch right now
+    fn blocking_sessions(&mut self) -> BoxFuture<'_, Result<Vec<BlockingSession>, GfxError>>;
+    /// Resolves when the sessions may have changed, never resolves if they can't change
+    fn changed(&mut self) -> BoxFuture<'_, ()>;
+}
 
-    const SLEEP_PERIOD: Duration = Duration::from_millis(100);
-    let logout_timeout_s = 30;
-    let start_time = Instant::now();
+/// Starts a [`SessionWatch`] for each switch that waits for logout. This is logind on a
+/// real system.
+pub trait SessionSource: Send + Sync {
+    fn watch(&self) -> BoxFuture<'_, Result<Box<dyn SessionWatch>, GfxError>>;
+}
+
+/// Watches the sessions through logind on the system bus
+pub struct LogindSessionSource;
 
-    let connection = Connection::system().await?;
-    let manager = ManagerProxy::new(&connection).await?;
+impl SessionSource for LogindSessionSource {
+    fn watch(&self) -> BoxFuture<'_, Result<Box<dyn SessionWatch>, GfxError>> {
+        async move {
+            let connection = Connection::system().await?;
+            let sessions = LogindSessions::new(connection).await?;
+            Ok(Box::new(sessions) as Box<dyn SessionWatch>)
+        }
+        .boxed()
+    }
+}
+
+/// Watches logind for sessions being added or removed, and for the `State` of each
+/// known session changing. The sessions are only listed again after a change.
+struct LogindSessions {
+    connection: Connection,
+    manager: ManagerProxy<'static>,
+    changes: SelectAll<BoxStream<'static, ()>>,
+    /// Sessions that already have a `State` change subscription
+    watched: HashSet<OwnedObjectPath>,
+}
 
-    while !loop_exit.load(Ordering::Acquire) {
-        let sessions = manager.list_sessions().await?;
+impl LogindSessions {
+    async fn new(connection: Connection) -> Result<Self, GfxError> {
+        let manager = ManagerProxy::new(&connection).await?;
+        // Subscribe before the first listing so nothing is missed in between
+        let mut changes = SelectAll::new();
+        changes.push(manager.receive_session_new().await?.map(|_| ()).boxed());
+        changes.push(manager.receive_session_removed().await?.map(|_| ()).boxed());
+        Ok(Self {
+            connection,
+            manager,
+            changes,
+            watched: HashSet::new(),
+        })
+    }
+}
 
-        if !graphical_user_sessions_exist(&connection, &sessions).await? {
-            break;
+impl SessionWatch for LogindSessions {
+    fn blocking_sessions(&mut self) -> BoxFuture<'_, Result<Vec<BlockingSession>, GfxError>> {
+        async move {
+            let sessions = self.manager.list_sessions().await?;
+            for session in &sessions {
+                if self.watched.contains(session.path()) {
+                    continue;
+                }
+                let proxy = SessionProxy::builder(&self.connection)
+                    .path(session.path().clone())?
+                    .build()
+                    .await?;
+                self.changes
+                    .push(proxy.receive_state_changed().await.map(|_| ()).boxed());
+                self.watched.insert(session.path().clone());
+            }
+            graphical_user_sessions(&self.connection, &sessions).await
         }
+        .boxed()
+    }
 
-        // exit if 3 minutes pass
-        if logout_timeout_s != 0
-            && Instant::now().duration_since(start_time).as_secs() > logout_timeout_s
-        {
-            let detail = format!("Time ({} seconds) for logout exceeded", logout_timeout_s);
-            warn!("mode_change_loop: {}", detail);
-            return Err(GfxError::SystemdUnitWaitTimeout(detail));
+    fn changed(&mut self) -> BoxFuture<'_, ()> {
+        async move {
+            if self.changes.next().await.is_none() {
+                std::future::pending::<()>().await;
+            }
+        }
+        .boxed()
+    }
+}
+
+/// Wait for all graphical sessions to log out, honouring `no_logind` and
+/// `logout_timeout_s` from the switch config
+async fn wait_logout(ctx: &SwitchContext) -> Result<(), GfxError> {
+    if ctx.config.no_logind {
+        debug!("wait_logout: no_logind is set, not waiting");
+        return Ok(());
+    }
+
+    let mut sessions = ctx.sessions.watch().await?;
+    wait_for_sessions(ctx, sessions.as_mut()).await
+}
+
+/// How often to report the sessions still blocking a switch
+const WAITING_NOTIFY_PERIOD: Duration = Duration::from_secs(5);
+
+/// Wait until `watch` has no blocking sessions or the logout timeout passes (0 =
+/// infinite). If the switch is stopped first this returns [`GfxError::SwitchStopped`]. Sessions are only re-evaluated when the watch reports
+/// a change. The sessions still blocking are sent as a `WaitingForLogout` event when
+/// the wait starts and every `WAITING_NOTIFY_PERIOD` after.
+pub(crate) async fn wait_for_sessions(
+    ctx: &SwitchContext,
+    watch: &mut dyn SessionWatch,
+) -> Result<(), GfxError> {
+    let logout_timeout_s = ctx.config.logout_timeout_s;
+    let start_time = Instant::now();
+    let deadline =
+        (logout_timeout_s != 0).then(|| start_time + Duration::from_secs(logout_timeout_s));
+    let mut next_notify = start_time;
+
+    let mut sessions = watch.blocking_sessions().await?;
+    while !sessions.is_empty() {
+        if ctx.stop.is_stopped() {
+            debug!("wait_logout: the switch was stopped");
+            return Err(GfxError::SwitchStopped);
+        }
+
+        let now = Instant::now();
+        if now >= next_notify {
+            let list: Vec<String> = sessions.iter().map(|s| s.to_string()).collect();
+            info!(
+                "wait_logout: still waiting for {} sessions: {}",
+                sessions.len(),
+                list.join(", ")
+            );
+            ctx.events
+                .send(SwitchEvent::WaitingForLogout {
+                    sessions: sessions.clone(),
+                })
+                .ok();
+            next_notify = now + WAITING_NOTIFY_PERIOD;
         }
 
-        // Don't spin at max speed
-        sleep(SLEEP_PERIOD).await;
+        if matches!(deadline, Some(deadline) if now >= deadline) {
+            warn!("wait_logout: time ({logout_timeout_s} seconds) for logout exceeded");
+            return Err(GfxError::LogoutTimeout(logout_timeout_s));
+        }
+
+        let mut wake = next_notify;
+        if let Some(deadline) = deadline {
+            wake = wake.min(deadline);
+        }
+        tokio::select! {
+            _ = watch.changed() => sessions = watch.blocking_sessions().await?,
+            _ = ctx.stop.stopped() => {}
+            _ = sleep_until(wake.into()) => {}
+        }
     }
 
-    loop_exit.store(false, Ordering::Release);
-    debug!("wait_logout: loop exited");
+    debug!("wait_logout: no sessions left");
     Ok(())
 }
 
@@ -722,13 +1157,13 @@ fn rescan_pci(device: &mut DiscreetGpu) -> Result<(), GfxError> {
 
     if do_find_device {
         info!("do_rescan: Device rescan required");
-        match DiscreetGpu::new() {
+        match DiscreetGpu::new(device.paths().clone(), device.runner_arc_clone()) {
             Ok(dev) => *device = dev,
             Err(e) => warn!("do_rescan: tried to reset Unknown dgpu status/devices: {e:?}"),
         }
     } else {
         info!("do_rescan: Rescanning PCI bus");
-        rescan_pci_bus()?; // should force re-attach of driver
+        rescan_pci_bus(device.paths())?; // should force re-attach of driver
     }
 
     Ok(())
@@ -1,20 +1,51 @@
 //! Basic CLI tool to control the `supergfxd` daemon
 
-use std::{env::args, process::Command};
+use serde::Serialize;
+use serde_json::json;
+use std::{
+    env::args,
+    fmt::Display,
+    process::Command,
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
 use supergfxctl::{
-    actions::UserActionRequired, error::GfxError, pci_device::GfxMode,
+    actions::{SwitchOutcome, UserActionRequired},
+    completions::{parse_commands, parse_options, CliSpec, SHELLS},
+    config::CONFIG_KEYS,
+    error::GfxError,
+    history::{format_timestamp, unix_now, HistoryEntry, UNKNOWN_UID},
+    pci_device::GfxMode,
     zbus_proxy::DaemonProxyBlocking,
+    VERSION,
 };
 
+/// How many switches `--history` shows
+const HISTORY_LIMIT: u32 = 20;
+
+/// Exit codes of `--mode X --wait`
+const EXIT_DONE: i32 = 0;
+const EXIT_FAILED: i32 = 1;
+const EXIT_NEEDS_LOGOUT: i32 = 2;
+const EXIT_NEEDS_REBOOT: i32 = 3;
+const EXIT_TIMEOUT: i32 = 4;
+/// How often `--wait` checks the pending mode in case a notification was missed
+const WAIT_POLL_PERIOD: Duration = Duration::from_secs(1);
+
 use gumdrop::Options;
 use zbus::{blocking::Connection, proxy::CacheProperties};
 
-#[derive(Default, Clone, Copy, Options)]
+#[derive(Default, Clone, Options)]
 struct CliStart {
     #[options(help = "print help message")]
     help: bool,
-    #[options(meta = "", help = "Set graphics mode")]
+    #[options(meta = "MODE", help = "Set graphics mode")]
     mode: Option<GfxMode>,
+    #[options(help = "With --mode, print the steps the change would take without doing it")]
+    dry_run: bool,
+    #[options(help = "Cancel the pending mode change if it hasn't started yet")]
+    cancel: bool,
     #[options(help = "Get supergfxd version")]
     version: bool,
     #[options(help = "Get the current mode")]
@@ -29,6 +60,90 @@ struct CliStart {
     pend_action: bool,
     #[options(help = "Get the pending mode change if any")]
     pend_mode: bool,
+    #[options(help = "Show the most recent mode changes")]
+    history: bool,
+    #[options(
+        help = "Print a report of the devices, system and config state to attach to bug reports"
+    )]
+    diagnose: bool,
+    #[options(help = "Print the results of the queries as one JSON object")]
+    json: bool,
+    #[options(help = "Print each power, mode and switch notification until interrupted")]
+    watch: bool,
+    #[options(
+        help = "With --mode, wait for the change to finish. Exits with 0 when done, 1 if it failed, 2 if a logout is needed, 3 if a reboot is needed, 4 on timeout"
+    )]
+    wait: bool,
+    #[options(meta = "SECS", help = "With --wait, give up after this many seconds")]
+    timeout: Option<u64>,
+    #[options(command)]
+    cmd: Option<CliCommand>,
+}
+
+#[derive(Clone, Options)]
+enum CliCommand {
+    #[options(help = "Get or set the daemon config")]
+    Config(ConfigCommand),
+    #[options(help = "Print a completion script for bash, zsh or fish")]
+    Completions(CompletionsCommand),
+    #[options(help = "Print the man page in roff format")]
+    Man(ManCommand),
+}
+
+#[derive(Default, Clone, Options)]
+struct CompletionsCommand {
+    #[options(help = "print help message")]
+    help: bool,
+    #[options(free, required, help = "The shell: bash, zsh or fish")]
+    shell: String,
+}
+
+#[derive(Default, Clone, Options)]
+struct ManCommand {
+    #[options(help = "print help message")]
+    help: bool,
+}
+
+#[derive(Default, Clone, Options)]
+struct ConfigCommand {
+    #[options(help = "print help message")]
+    help: bool,
+    #[options(command)]
+    cmd: Option<ConfigAction>,
+}
+
+#[derive(Clone, Options)]
+enum ConfigAction {
+    #[options(help = "Print the value of a config key, or of all keys")]
+    Get(ConfigGet),
+    #[options(help = "Set a config key, the daemon checks the new config is usable")]
+    Set(ConfigSet),
+    #[options(help = "Print each config value and the file it came from")]
+    Sources(ConfigSources),
+}
+
+#[derive(Default, Clone, Options)]
+struct ConfigGet {
+    #[options(help = "print help message")]
+    help: bool,
+    #[options(free, help = "The config key")]
+    key: Option<String>,
+}
+
+#[derive(Default, Clone, Options)]
+struct ConfigSources {
+    #[options(help = "print help message")]
+    help: bool,
+}
+
+#[derive(Default, Clone, Options)]
+struct ConfigSet {
+    #[options(help = "print help message")]
+    help: bool,
+    #[options(free, required, help = "The config key")]
+    key: String,
+    #[options(free, required, help = "The new value")]
+    value: String,
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -37,13 +152,17 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     match CliStart::parse_args_default(&args) {
         Ok(command) => {
             do_gfx(command).map_err(|err|{
+                if matches!(err, GfxError::UnknownConfigKey(_) | GfxError::InvalidConfigValue(_, _)) {
+                    eprintln!("Error: {}", err);
+                    std::process::exit(1);
+                }
                 eprintln!("Graphics mode change error.");
                 if !check_systemd_unit_enabled("supergfxd") {
                     eprintln!("\x1b[0;31msupergfxd is not enabled, enable it with `systemctl enable supergfxd\x1b[0m");
                 } else if !check_systemd_unit_active("supergfxd") {
                     eprintln!("\x1b[0;31msupergfxd is not running, start it with `systemctl start supergfxd\x1b[0m");
                 } else {
-                    eprintln!("Please check `journalctl -b -u supergfxd`, and `systemctl status supergfxd`");
+                    eprintln!("Please check `journalctl -b -u supergfxd`, and `systemctl status supergfxd`, and attach the output of `supergfxctl --diagnose` to any bug report");
                     if let GfxError::Zbus(zbus::Error::MethodError(_,Some(text),_)) = &err {
                                 eprintln!("\x1b[0;31m{}\x1b[0m", text);
                                 std::process::exit(1);
@@ -64,6 +183,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
 fn do_gfx(command: CliStart) -> Result<(), GfxError> {
     if command.mode.is_none()
+        && !command.cancel
         && !command.get
         && !command.version
         && !command.supported
@@ -71,68 +191,544 @@ fn do_gfx(command: CliStart) -> Result<(), GfxError> {
         && !command.status
         && !command.pend_action
         && !command.pend_mode
+        && !command.history
+        && !command.diagnose
+        && !command.watch
+        && !command.wait
+        && command.timeout.is_none()
+        && command.cmd.is_none()
         || command.help
     {
         println!("{}", command.self_usage());
+        if let Some(commands) = command.self_command_list() {
+            println!("\nCommands:\n{commands}");
+        }
     }
 
-    let proxy = DaemonProxyBlocking::builder(&Connection::system()?)
-        .cache_properties(CacheProperties::No)
-        .build()?;
-
-    if let Some(mode) = command.mode {
-        let res = proxy.set_mode(&mode)?;
-        match res {
-            UserActionRequired::SwitchToIntegrated => {
-                eprintln!("You must change to Integrated before you can change to {mode}",);
-                std::process::exit(1);
+    match &command.cmd {
+        Some(CliCommand::Config(config)) => {
+            if let Some(usage) = config_usage(config) {
+                println!("{usage}");
+                return Ok(());
             }
-            UserActionRequired::Logout => {
-                println!(
-                    "Graphics mode changed to {mode}. Required user action is: {}",
-                    <&str>::from(res)
+        }
+        Some(CliCommand::Completions(completions)) => {
+            if completions.help {
+                println!("{}", completions.self_usage());
+            } else if let Some(script) = cli_spec().completions(&completions.shell) {
+                print!("{script}");
+            } else {
+                eprintln!(
+                    "Unknown shell {}, expected one of: {}",
+                    completions.shell,
+                    SHELLS.join(", ")
                 );
+                std::process::exit(1);
             }
-            UserActionRequired::Nothing => {
-                println!("Graphics mode changed to {mode}");
+            return Ok(());
+        }
+        Some(CliCommand::Man(man)) => {
+            if man.help {
+                println!("{}", man.self_usage());
+            } else {
+                print!("{}", cli_spec().man_page());
             }
+            return Ok(());
+        }
+        None => {}
+    }
+
+    let proxy = DaemonProxyBlocking::builder(&Connection::system()?)
+        .cache_properties(CacheProperties::No)
+        .build()?;
+
+    // Subscribe first so that a switch requested with --mode can be followed
+    let watcher = if command.watch {
+        Some(Watcher::subscribe(&proxy)?)
+    } else {
+        None
+    };
 
-            UserActionRequired::Reboot => {
-                println!("A reboot is required to complete the mode change")
+    if let Some(CliCommand::Config(config)) = &command.cmd {
+        do_config(&proxy, config)?;
+    }
+
+    if command.dry_run {
+        let Some(mode) = command.mode else {
+            eprintln!("--dry-run requires --mode");
+            std::process::exit(1);
+        };
+        let (action, steps) = proxy.plan_mode(&mode)?;
+        println!("Switching to {mode}. Required user action is: {action}");
+        if steps.is_empty() {
+            println!("No steps would be performed: {}", <&str>::from(action));
+        }
+        for (i, step) in steps.iter().enumerate() {
+            println!("{:>3}. {step}: {}", i + 1, <&str>::from(step));
+        }
+    } else if let Some(mode) = command.mode {
+        if command.wait {
+            // Subscribe before the switch starts so the result can't be missed
+            let watcher = Watcher::subscribe(&proxy)?;
+            // A switch with no steps ends as soon as it is requested
+            let staged = !proxy.plan_mode(&mode)?.1.is_empty();
+            let res = proxy.set_mode(&mode)?;
+            let code = wait_for_switch(&proxy, watcher, mode, res, staged, command.timeout)?;
+            if code != EXIT_DONE {
+                std::process::exit(code);
             }
-            UserActionRequired::AsusEgpuDisable => println!("{res:?}"),
+        } else {
+            print_set_mode(mode, proxy.set_mode(&mode)?);
         }
+    } else if command.wait || command.timeout.is_some() {
+        eprintln!("--wait and --timeout require --mode");
+        std::process::exit(1);
     }
 
+    if command.cancel {
+        let res = proxy.cancel_pending_mode()?;
+        println!("Cancelled the change to {res}");
+    }
+
+    let mut report = Report::new(command.json);
     if command.version {
         let res = proxy.version()?;
-        println!("{}", res);
+        report.add("version", &res, &res);
     }
     if command.get {
         let res = proxy.mode()?;
-        println!("{res}");
+        report.add("mode", &res, res);
     }
     if command.supported {
         let res = proxy.supported()?;
-        println!("{:?}", res);
+        report.add("supported", &res, format!("{:?}", res));
     }
     if command.vendor {
         let res = proxy.vendor()?;
-        println!("{}", res);
+        report.add("vendor", &res, &res);
     }
     if command.status {
         let res = proxy.power()?;
-        println!("{}", <&str>::from(&res));
+        report.add("status", &res, <&str>::from(&res));
     }
     if command.pend_action {
         let res = proxy.pending_user_action()?;
-        println!("{}", <&str>::from(&res));
+        report.add("pending_action", &res, <&str>::from(&res));
     }
     if command.pend_mode {
         let res = proxy.pending_mode()?;
-        println!("{res}");
+        report.add("pending_mode", &res, res);
+    }
+    if command.history {
+        let res = proxy.history(HISTORY_LIMIT)?;
+        report.add("history", &res, history_text(&res));
+    }
+    if command.diagnose {
+        let res = proxy.diagnostics()?;
+        report.add("diagnostics", &res, &res);
+    }
+    report.print_json();
+
+    if let Some(watcher) = watcher {
+        watcher.run(command.json);
+    }
+
+    Ok(())
+}
+
+fn print_set_mode(mode: GfxMode, res: UserActionRequired) {
+    match res {
+        UserActionRequired::SwitchToIntegrated => {
+            eprintln!("You must change to Integrated before you can change to {mode}",);
+            std::process::exit(1);
+        }
+        UserActionRequired::Logout => {
+            println!(
+                "Graphics mode changed to {mode}. Required user action is: {}",
+                <&str>::from(res)
+            );
+        }
+        UserActionRequired::Nothing => {
+            println!("Graphics mode changed to {mode}");
+        }
+
+        UserActionRequired::Reboot => {
+            println!("A reboot is required to complete the mode change")
+        }
+        UserActionRequired::AsusEgpuDisable => println!("{res:?}"),
+    }
+}
+
+/// Wait for the switch to `mode` started by `set_mode` to finish, printing the outcome.
+/// `staged` is false if the switch has no steps, so there is no result to wait for.
+/// Returns the exit code for the outcome.
+fn wait_for_switch(
+    proxy: &DaemonProxyBlocking,
+    watcher: Watcher,
+    mode: GfxMode,
+    res: UserActionRequired,
+    staged: bool,
+    timeout: Option<u64>,
+) -> Result<i32, GfxError> {
+    match res {
+        UserActionRequired::SwitchToIntegrated => {
+            eprintln!("You must change to Integrated before you can change to {mode}");
+            return Ok(EXIT_FAILED);
+        }
+        UserActionRequired::AsusEgpuDisable => {
+            eprintln!("{res:?}");
+            return Ok(EXIT_FAILED);
+        }
+        UserActionRequired::Reboot => {
+            println!("A reboot is required to complete the mode change");
+            return Ok(EXIT_NEEDS_REBOOT);
+        }
+        UserActionRequired::Logout | UserActionRequired::Nothing => {}
+    }
+    if !staged {
+        println!("Graphics mode changed to {mode}");
+        return Ok(EXIT_DONE);
     }
 
+    println!("Switching to {mode}");
+    let deadline = timeout.map(|secs| Instant::now() + Duration::from_secs(secs));
+    loop {
+        let period = match deadline {
+            Some(deadline) => {
+                let left = deadline.saturating_duration_since(Instant::now());
+                if left.is_zero() {
+                    eprintln!("Timed out waiting for the change to {mode}");
+                    return Ok(EXIT_TIMEOUT);
+                }
+                left.min(WAIT_POLL_PERIOD)
+            }
+            None => WAIT_POLL_PERIOD,
+        };
+
+        match watcher.rx.recv_timeout(period) {
+            Ok(("result", value, text)) => {
+                if value["success"].as_bool().unwrap_or(false) {
+                    println!("Graphics mode changed to {mode}");
+                    return Ok(EXIT_DONE);
+                }
+                eprintln!("Graphics mode change {text}");
+                return Ok(EXIT_FAILED);
+            }
+            Ok(("waiting_for_logout", _, sessions)) => {
+                println!(
+                    "A logout is required to complete the mode change, waiting on: {sessions}"
+                );
+                return Ok(EXIT_NEEDS_LOGOUT);
+            }
+            Ok(_) => {}
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                // The result may have been sent before the subscription was active
+                if proxy.pending_mode()? == GfxMode::None {
+                    return Ok(match proxy.last_switch_outcome()? {
+                        SwitchOutcome::Committed => {
+                            println!("Graphics mode changed to {mode}");
+                            EXIT_DONE
+                        }
+                        outcome => {
+                            eprintln!("Graphics mode change did not complete: {outcome:?}");
+                            EXIT_FAILED
+                        }
+                    });
+                }
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                eprintln!("Lost the connection to the daemon");
+                return Ok(EXIT_FAILED);
+            }
+        }
+    }
+}
+
+/// Collects query results to print either as text as they come, or as one JSON object
+struct Report {
+    json: Option<serde_json::Map<String, serde_json::Value>>,
+}
+
+impl Report {
+    fn new(json: bool) -> Self {
+        Self {
+            json: json.then(serde_json::Map::new),
+        }
+    }
+
+    fn add(&mut self, key: &str, value: &impl Serialize, text: impl Display) {
+        match &mut self.json {
+            Some(json) => {
+                let value = serde_json::to_value(value).unwrap_or_else(|err| {
+                    eprintln!("Could not convert {key} to JSON: {err}");
+                    std::process::exit(1);
+                });
+                json.insert(key.to_string(), value);
+            }
+            None => println!("{text}"),
+        }
+    }
+
+    fn print_json(self) {
+        match self.json {
+            Some(json) if !json.is_empty() => println!("{}", serde_json::Value::Object(json)),
+            _ => {}
+        }
+    }
+}
+
+/// A notification from the daemon, as the signal name, the args as JSON and as text
+type Notification = (&'static str, serde_json::Value, String);
+
+/// Receives daemon signals on a thread per signal and prints them as they come
+struct Watcher {
+    rx: mpsc::Receiver<Notification>,
+}
+
+impl Watcher {
+    fn subscribe(proxy: &DaemonProxyBlocking<'static>) -> Result<Self, GfxError> {
+        let (tx, rx) = mpsc::channel();
+
+        let power = proxy.receive_notify_gfx_status()?;
+        forward(&tx, power, |s| {
+            let a = s.args()?;
+            Ok((
+                "power",
+                json!(a.status),
+                <&str>::from(&a.status).to_string(),
+            ))
+        });
+        let mode = proxy.receive_notify_gfx()?;
+        forward(&tx, mode, |s| {
+            let a = s.args()?;
+            Ok(("mode", json!(a.mode), a.mode.to_string()))
+        });
+        let action = proxy.receive_notify_action()?;
+        forward(&tx, action, |s| {
+            let a = s.args()?;
+            Ok((
+                "action",
+                json!(a.action),
+                <&str>::from(a.action).to_string(),
+            ))
+        });
+        let progress = proxy.receive_notify_switch_progress()?;
+        forward(&tx, progress, |s| {
+            let a = s.args()?;
+            Ok((
+                "progress",
+                json!({
+                    "step_index": a.step_index,
+                    "total_steps": a.total_steps,
+                    "action": a.action,
+                    "status": a.status,
+                }),
+                format!(
+                    "step {}/{} {} {:?}",
+                    a.step_index + 1,
+                    a.total_steps,
+                    a.action,
+                    a.status
+                ),
+            ))
+        });
+        let waiting = proxy.receive_notify_waiting_for_logout()?;
+        forward(&tx, waiting, |s| {
+            let a = s.args()?;
+            let sessions: Vec<String> = a.sessions.iter().map(|s| s.to_string()).collect();
+            Ok(("waiting_for_logout", json!(a.sessions), sessions.join(", ")))
+        });
+        let cancelled = proxy.receive_notify_switch_cancelled()?;
+        forward(&tx, cancelled, |s| {
+            let a = s.args()?;
+            Ok(("cancelled", json!(a.mode), a.mode.to_string()))
+        });
+        let result = proxy.receive_notify_switch_outcome()?;
+        forward(&tx, result, |s| {
+            let a = s.args()?;
+            let text = if a.success {
+                format!("switched to {}", a.mode)
+            } else {
+                format!("failed, mode is {}: {}", a.mode, a.error_text)
+            };
+            Ok((
+                "result",
+                json!({
+                    "outcome": a.outcome,
+                    "success": a.success,
+                    "mode": a.mode,
+                    "error_text": a.error_text,
+                }),
+                text,
+            ))
+        });
+
+        let config_warning = proxy.receive_notify_config_warning()?;
+        forward(&tx, config_warning, |s| {
+            let a = s.args()?;
+            Ok(("config_warning", json!(a.warning), a.warning.to_string()))
+        });
+
+        Ok(Self { rx })
+    }
+
+    /// Print notifications until interrupted or the daemon connection is lost
+    fn run(self, json: bool) {
+        for (signal, value, text) in self.rx {
+            let timestamp = unix_now();
+            if json {
+                println!(
+                    "{}",
+                    json!({ "timestamp": timestamp, "signal": signal, "value": value })
+                );
+            } else {
+                println!("{} UTC {signal}: {text}", format_timestamp(timestamp));
+            }
+        }
+    }
+}
+
+/// Send each signal from `signals` to `tx` as a [`Notification`] from a new thread
+fn forward<S>(
+    tx: &mpsc::Sender<Notification>,
+    signals: impl Iterator<Item = S> + Send + 'static,
+    convert: impl Fn(S) -> zbus::Result<Notification> + Send + 'static,
+) {
+    let tx = tx.clone();
+    thread::spawn(move || {
+        for signal in signals {
+            match convert(signal) {
+                Ok(notification) => {
+                    if tx.send(notification).is_err() {
+                        break;
+                    }
+                }
+                Err(err) => eprintln!("Bad notification: {err}"),
+            }
+        }
+    });
+}
+
+fn history_text(history: &[HistoryEntry]) -> String {
+    if history.is_empty() {
+        return "No mode changes recorded".to_string();
+    }
+    let mut lines = Vec::new();
+    for entry in history {
+        let uid = if entry.uid == UNKNOWN_UID {
+            "unknown".to_string()
+        } else {
+            entry.uid.to_string()
+        };
+        lines.push(format!(
+            "{} UTC: {} -> {}, requested by uid {uid}, user action {}: {}",
+            format_timestamp(entry.timestamp),
+            entry.from,
+            entry.to,
+            entry.user_action,
+            entry.outcome
+        ));
+        for step in &entry.steps {
+            let result = if step.success {
+                "ok"
+            } else {
+                step.error.as_str()
+            };
+            lines.push(format!(
+                "    {} ({}ms): {result}",
+                step.action, step.duration_ms
+            ));
+        }
+    }
+    lines.join("\n")
+}
+
+/// The options and commands of the CLI, as generated by gumdrop, for the completion
+/// scripts and man page
+fn cli_spec() -> CliSpec {
+    let mut commands = parse_commands(CliStart::command_list().unwrap_or_default());
+    for command in &mut commands {
+        match command.name.as_str() {
+            "config" => {
+                command.commands =
+                    parse_commands(ConfigCommand::command_list().unwrap_or_default());
+                for action in &mut command.commands {
+                    if matches!(action.name.as_str(), "get" | "set") {
+                        action.values = CONFIG_KEYS.iter().map(|k| k.to_string()).collect();
+                    }
+                }
+            }
+            "completions" => command.values = SHELLS.iter().map(|s| s.to_string()).collect(),
+            _ => {}
+        }
+    }
+
+    CliSpec {
+        name: "supergfxctl".to_string(),
+        version: VERSION.to_string(),
+        about: "control the graphics mode of laptops with a discreet GPU".to_string(),
+        options: parse_options(CliStart::usage()),
+        commands,
+        mode_option: "mode".to_string(),
+        mode_query: "--supported".to_string(),
+        fallback_modes: GfxMode::ALL.iter().map(|m| m.to_string()).collect(),
+        sections: vec![(
+            "Exit status".to_string(),
+            format!(
+                "0 on success, 1 on failure.\n\nWith --wait: {EXIT_DONE} when the mode change is done, \
+                {EXIT_FAILED} if it failed, {EXIT_NEEDS_LOGOUT} if a logout is needed, \
+                {EXIT_NEEDS_REBOOT} if a reboot is needed, {EXIT_TIMEOUT} if the timeout passed."
+            ),
+        )],
+    }
+}
+
+/// The help for `config`, if it was asked for or no action was given
+fn config_usage(command: &ConfigCommand) -> Option<String> {
+    match &command.cmd {
+        Some(ConfigAction::Get(get)) if get.help => Some(get.self_usage().to_string()),
+        Some(ConfigAction::Set(set)) if set.help => Some(set.self_usage().to_string()),
+        Some(ConfigAction::Sources(sources)) if sources.help => {
+            Some(sources.self_usage().to_string())
+        }
+        Some(_) if !command.help => None,
+        _ => {
+            let mut usage = command.self_usage().to_string();
+            if let Some(commands) = command.self_command_list() {
+                usage.push_str(&format!("\n\nCommands:\n{commands}"));
+            }
+            usage.push_str(&format!("\n\nKeys: {}", CONFIG_KEYS.join(", ")));
+            Some(usage)
+        }
+    }
+}
+
+fn do_config(proxy: &DaemonProxyBlocking, command: &ConfigCommand) -> Result<(), GfxError> {
+    match &command.cmd {
+        Some(ConfigAction::Get(get)) => {
+            let config = proxy.config()?;
+            match &get.key {
+                Some(key) => println!("{}", config.get(key)?),
+                None => {
+                    for key in CONFIG_KEYS {
+                        println!("{key}: {}", config.get(key)?);
+                    }
+                }
+            }
+        }
+        Some(ConfigAction::Set(set)) => {
+            let mut config = proxy.config()?;
+            config.set(&set.key, &set.value)?;
+            proxy.set_config(&config)?;
+            println!("{} set to {}", set.key, config.get(&set.key)?);
+        }
+        Some(ConfigAction::Sources(_)) => {
+            for source in proxy.config_sources()? {
+                println!("{}: {} ({})", source.key, source.value, source.origin);
+            }
+        }
+        None => {}
+    }
     Ok(())
 }
 
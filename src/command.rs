@@ -0,0 +1,119 @@
+use std::{process::Command, sync::Mutex};
+
+use log::trace;
+
+/// The result of a finished command
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CommandOutput {
+    pub success: bool,
+    /// Exit code, `None` if the process was killed by a signal
+    pub code: Option<i32>,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+}
+
+impl CommandOutput {
+    /// A successful command with the given stdout
+    pub fn ok(stdout: &str) -> Self {
+        Self {
+            success: true,
+            code: Some(0),
+            stdout: stdout.as_bytes().to_vec(),
+            stderr: Vec::new(),
+        }
+    }
+
+    /// A failed command with the given stderr
+    pub fn failed(code: i32, stderr: &str) -> Self {
+        Self {
+            success: false,
+            code: Some(code),
+            stdout: Vec::new(),
+            stderr: stderr.as_bytes().to_vec(),
+        }
+    }
+}
+
+/// Everything supergfxd spawns (modprobe, rmmod, systemctl, lsof, kill, lspci) goes
+/// through this so that the switching path can be tested without touching the system.
+pub trait CommandRunner: Send + Sync {
+    /// Run `program` with `args` and wait for it to exit
+    fn run(&self, program: &str, args: &[&str]) -> std::io::Result<CommandOutput>;
+}
+
+/// Format a command for logs and errors
+pub fn command_line(program: &str, args: &[&str]) -> String {
+    let mut line = program.to_string();
+    for arg in args {
+        line.push(' ');
+        line.push_str(arg);
+    }
+    line
+}
+
+/// Runs commands on the real system with `std::process::Command`
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemCommandRunner;
+
+impl CommandRunner for SystemCommandRunner {
+    fn run(&self, program: &str, args: &[&str]) -> std::io::Result<CommandOutput> {
+        trace!("run: {}", command_line(program, args));
+        let output = Command::new(program).args(args).output()?;
+        Ok(CommandOutput {
+            success: output.status.success(),
+            code: output.status.code(),
+            stdout: output.stdout,
+            stderr: output.stderr,
+        })
+    }
+}
+
+/// A fake runner which records every command line and replies with scripted output.
+/// Commands with no scripted reply succeed with empty output.
+#[derive(Debug, Default)]
+pub struct RecordingCommandRunner {
+    calls: Mutex<Vec<String>>,
+    /// Command line prefix and the output to give for it, the last match wins
+    replies: Mutex<Vec<(String, CommandOutput)>>,
+}
+
+impl RecordingCommandRunner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reply with `output` for any command line starting with `prefix`, e.g `"modprobe vfio-pci"`
+    pub fn reply(&self, prefix: &str, output: CommandOutput) {
+        self.replies
+            .lock()
+            .unwrap()
+            .push((prefix.to_string(), output));
+    }
+
+    /// All command lines run so far, in order
+    pub fn calls(&self) -> Vec<String> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    pub fn clear_calls(&self) {
+        self.calls.lock().unwrap().clear();
+    }
+}
+
+impl CommandRunner for RecordingCommandRunner {
+    fn run(&self, program: &str, args: &[&str]) -> std::io::Result<CommandOutput> {
+        let line = command_line(program, args);
+        trace!("run (recorded): {line}");
+        self.calls.lock().unwrap().push(line.clone());
+        let output = self
+            .replies
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .find(|(prefix, _)| line.starts_with(prefix))
+            .map(|(_, output)| output.clone())
+            .unwrap_or_else(|| CommandOutput::ok(""));
+        Ok(output)
+    }
+}
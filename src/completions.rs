@@ -0,0 +1,559 @@
+use std::fmt::Write;
+
+/// The shells completion scripts can be generated for
+pub const SHELLS: [&str; 3] = ["bash", "zsh", "fish"];
+
+/// A command line option as listed in a gumdrop usage text
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CliOption {
+    pub short: Option<char>,
+    pub long: Option<String>,
+    /// The name of the value the option takes, if any
+    pub meta: Option<String>,
+    pub help: String,
+}
+
+/// A subcommand, with its own subcommands or the values of its first free argument
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CliCommand {
+    pub name: String,
+    pub help: String,
+    pub values: Vec<String>,
+    pub commands: Vec<CliCommand>,
+}
+
+/// Everything needed to generate completions and a man page for a program
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CliSpec {
+    pub name: String,
+    pub version: String,
+    /// One line description for the man page
+    pub about: String,
+    pub options: Vec<CliOption>,
+    pub commands: Vec<CliCommand>,
+    /// The option whose values are completed from the output of `<name> <mode_query>`
+    pub mode_option: String,
+    /// An option which prints the supported modes as `[Mode, Mode]`
+    pub mode_query: String,
+    /// Completed if `mode_query` fails, e.g if the daemon isn't running
+    pub fallback_modes: Vec<String>,
+    /// Extra man page sections as `(title, text)`
+    pub sections: Vec<(String, String)>,
+}
+
+/// Split a usage line in to the part before the first run of two or more spaces, and the
+/// help text after it
+fn split_help(line: &str) -> (&str, &str) {
+    match line.find("  ") {
+        Some(idx) => (&line[..idx], line[idx..].trim()),
+        None => (line, ""),
+    }
+}
+
+/// Parse the options of a gumdrop usage text, e.g `  -m, --mode MODE  Set graphics mode`
+pub fn parse_options(usage: &str) -> Vec<CliOption> {
+    let mut options = Vec::new();
+    for line in usage.lines().map(str::trim) {
+        if !line.starts_with('-') {
+            continue;
+        }
+        let (spec, help) = split_help(line);
+        let mut option = CliOption {
+            short: None,
+            long: None,
+            meta: None,
+            help: help.to_string(),
+        };
+        for word in spec.split([',', ' ']).filter(|w| !w.is_empty()) {
+            if let Some(long) = word.strip_prefix("--") {
+                option.long = Some(long.to_string());
+            } else if let Some(short) = word.strip_prefix('-') {
+                option.short = short.chars().next();
+            } else {
+                option.meta = Some(word.to_string());
+            }
+        }
+        options.push(option);
+    }
+    options
+}
+
+/// Parse a gumdrop command list, e.g `  config  Get or set the daemon config`
+pub fn parse_commands(command_list: &str) -> Vec<CliCommand> {
+    command_list
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let (name, help) = split_help(line);
+            CliCommand {
+                name: name.to_string(),
+                help: help.to_string(),
+                ..Default::default()
+            }
+        })
+        .collect()
+}
+
+impl CliOption {
+    /// Every way to write the option, e.g `-m` and `--mode`
+    fn flags(&self) -> Vec<String> {
+        let mut flags = Vec::new();
+        if let Some(short) = self.short {
+            flags.push(format!("-{short}"));
+        }
+        if let Some(long) = &self.long {
+            flags.push(format!("--{long}"));
+        }
+        flags
+    }
+}
+
+impl CliSpec {
+    /// Generate the completion script for `shell`, one of [`SHELLS`]
+    pub fn completions(&self, shell: &str) -> Option<String> {
+        match shell {
+            "bash" => Some(self.bash()),
+            "zsh" => Some(self.zsh()),
+            "fish" => Some(self.fish()),
+            _ => None,
+        }
+    }
+
+    fn function_name(&self) -> String {
+        format!("_{}", self.name.replace('-', "_"))
+    }
+
+    /// Shell code printing the supported modes separated by spaces
+    fn mode_query_command(&self) -> String {
+        format!(
+            "{} {} 2>/dev/null | tr -d '[],'",
+            self.name, self.mode_query
+        )
+    }
+
+    fn is_mode_option(&self, option: &CliOption) -> bool {
+        option.long.as_deref() == Some(self.mode_option.as_str())
+    }
+
+    pub fn bash(&self) -> String {
+        let func = self.function_name();
+        let mut out = String::new();
+        let fallback = self.fallback_modes.join(" ");
+        let value_flags: Vec<String> = self
+            .options
+            .iter()
+            .filter(|o| o.meta.is_some())
+            .flat_map(CliOption::flags)
+            .collect();
+        let mode_flags: Vec<String> = self
+            .options
+            .iter()
+            .filter(|o| self.is_mode_option(o))
+            .flat_map(CliOption::flags)
+            .collect();
+
+        writeln!(out, "# bash completion for {}", self.name).ok();
+        writeln!(out, "{func}_modes() {{").ok();
+        writeln!(out, "    local modes").ok();
+        writeln!(
+            out,
+            "    modes=\"$({})\" && [ -n \"$modes\" ] || modes=\"{fallback}\"",
+            self.mode_query_command()
+        )
+        .ok();
+        writeln!(out, "    echo \"$modes\"").ok();
+        writeln!(out, "}}\n").ok();
+
+        writeln!(out, "{func}() {{").ok();
+        writeln!(out, "    local cur prev path i").ok();
+        writeln!(out, "    cur=\"${{COMP_WORDS[COMP_CWORD]}}\"").ok();
+        writeln!(out, "    prev=\"${{COMP_WORDS[COMP_CWORD-1]}}\"").ok();
+        if !value_flags.is_empty() {
+            writeln!(out, "    case \"$prev\" in").ok();
+            if !mode_flags.is_empty() {
+                writeln!(out, "        {})", mode_flags.join("|")).ok();
+                writeln!(
+                    out,
+                    "            COMPREPLY=($(compgen -W \"$({func}_modes)\" -- \"$cur\"))"
+                )
+                .ok();
+                writeln!(out, "            return ;;").ok();
+            }
+            let other_flags: Vec<&String> = value_flags
+                .iter()
+                .filter(|f| !mode_flags.contains(f))
+                .collect();
+            if !other_flags.is_empty() {
+                writeln!(
+                    out,
+                    "        {})",
+                    other_flags
+                        .iter()
+                        .map(|f| f.as_str())
+                        .collect::<Vec<_>>()
+                        .join("|")
+                )
+                .ok();
+                writeln!(out, "            return ;;").ok();
+            }
+            writeln!(out, "    esac").ok();
+        }
+
+        // The subcommand path is every free word before the cursor
+        writeln!(out, "    path=\"\"").ok();
+        writeln!(out, "    for ((i = 1; i < COMP_CWORD; i++)); do").ok();
+        writeln!(out, "        case \"${{COMP_WORDS[i]}}\" in").ok();
+        if !value_flags.is_empty() {
+            writeln!(out, "            {}) ((i++)) ;;", value_flags.join("|")).ok();
+        }
+        writeln!(out, "            -*) ;;").ok();
+        writeln!(out, "            *) path=\"$path ${{COMP_WORDS[i]}}\" ;;").ok();
+        writeln!(out, "        esac").ok();
+        writeln!(out, "    done\n").ok();
+
+        writeln!(out, "    case \"$path\" in").ok();
+        let mut top: Vec<String> = self.options.iter().flat_map(CliOption::flags).collect();
+        top.extend(self.commands.iter().map(|c| c.name.clone()));
+        writeln!(out, "        \"\")").ok();
+        writeln!(
+            out,
+            "            COMPREPLY=($(compgen -W \"{}\" -- \"$cur\")) ;;",
+            top.join(" ")
+        )
+        .ok();
+        fn bash_commands(out: &mut String, path: &str, commands: &[CliCommand]) {
+            for command in commands {
+                let path = format!("{path} {}", command.name);
+                let mut words = vec!["--help".to_string()];
+                words.extend(command.commands.iter().map(|c| c.name.clone()));
+                words.extend(command.values.iter().cloned());
+                writeln!(out, "        \"{path}\")").ok();
+                writeln!(
+                    out,
+                    "            COMPREPLY=($(compgen -W \"{}\" -- \"$cur\")) ;;",
+                    words.join(" ")
+                )
+                .ok();
+                bash_commands(out, &path, &command.commands);
+            }
+        }
+        bash_commands(&mut out, "", &self.commands);
+        writeln!(out, "    esac").ok();
+        writeln!(out, "}}\n").ok();
+        writeln!(out, "complete -F {func} {}", self.name).ok();
+        out
+    }
+
+    pub fn zsh(&self) -> String {
+        let func = self.function_name();
+        let mut out = String::new();
+        writeln!(out, "#compdef {}\n", self.name).ok();
+        writeln!(out, "{func}_modes() {{").ok();
+        writeln!(out, "    local -a modes").ok();
+        writeln!(
+            out,
+            "    modes=(${{=\"$({})\"}})",
+            self.mode_query_command()
+        )
+        .ok();
+        writeln!(
+            out,
+            "    (( ${{#modes}} )) || modes=({})",
+            self.fallback_modes.join(" ")
+        )
+        .ok();
+        writeln!(out, "    compadd -a modes").ok();
+        writeln!(out, "}}\n").ok();
+
+        let mut args: Vec<String> = self
+            .options
+            .iter()
+            .map(|o| {
+                let value = match &o.meta {
+                    Some(meta) if self.is_mode_option(o) => format!(":{meta}:{func}_modes"),
+                    Some(meta) => format!(":{meta}: "),
+                    None => String::new(),
+                };
+                zsh_option(o, &value)
+            })
+            .collect();
+        zsh_function(&mut out, &func, &mut args, &self.commands, &[]);
+        writeln!(out, "{func} \"$@\"").ok();
+        out
+    }
+
+    pub fn fish(&self) -> String {
+        let name = &self.name;
+        let mut out = String::new();
+        writeln!(out, "# fish completion for {name}").ok();
+        writeln!(out, "function __{}_modes", name.replace('-', "_")).ok();
+        writeln!(
+            out,
+            "    set -l modes (string split -n ' ' ({}))",
+            self.mode_query_command()
+        )
+        .ok();
+        writeln!(
+            out,
+            "    test -n \"$modes\"; or set modes {}",
+            self.fallback_modes.join(" ")
+        )
+        .ok();
+        writeln!(out, "    printf '%s\\n' $modes").ok();
+        writeln!(out, "end\n").ok();
+        writeln!(out, "complete -c {name} -f").ok();
+
+        let top = "__fish_use_subcommand";
+        for option in &self.options {
+            let mut line = format!("complete -c {name} -n {top}");
+            if let Some(short) = option.short {
+                write!(line, " -s {short}").ok();
+            }
+            if let Some(long) = &option.long {
+                write!(line, " -l {long}").ok();
+            }
+            if option.meta.is_some() {
+                line.push_str(" -r");
+                if self.is_mode_option(option) {
+                    write!(line, " -a '(__{}_modes)'", name.replace('-', "_")).ok();
+                }
+            }
+            write!(line, " -d {}", fish_quote(&option.help)).ok();
+            writeln!(out, "{line}").ok();
+        }
+
+        fn fish_commands(out: &mut String, name: &str, parents: &[&str], commands: &[CliCommand]) {
+            let names: Vec<&str> = commands.iter().map(|c| c.name.as_str()).collect();
+            let condition = if parents.is_empty() {
+                "__fish_use_subcommand".to_string()
+            } else {
+                let mut condition: Vec<String> = parents
+                    .iter()
+                    .map(|p| format!("__fish_seen_subcommand_from {p}"))
+                    .collect();
+                condition.push(format!(
+                    "not __fish_seen_subcommand_from {}",
+                    names.join(" ")
+                ));
+                condition.join("; and ")
+            };
+            for command in commands {
+                writeln!(
+                    out,
+                    "complete -c {name} -n '{condition}' -a {} -d {}",
+                    command.name,
+                    fish_quote(&command.help)
+                )
+                .ok();
+                let mut path = parents.to_vec();
+                path.push(&command.name);
+                let seen: Vec<String> = path
+                    .iter()
+                    .map(|p| format!("__fish_seen_subcommand_from {p}"))
+                    .collect();
+                writeln!(
+                    out,
+                    "complete -c {name} -n '{}' -s h -l help -d 'print help message'",
+                    seen.join("; and ")
+                )
+                .ok();
+                if !command.values.is_empty() {
+                    writeln!(
+                        out,
+                        "complete -c {name} -n '{}; and not __fish_seen_subcommand_from {}' -a '{}'",
+                        seen.join("; and "),
+                        command.values.join(" "),
+                        command.values.join(" ")
+                    )
+                    .ok();
+                }
+                fish_commands(out, name, &path, &command.commands);
+            }
+        }
+        fish_commands(&mut out, name, &[], &self.commands);
+        out
+    }
+
+    /// Generate a man page in roff format
+    pub fn man_page(&self) -> String {
+        let mut out = String::new();
+        writeln!(
+            out,
+            ".TH {} 1 \"\" \"{} {}\" \"User Commands\"",
+            self.name.to_uppercase(),
+            self.name,
+            self.version
+        )
+        .ok();
+        writeln!(out, ".SH NAME").ok();
+        writeln!(out, "{} \\- {}", self.name, roff_escape(&self.about)).ok();
+        writeln!(out, ".SH SYNOPSIS").ok();
+        writeln!(out, ".B {}", self.name).ok();
+        writeln!(out, "[\\fIOPTIONS\\fR] [\\fICOMMAND\\fR]").ok();
+
+        writeln!(out, ".SH OPTIONS").ok();
+        for option in &self.options {
+            let mut flags: Vec<String> = option
+                .flags()
+                .iter()
+                .map(|f| format!("\\fB{}\\fR", roff_escape(f)))
+                .collect::<Vec<_>>();
+            if let Some(meta) = &option.meta {
+                let last = flags.pop().unwrap_or_default();
+                flags.push(format!("{last} \\fI{}\\fR", roff_escape(meta)));
+            }
+            writeln!(out, ".TP\n{}", flags.join(", ")).ok();
+            writeln!(out, "{}", roff_escape(&option.help)).ok();
+        }
+
+        if !self.commands.is_empty() {
+            writeln!(out, ".SH COMMANDS").ok();
+            fn man_commands(out: &mut String, path: &str, commands: &[CliCommand]) {
+                for command in commands {
+                    let path = format!("{path}{} ", command.name);
+                    let mut synopsis = format!("\\fB{}\\fR", roff_escape(path.trim_end()));
+                    if !command.values.is_empty() {
+                        write!(synopsis, " <\\fI{}\\fR>", command.values.join("|")).ok();
+                    }
+                    writeln!(out, ".TP\n{synopsis}").ok();
+                    writeln!(out, "{}", roff_escape(&command.help)).ok();
+                    man_commands(out, &path, &command.commands);
+                }
+            }
+            man_commands(&mut out, "", &self.commands);
+        }
+
+        for (title, text) in &self.sections {
+            writeln!(out, ".SH {}", title.to_uppercase()).ok();
+            let paragraphs: Vec<String> =
+                text.split("\n\n").map(|p| roff_escape(p.trim())).collect();
+            writeln!(out, "{}", paragraphs.join("\n.PP\n")).ok();
+        }
+        out
+    }
+}
+
+/// The `_arguments` spec for an option, `value` is the `:message:action` part if any
+fn zsh_option(option: &CliOption, value: &str) -> String {
+    let help = zsh_escape(&option.help);
+    let flags = option.flags();
+    if flags.len() > 1 {
+        format!(
+            "'({})'{{{}}}'[{help}]{value}'",
+            flags.join(" "),
+            flags.join(",")
+        )
+    } else {
+        format!("'{}[{help}]{value}'", flags.join(""))
+    }
+}
+
+/// Write a zsh function completing `args` and then `commands`, and a function for each
+/// of the commands
+fn zsh_function(
+    out: &mut String,
+    func: &str,
+    args: &mut Vec<String>,
+    commands: &[CliCommand],
+    values: &[String],
+) {
+    writeln!(out, "{func}() {{").ok();
+    if commands.is_empty() {
+        if !values.is_empty() {
+            args.push(format!("'1:value:({})'", values.join(" ")));
+        }
+        writeln!(
+            out,
+            "    _arguments \\\n        {}",
+            args.join(" \\\n        ")
+        )
+        .ok();
+        writeln!(out, "}}\n").ok();
+        return;
+    }
+
+    args.push("'1: :->command'".to_string());
+    args.push("'*:: :->args'".to_string());
+    writeln!(out, "    local curcontext=\"$curcontext\" state line").ok();
+    writeln!(
+        out,
+        "    _arguments -C \\\n        {}",
+        args.join(" \\\n        ")
+    )
+    .ok();
+    writeln!(out, "    case $state in").ok();
+    writeln!(out, "        command)").ok();
+    writeln!(out, "            local -a commands").ok();
+    writeln!(out, "            commands=(").ok();
+    for command in commands {
+        writeln!(
+            out,
+            "                '{}:{}'",
+            command.name,
+            zsh_escape(&command.help)
+        )
+        .ok();
+    }
+    writeln!(out, "            )").ok();
+    writeln!(
+        out,
+        "            _describe -t commands 'command' commands ;;"
+    )
+    .ok();
+    writeln!(out, "        args)").ok();
+    writeln!(out, "            case $line[1] in").ok();
+    for command in commands {
+        writeln!(
+            out,
+            "                {}) {func}_{} ;;",
+            command.name, command.name
+        )
+        .ok();
+    }
+    writeln!(out, "            esac ;;").ok();
+    writeln!(out, "    esac").ok();
+    writeln!(out, "}}\n").ok();
+
+    for command in commands {
+        let help = CliOption {
+            short: Some('h'),
+            long: Some("help".to_string()),
+            meta: None,
+            help: "print help message".to_string(),
+        };
+        let mut args = vec![zsh_option(&help, "")];
+        zsh_function(
+            out,
+            &format!("{func}_{}", command.name),
+            &mut args,
+            &command.commands,
+            &command.values,
+        );
+    }
+}
+
+fn zsh_escape(text: &str) -> String {
+    text.replace('\'', "'\\''")
+        .replace('[', "\\[")
+        .replace(']', "\\]")
+        .replace(':', "\\:")
+}
+
+fn fish_quote(text: &str) -> String {
+    format!("'{}'", text.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+fn roff_escape(text: &str) -> String {
+    let text = text.replace('\\', "\\e").replace('-', "\\-");
+    text.lines()
+        .map(|line| {
+            if line.starts_with('.') || line.starts_with('\'') {
+                format!("\\&{line}")
+            } else {
+                line.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
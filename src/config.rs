@@ -1,20 +1,23 @@
-use log::{error, info, warn};
+use log::{info, warn};
 use serde_derive::{Deserialize, Serialize};
-use std::fs::{File, OpenOptions};
-use std::io::{Read, Write};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
 use zbus::zvariant::Type;
 
-use crate::actions::UserActionRequired;
-use crate::config_old::{GfxConfig300, GfxConfig405, GfxConfig500};
+use crate::config_old::migrate_config;
 use crate::error::GfxError;
+use crate::host_paths::HostPaths;
 use crate::pci_device::{DiscreetGpu, GfxMode, HotplugType};
 use crate::{
-    CONFIG_NVIDIA_VKICD, MODPROBE_INTEGRATED, MODPROBE_NVIDIA_BASE, MODPROBE_NVIDIA_DRM_MODESET_ON,
-    MODPROBE_NVIDIA_EC_BKLT, MODPROBE_PATH, MODPROBE_VFIO,
+    MODPROBE_INTEGRATED, MODPROBE_NVIDIA_BASE, MODPROBE_NVIDIA_DRM_MODESET_ON,
+    MODPROBE_NVIDIA_EC_BKLT, MODPROBE_VFIO,
 };
 
 /// Cleaned config for passing over dbus only
-#[derive(Debug, Clone, Deserialize, Serialize, Type)]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize, Type)]
 pub struct GfxConfigDbus {
     pub mode: GfxMode,
     pub vfio_enable: bool,
@@ -25,8 +28,8 @@ pub struct GfxConfigDbus {
     pub hotplug_type: HotplugType,
 }
 
-impl From<&GfxConfig> for GfxConfigDbus {
-    fn from(c: &GfxConfig) -> Self {
+impl From<&GfxSettings> for GfxConfigDbus {
+    fn from(c: &GfxSettings) -> Self {
         Self {
             mode: c.mode,
             vfio_enable: c.vfio_enable,
@@ -39,21 +42,187 @@ impl From<&GfxConfig> for GfxConfigDbus {
     }
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
-pub struct GfxConfig {
-    #[serde(skip)]
-    pub config_path: String,
+/// The config keys which can be read and changed by name, e.g from the CLI
+pub const CONFIG_KEYS: [&str; 6] = [
+    "vfio_enable",
+    "vfio_save",
+    "always_reboot",
+    "no_logind",
+    "logout_timeout_s",
+    "hotplug_type",
+];
+
+impl GfxConfigDbus {
+    /// Get the value of a config key as a string
+    pub fn get(&self, key: &str) -> Result<String, GfxError> {
+        Ok(match key {
+            "vfio_enable" => self.vfio_enable.to_string(),
+            "vfio_save" => self.vfio_save.to_string(),
+            "always_reboot" => self.always_reboot.to_string(),
+            "no_logind" => self.no_logind.to_string(),
+            "logout_timeout_s" => self.logout_timeout_s.to_string(),
+            "hotplug_type" => self.hotplug_type.to_string(),
+            _ => return Err(GfxError::UnknownConfigKey(key.to_string())),
+        })
+    }
+
+    /// Parse `value` and set it for a config key. This only checks the value parses,
+    /// the daemon checks the config can be used.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), GfxError> {
+        let invalid = || GfxError::InvalidConfigValue(key.to_string(), value.to_string());
+        let value = value.trim();
+        match key {
+            "vfio_enable" => self.vfio_enable = value.parse().map_err(|_| invalid())?,
+            "vfio_save" => self.vfio_save = value.parse().map_err(|_| invalid())?,
+            "always_reboot" => self.always_reboot = value.parse().map_err(|_| invalid())?,
+            "no_logind" => self.no_logind = value.parse().map_err(|_| invalid())?,
+            "logout_timeout_s" => self.logout_timeout_s = value.parse().map_err(|_| invalid())?,
+            "hotplug_type" => self.hotplug_type = value.parse().map_err(|_| invalid())?,
+            _ => return Err(GfxError::UnknownConfigKey(key.to_string())),
+        }
+        Ok(())
+    }
+}
+
+/// The origin of a config value that isn't set in any file
+pub const DEFAULT_ORIGIN: &str = "default";
+
+/// A config value and the file it came from, see [`ConfigStore::sources`]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize, Type)]
+pub struct ConfigSource {
+    pub key: String,
+    pub value: String,
+    /// The path of the file that set the value, or [`DEFAULT_ORIGIN`]
+    pub origin: String,
+}
+
+/// The config layers below the state file: the built-in defaults, then each drop-in
+/// file in name order from each drop-in directory in turn. The state file only holds
+/// the values that differ from these.
+#[derive(Debug, Clone, Default)]
+pub struct ConfigLayers {
+    /// The drop-in directories, lowest priority first
+    pub dirs: Vec<PathBuf>,
+    /// Every config key with its value from the highest layer that sets it
+    values: Map<String, Value>,
+    /// The drop-in file each value came from, if not the defaults
+    origins: BTreeMap<String, String>,
+}
+
+impl ConfigLayers {
+    /// Read the defaults and the `*.json` files in `dirs`. A drop-in that can't be
+    /// read, or would make the config invalid, is skipped with a warning.
+    pub fn load(dirs: Vec<PathBuf>) -> Self {
+        let mut values = match serde_json::to_value(GfxSettings::default()) {
+            Ok(Value::Object(values)) => values,
+            _ => Map::new(),
+        };
+        let mut origins = BTreeMap::new();
+
+        for dir in &dirs {
+            let mut files: Vec<PathBuf> = match std::fs::read_dir(dir) {
+                Ok(entries) => entries
+                    .filter_map(|e| e.ok().map(|e| e.path()))
+                    .filter(|p| p.extension().map_or(false, |ext| ext == "json"))
+                    .collect(),
+                Err(_) => continue,
+            };
+            files.sort();
+
+            for file in files {
+                let origin = file.to_string_lossy().to_string();
+                let keys = match std::fs::read_to_string(&file)
+                    .map_err(|err| GfxError::Read(origin.clone(), err))
+                    .and_then(|buf| migrate_config(&buf))
+                {
+                    Ok((keys, _)) => keys,
+                    Err(err) => {
+                        warn!("Ignoring config drop-in {origin}: {err}");
+                        continue;
+                    }
+                };
+
+                let mut merged = values.clone();
+                let mut set_keys = Vec::new();
+                for (key, value) in keys {
+                    if !merged.contains_key(&key) {
+                        warn!("Ignoring unknown key {key} in config drop-in {origin}");
+                        continue;
+                    }
+                    set_keys.push(key.clone());
+                    merged.insert(key, value);
+                }
+                if let Err(err) =
+                    serde_json::from_value::<GfxSettings>(Value::Object(merged.clone()))
+                {
+                    warn!("Ignoring config drop-in {origin}: {err}");
+                    continue;
+                }
+                for key in set_keys {
+                    origins.insert(key, origin.clone());
+                }
+                values = merged;
+            }
+        }
+
+        Self {
+            dirs,
+            values,
+            origins,
+        }
+    }
+
+    /// The settings with no state file
+    fn settings(&self) -> GfxSettings {
+        self.parse("{}")
+            .map(|(settings, _)| settings)
+            .unwrap_or_default()
+    }
+
+    /// Parse a state file of any version over these layers. Returns the settings and
+    /// the version the file was in. Only the keys this version knows are taken from a
+    /// newer file, and any of those it can't parse are skipped.
+    fn parse(&self, content: &str) -> Result<(GfxSettings, u32), GfxError> {
+        let (keys, version) = migrate_config(content)?;
+        let mut values = self.values.clone();
+        if version > CONFIG_VERSION {
+            for (key, value) in keys {
+                if !values.contains_key(&key) {
+                    continue;
+                }
+                let mut merged = values.clone();
+                merged.insert(key.clone(), value);
+                match serde_json::from_value::<GfxSettings>(Value::Object(merged.clone())) {
+                    Ok(_) => values = merged,
+                    Err(err) => warn!("Ignoring {key} from a newer config version: {err}"),
+                }
+            }
+        } else {
+            values.extend(keys);
+        }
+        let config = serde_json::from_value(Value::Object(values))
+            .map_err(|e| GfxError::ParseConfig(e.to_string()))?;
+        Ok((config, version))
+    }
+}
+
+/// The version of the config file format. Earlier versions are migrated on load, see
+/// `config_old` for their formats.
+pub const CONFIG_VERSION: u32 = 5;
+
+/// The config as saved, with the format version first
+#[derive(Serialize)]
+struct VersionedConfig {
+    version: u32,
+    #[serde(flatten)]
+    config: Map<String, Value>,
+}
+
+/// The user preferences, saved to the config file
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct GfxSettings {
     /// The current mode set, also applies on boot
     pub mode: GfxMode,
-    /// Only for temporary modes like compute or vfio
-    #[serde(skip)]
-    pub tmp_mode: Option<GfxMode>,
-    /// Just for tracking the requested mode change in rebootless mode
-    #[serde(skip)]
-    pub pending_mode: Option<GfxMode>,
-    /// Just for tracking the required user action
-    #[serde(skip)]
-    pub pending_action: Option<UserActionRequired>,
     /// Set if vfio option is enabled. This requires the vfio drivers to be built as modules
     pub vfio_enable: bool,
     /// Save the VFIO mode so that it is reloaded on boot
@@ -68,14 +237,10 @@ pub struct GfxConfig {
     pub hotplug_type: HotplugType,
 }
 
-impl GfxConfig {
-    fn new(config_path: String) -> Self {
+impl Default for GfxSettings {
+    fn default() -> Self {
         Self {
-            config_path,
             mode: GfxMode::Hybrid,
-            tmp_mode: None,
-            pending_mode: None,
-            pending_action: None,
             vfio_enable: false,
             vfio_save: false,
             always_reboot: false,
@@ -84,73 +249,236 @@ impl GfxConfig {
             hotplug_type: HotplugType::None,
         }
     }
+}
 
-    /// `load` will attempt to read the config, and panic if the dir is missing
-    pub fn load(config_path: String) -> Self {
-        let mut file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(&config_path)
-            .unwrap_or_else(|_| panic!("The directory {} is missing", config_path)); // okay to cause panic here
-        let mut buf = String::new();
-        let mut config;
-        if let Ok(read_len) = file.read_to_string(&mut buf) {
-            if read_len == 0 {
-                config = Self::new(config_path);
-            } else if let Ok(data) = serde_json::from_str(&buf) {
-                config = data;
-                config.config_path = config_path;
-            } else if let Ok(data) = serde_json::from_str(&buf) {
-                let old: GfxConfig300 = data;
-                config = old.into();
-                config.config_path = config_path;
-            } else if let Ok(data) = serde_json::from_str(&buf) {
-                let old: GfxConfig405 = data;
-                config = old.into();
-                config.config_path = config_path;
-            } else if let Ok(data) = serde_json::from_str(&buf) {
-                let old: GfxConfig500 = data;
-                config = old.into();
-                config.config_path = config_path;
-            } else {
-                warn!("Could not deserialise {}, recreating", config_path);
-                config = GfxConfig::new(config_path);
-            }
-        } else {
-            config = Self::new(config_path)
+impl GfxSettings {
+    /// The settings as their keys and values
+    fn values(&self) -> Map<String, Value> {
+        match serde_json::to_value(self) {
+            Ok(Value::Object(values)) => values,
+            _ => Map::new(),
         }
-        config.write();
-        config
     }
+}
 
-    pub fn read(&mut self) {
-        let mut file = OpenOptions::new()
-            .read(true)
-            .open(&self.config_path)
-            .unwrap_or_else(|err| panic!("Error reading {}: {}", self.config_path, err));
-        let mut buf = String::new();
-        if let Ok(l) = file.read_to_string(&mut buf) {
-            if l == 0 {
-                warn!("File is empty {}", self.config_path);
-            } else {
-                let mut x: Self = serde_json::from_str(&buf)
-                    .unwrap_or_else(|_| panic!("Could not deserialise {}", self.config_path));
-                // copy over serde skipped values
-                x.tmp_mode = self.tmp_mode;
-                *self = x;
+/// Where the settings are kept: the daemon managed state file, layered over the
+/// defaults and drop-in files
+#[derive(Debug, Clone)]
+pub struct ConfigStore {
+    /// The path of the state file
+    pub path: String,
+    /// The defaults and drop-in files the state file is layered over
+    pub layers: ConfigLayers,
+    /// Set if the state file was corrupt when loaded and was restored from the backup
+    /// or reset, or is from a newer version, for sending to clients in
+    /// `NotifyConfigWarning`
+    pub warning: Option<String>,
+    /// Set if the state file is from a newer version. It is loaded but never written
+    /// over, so the keys this version doesn't know aren't lost.
+    pub read_only: bool,
+}
+
+impl ConfigStore {
+    /// A store for the state file at `path` over the drop-in files in `dropin_dirs`
+    pub fn new(path: String, dropin_dirs: Vec<PathBuf>) -> Self {
+        Self {
+            path,
+            layers: ConfigLayers::load(dropin_dirs),
+            warning: None,
+            read_only: false,
+        }
+    }
+
+    /// `load` will read the settings, migrating the state file from an older version,
+    /// and save them. A corrupt state file is replaced by the backup of the last good
+    /// config, or the defaults if there is none, with `warning` set. A state file from
+    /// a newer version is loaded read-only, also with `warning` set.
+    pub fn load(&mut self) -> Result<GfxSettings, GfxError> {
+        let settings = match read_config_file(&self.path)? {
+            None => self.layers.settings(),
+            Some(buf) => match self.layers.parse(&buf) {
+                Ok((settings, version)) => {
+                    if version < CONFIG_VERSION {
+                        self.backup_old_version(&buf, version);
+                    } else if version > CONFIG_VERSION {
+                        let warning = format!(
+                            "{} is from the newer config version {version}, only the settings known to version {CONFIG_VERSION} are used and changes are not saved",
+                            self.path
+                        );
+                        warn!("{warning}");
+                        self.warning = Some(warning);
+                        self.read_only = true;
+                    }
+                    settings
+                }
+                Err(err) => self.recover(&buf, err),
+            },
+        };
+        self.write(&settings)?;
+        Ok(settings)
+    }
+
+    /// Replace the corrupt state file `content` by the backup, keeping the corrupt file
+    fn recover(&mut self, content: &str, err: GfxError) -> GfxSettings {
+        let config_path = &self.path;
+        let corrupt = format!("{config_path}.corrupt");
+        if let Err(err) = std::fs::write(&corrupt, content) {
+            warn!("Could not save the corrupt config as {corrupt}: {err}");
+        }
+
+        let backup = backup_path(config_path);
+        let restored = read_config_file(&backup)
+            .ok()
+            .flatten()
+            .and_then(|buf| self.layers.parse(&buf).ok());
+        let (settings, warning) = match restored {
+            Some((settings, _)) => (
+                settings,
+                format!("{config_path} is corrupt ({err}), restored the last good config from {backup}, the corrupt file is saved as {corrupt}"),
+            ),
+            None => (
+                self.layers.settings(),
+                format!("{config_path} is corrupt ({err}) and there is no usable backup, reset to the defaults, the corrupt file is saved as {corrupt}"),
+            ),
+        };
+        warn!("{warning}");
+        self.warning = Some(warning);
+        settings
+    }
+
+    /// Keep a copy of a state file from before it was migrated from `version`
+    fn backup_old_version(&self, content: &str, version: u32) {
+        let config_path = &self.path;
+        let backup = format!("{config_path}.v{version}.bak");
+        match std::fs::write(&backup, content) {
+            Ok(()) => info!(
+                "Migrating {config_path} from version {version} to {CONFIG_VERSION}, the old config is saved as {backup}"
+            ),
+            Err(err) => warn!(
+                "Migrating {config_path} from version {version} to {CONFIG_VERSION}, could not save the old config as {backup}: {err}"
+            ),
+        }
+    }
+
+    /// Re-read the drop-in files and state file. The store is unchanged if the state
+    /// file can't be read or parsed.
+    pub fn read(&mut self) -> Result<GfxSettings, GfxError> {
+        let layers = ConfigLayers::load(self.layers.dirs.clone());
+        let (settings, version) = match read_config_file(&self.path)? {
+            None => (layers.settings(), CONFIG_VERSION),
+            Some(buf) => layers.parse(&buf)?,
+        };
+        self.layers = layers;
+        self.read_only = version > CONFIG_VERSION;
+        Ok(settings)
+    }
+
+    /// Each setting with the file it came from: the state file if it differs from the
+    /// drop-ins, otherwise the last drop-in that set it, or the defaults
+    pub fn sources(&self, settings: &GfxSettings) -> Vec<ConfigSource> {
+        let dbus = GfxConfigDbus::from(settings);
+        let values = settings.values();
+        std::iter::once("mode")
+            .chain(CONFIG_KEYS)
+            .map(|key| {
+                let value = match key {
+                    "mode" => settings.mode.to_string(),
+                    key => dbus.get(key).unwrap_or_default(),
+                };
+                let origin = if values.get(key) != self.layers.values.get(key) {
+                    self.path.clone()
+                } else {
+                    self.layers
+                        .origins
+                        .get(key)
+                        .cloned()
+                        .unwrap_or_else(|| DEFAULT_ORIGIN.to_string())
+                };
+                ConfigSource {
+                    key: key.to_string(),
+                    value,
+                    origin,
+                }
+            })
+            .collect()
+    }
+
+    /// Save the settings that differ from the drop-ins to the state file. The file is
+    /// replaced in one step so it is never left partly written, and the config it
+    /// replaces is kept as the backup if it was valid. Nothing is written if the file
+    /// is unchanged, so the config watch isn't woken by it. A `read_only` state file is
+    /// left as it is.
+    pub fn write(&self, settings: &GfxSettings) -> Result<(), GfxError> {
+        if self.read_only {
+            warn!(
+                "Not saving the config, {} is from a newer version",
+                self.path
+            );
+            return Ok(());
+        }
+        let mut config = settings.values();
+        config.retain(|key, value| self.layers.values.get(key) != Some(value));
+        let json = serde_json::to_string_pretty(&VersionedConfig {
+            version: CONFIG_VERSION,
+            config,
+        })
+        .map_err(|err| GfxError::ParseConfig(err.to_string()))?;
+
+        if let Ok(Some(old)) = read_config_file(&self.path) {
+            if old == json {
+                return Ok(());
+            }
+            if self.layers.parse(&old).is_ok() {
+                let backup = backup_path(&self.path);
+                write_atomic(&backup, old.as_bytes())
+                    .unwrap_or_else(|err| warn!("Could not back up the config: {err}"));
             }
         }
+        write_atomic(&self.path, json.as_bytes())
     }
+}
+
+/// The backup of the last good config
+fn backup_path(config_path: &str) -> String {
+    format!("{config_path}.bak")
+}
 
-    pub fn write(&self) {
-        let mut file = File::create(&self.config_path).expect("Couldn't overwrite config");
-        let json = serde_json::to_string_pretty(self).expect("Parse config to JSON failed");
-        file.write_all(json.as_bytes())
-            .unwrap_or_else(|err| error!("Could not write config: {}", err));
+/// Read a config file, `None` if it is missing. An empty file is returned as it is, it
+/// was most likely truncated and is as corrupt as any other file that can't be parsed.
+fn read_config_file(path: &str) -> Result<Option<String>, GfxError> {
+    match std::fs::read_to_string(path) {
+        Ok(buf) => Ok(Some(buf)),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(GfxError::Read(path.to_string(), err)),
     }
 }
 
+/// Write `content` to a temporary file beside `path`, sync it and rename it over `path`,
+/// so a crash leaves either the old or the new file
+pub(crate) fn write_atomic(path: &str, content: &[u8]) -> Result<(), GfxError> {
+    let tmp = format!("{path}.tmp");
+    let write = || -> std::io::Result<()> {
+        let mut file = File::create(&tmp)?;
+        file.write_all(content)?;
+        file.sync_all()?;
+        std::fs::rename(&tmp, path)?;
+        // Sync the directory so the rename itself is durable
+        if let Some(dir) = Path::new(path).parent() {
+            let dir = if dir.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                dir
+            };
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    };
+    write().map_err(|err| {
+        std::fs::remove_file(&tmp).ok();
+        GfxError::Write(path.to_string(), err)
+    })
+}
+
 /// Creates the full modprobe.conf required for vfio pass-through
 fn create_vfio_conf(devices: &DiscreetGpu) -> Vec<u8> {
     let mut vifo = MODPROBE_VFIO.to_vec();
@@ -169,28 +497,31 @@ fn create_vfio_conf(devices: &DiscreetGpu) -> Vec<u8> {
     conf
 }
 
-pub(crate) fn check_vulkan_icd(mode: GfxMode) -> Result<(), GfxError> {
-    let inactive_nv_icd: String = CONFIG_NVIDIA_VKICD.to_owned() + "_inactive";
+pub(crate) fn check_vulkan_icd(paths: &HostPaths, mode: GfxMode) -> Result<(), GfxError> {
+    let nv_icd = paths.nvidia_vkicd();
+    let mut inactive_nv_icd = nv_icd.clone().into_os_string();
+    inactive_nv_icd.push("_inactive");
+    let inactive_nv_icd = std::path::PathBuf::from(inactive_nv_icd);
     info!("check_vulkan_icd: checking for Vulkan ICD profiles...");
     if mode == GfxMode::Vfio || mode == GfxMode::Integrated {
-        if std::path::Path::new(CONFIG_NVIDIA_VKICD).exists() {
+        if nv_icd.exists() {
             info!(
                 "check_vulkan_icd: moving {} to {}",
-                CONFIG_NVIDIA_VKICD,
-                inactive_nv_icd.clone()
+                nv_icd.display(),
+                inactive_nv_icd.display()
             );
-            std::fs::rename(CONFIG_NVIDIA_VKICD, inactive_nv_icd)
-                .map_err(|err| GfxError::Write(CONFIG_NVIDIA_VKICD.to_owned(), err))?;
+            std::fs::rename(&nv_icd, inactive_nv_icd)
+                .map_err(|err| GfxError::Write(nv_icd.to_string_lossy().to_string(), err))?;
         }
-    } else if std::path::Path::new(&inactive_nv_icd).exists() {
+    } else if inactive_nv_icd.exists() {
         info!(
             "check_vulkan_icd: moving {} to {}",
-            inactive_nv_icd.clone(),
-            CONFIG_NVIDIA_VKICD
+            inactive_nv_icd.display(),
+            nv_icd.display()
         );
         // nvidia icd must be applied
-        std::fs::rename(inactive_nv_icd.clone(), CONFIG_NVIDIA_VKICD)
-            .map_err(|err| GfxError::Write(inactive_nv_icd, err))?;
+        std::fs::rename(&inactive_nv_icd, nv_icd)
+            .map_err(|err| GfxError::Write(inactive_nv_icd.to_string_lossy().to_string(), err))?;
     }
     Ok(())
 }
@@ -217,17 +548,18 @@ pub(crate) fn create_modprobe_conf(mode: GfxMode, device: &DiscreetGpu) -> Resul
         GfxMode::None | GfxMode::AsusMuxDgpu => vec![],
     };
 
+    let path = device.paths().modprobe_conf();
     let mut file = std::fs::OpenOptions::new()
         .create(true)
         .truncate(true)
         .write(true)
-        .open(MODPROBE_PATH)
-        .map_err(|err| GfxError::Path(MODPROBE_PATH.into(), err))?;
+        .open(&path)
+        .map_err(|err| GfxError::Path(path.to_string_lossy().to_string(), err))?;
 
-    info!("create_modprobe_conf: writing {}", MODPROBE_PATH);
+    info!("create_modprobe_conf: writing {}", path.display());
     file.write_all(&content)
         .and_then(|_| file.sync_all())
-        .map_err(|err| GfxError::Write(MODPROBE_PATH.into(), err))?;
+        .map_err(|err| GfxError::Write(path.to_string_lossy().to_string(), err))?;
 
     Ok(())
 }
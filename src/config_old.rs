@@ -1,10 +1,92 @@
+use serde::{de::DeserializeOwned, Serialize as SerializeTrait};
 use serde_derive::{Deserialize, Serialize};
+use serde_json::{Map, Value};
 
 use crate::{
-    config::GfxConfig,
+    config::{GfxSettings, CONFIG_VERSION},
+    error::GfxError,
     pci_device::{GfxMode, HotplugType},
 };
 
+/// The format of [`GfxConfig300`]
+pub(crate) const VERSION_300: u32 = 1;
+/// The format of [`GfxConfig402`], adding `compute_save` and `always_reboot`
+pub(crate) const VERSION_402: u32 = 2;
+/// The format of [`GfxConfig405`], adding `no_logind` and `logout_timeout_s`
+pub(crate) const VERSION_405: u32 = 3;
+/// The format of [`GfxConfig500`], adding `hotplug_type`
+pub(crate) const VERSION_500: u32 = 4;
+
+/// Migrates a config from the version at its index + 1 to the next version. A config of
+/// version `n` is brought up to date by running every migration from index `n - 1`.
+const MIGRATIONS: [fn(Value) -> Result<Value, serde_json::Error>; CONFIG_VERSION as usize - 1] = [
+    migrate::<GfxConfig300, GfxConfig402>,
+    migrate::<GfxConfig402, GfxConfig405>,
+    migrate::<GfxConfig405, GfxConfig500>,
+    migrate::<GfxConfig500, GfxSettings>,
+];
+
+fn migrate<Old, New>(value: Value) -> Result<Value, serde_json::Error>
+where
+    Old: DeserializeOwned + Into<New>,
+    New: SerializeTrait,
+{
+    let old: Old = serde_json::from_value(value)?;
+    serde_json::to_value(old.into())
+}
+
+/// The format version of a config file. Files saved before the `version` key was added
+/// are told apart by the keys each release added.
+pub(crate) fn config_version(value: &Value) -> Result<u32, GfxError> {
+    let Some(object) = value.as_object() else {
+        return Err(GfxError::ParseConfig("not a JSON object".to_string()));
+    };
+    if let Some(version) = object.get("version") {
+        return version
+            .as_u64()
+            .and_then(|v| u32::try_from(v).ok())
+            .filter(|v| *v > 0)
+            .ok_or_else(|| GfxError::ParseConfig(format!("invalid version {version}")));
+    }
+
+    Ok(if object.contains_key("gfx_mode") {
+        VERSION_300
+    } else if object.contains_key("compute_save") {
+        if object.contains_key("hotplug_type") {
+            VERSION_500
+        } else if object.contains_key("no_logind") {
+            VERSION_405
+        } else {
+            VERSION_402
+        }
+    } else {
+        // Later releases dropped `compute_save` and saved the current format, only
+        // without the version
+        CONFIG_VERSION
+    })
+}
+
+/// Parse the content of a config file of any version, migrating it to the current
+/// version. Returns the config keys and the version the file was in. A file in the
+/// current version only holds the keys set in it. The keys of a file from a newer
+/// version are returned as they are, it's up to the caller to pick out the known ones.
+pub(crate) fn migrate_config(content: &str) -> Result<(Map<String, Value>, u32), GfxError> {
+    let mut value: Value =
+        serde_json::from_str(content).map_err(|e| GfxError::ParseConfig(e.to_string()))?;
+    let version = config_version(&value)?;
+
+    for migration in MIGRATIONS.iter().skip(version as usize - 1) {
+        value = migration(value).map_err(|e| {
+            GfxError::ParseConfig(format!("migrating from version {version} failed: {e}"))
+        })?;
+    }
+    let Value::Object(mut keys) = value else {
+        return Err(GfxError::ParseConfig("not a JSON object".to_string()));
+    };
+    keys.remove("version");
+    Ok((keys, version))
+}
+
 #[derive(Debug, PartialEq, Eq, Copy, Clone, Deserialize, Serialize)]
 pub enum GfxMode300 {
     Hybrid,
@@ -30,25 +112,19 @@ impl From<GfxMode300> for GfxMode {
 
 #[derive(Deserialize, Serialize)]
 pub struct GfxConfig300 {
-    pub gfx_mode: GfxMode,
+    pub gfx_mode: GfxMode300,
     pub gfx_managed: bool,
     pub gfx_vfio_enable: bool,
 }
 
-impl From<GfxConfig300> for GfxConfig {
+impl From<GfxConfig300> for GfxConfig402 {
     fn from(old: GfxConfig300) -> Self {
-        GfxConfig {
-            config_path: Default::default(),
-            mode: old.gfx_mode,
-            tmp_mode: Default::default(),
-            pending_mode: None,
-            pending_action: None,
+        GfxConfig402 {
+            mode: old.gfx_mode.into(),
             vfio_enable: old.gfx_vfio_enable,
             vfio_save: false,
+            compute_save: false,
             always_reboot: false,
-            no_logind: false,
-            logout_timeout_s: 180,
-            hotplug_type: HotplugType::None,
         }
     }
 }
@@ -62,20 +138,16 @@ pub struct GfxConfig402 {
     pub always_reboot: bool,
 }
 
-impl From<GfxConfig402> for GfxConfig {
+impl From<GfxConfig402> for GfxConfig405 {
     fn from(old: GfxConfig402) -> Self {
-        GfxConfig {
-            config_path: Default::default(),
+        GfxConfig405 {
             mode: old.mode,
-            tmp_mode: Default::default(),
-            pending_mode: None,
-            pending_action: None,
             vfio_enable: old.vfio_enable,
             vfio_save: old.vfio_save,
+            compute_save: old.compute_save,
             always_reboot: old.always_reboot,
             no_logind: false,
             logout_timeout_s: 180,
-            hotplug_type: HotplugType::None,
         }
     }
 }
@@ -91,19 +163,16 @@ pub struct GfxConfig405 {
     pub logout_timeout_s: u64,
 }
 
-impl From<GfxConfig405> for GfxConfig {
+impl From<GfxConfig405> for GfxConfig500 {
     fn from(old: GfxConfig405) -> Self {
-        GfxConfig {
-            config_path: Default::default(),
+        GfxConfig500 {
             mode: old.mode,
-            tmp_mode: Default::default(),
-            pending_mode: None,
-            pending_action: None,
             vfio_enable: old.vfio_enable,
             vfio_save: old.vfio_save,
+            compute_save: old.compute_save,
             always_reboot: old.always_reboot,
-            no_logind: false,
-            logout_timeout_s: 180,
+            no_logind: old.no_logind,
+            logout_timeout_s: old.logout_timeout_s,
             hotplug_type: HotplugType::None,
         }
     }
@@ -121,20 +190,16 @@ pub struct GfxConfig500 {
     pub hotplug_type: HotplugType,
 }
 
-impl From<GfxConfig500> for GfxConfig {
+impl From<GfxConfig500> for GfxSettings {
     fn from(old: GfxConfig500) -> Self {
-        GfxConfig {
-            config_path: Default::default(),
+        GfxSettings {
             mode: old.mode,
-            tmp_mode: Default::default(),
-            pending_mode: None,
-            pending_action: None,
             vfio_enable: old.vfio_enable,
             vfio_save: old.vfio_save,
             always_reboot: old.always_reboot,
-            no_logind: false,
-            logout_timeout_s: 180,
-            hotplug_type: HotplugType::None,
+            no_logind: old.no_logind,
+            logout_timeout_s: old.logout_timeout_s,
+            hotplug_type: old.hotplug_type,
         }
     }
 }
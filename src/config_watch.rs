@@ -0,0 +1,122 @@
+use std::{
+    collections::HashMap,
+    ffi::{OsStr, OsString},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use futures_util::stream::StreamExt;
+use inotify::{EventStream, Inotify, WatchDescriptor, WatchMask};
+use log::{debug, info, warn};
+use tokio::{
+    signal::unix::{signal, Signal, SignalKind},
+    sync::mpsc,
+    time::timeout,
+};
+
+/// How long to wait for more changes after the first before reloading, so an editor
+/// saving in several steps causes one reload
+pub const SETTLE_PERIOD: Duration = Duration::from_millis(200);
+
+/// Start watching the config file and the drop-in directories. A notice is sent when
+/// the file or a `*.json` drop-in is written, replaced or removed, and when the daemon
+/// gets SIGHUP. A drop-in directory created later is only seen after SIGHUP.
+///
+/// The directory is watched rather than the file, as saving by renaming a new file over
+/// it (as the daemon and many editors do) replaces the inode a file watch is on.
+pub fn watch_config(config_path: &str, dropin_dirs: &[PathBuf]) -> mpsc::UnboundedReceiver<()> {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let path = Path::new(config_path);
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => Path::new(".").to_path_buf(),
+    };
+
+    // The file each watched directory is watched for, `None` for any drop-in
+    let mut watched: HashMap<WatchDescriptor, Option<OsString>> = HashMap::new();
+    let mut inotify = Inotify::init()
+        .and_then(|inotify| {
+            let wd = inotify
+                .watches()
+                .add(&dir, WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO)?;
+            watched.insert(wd, path.file_name().map(|n| n.to_owned()));
+            for dropin_dir in dropin_dirs {
+                let mask = WatchMask::CLOSE_WRITE
+                    | WatchMask::MOVED_TO
+                    | WatchMask::MOVED_FROM
+                    | WatchMask::DELETE;
+                match inotify.watches().add(dropin_dir, mask) {
+                    Ok(wd) => {
+                        watched.insert(wd, None);
+                    }
+                    Err(e) => debug!("watch_config: not watching {dropin_dir:?}: {e}"),
+                }
+            }
+            inotify.into_event_stream([0u8; 1024])
+        })
+        .map_err(|e| warn!("watch_config: can't watch {dir:?}, SIGHUP only: {e}"))
+        .ok();
+    let mut hangup = signal(SignalKind::hangup())
+        .map_err(|e| warn!("watch_config: can't handle SIGHUP: {e}"))
+        .ok();
+
+    tokio::spawn(async move {
+        while next_change(&mut inotify, &mut hangup, &watched).await {
+            // Take in the rest of a burst of changes
+            while let Ok(true) = timeout(
+                SETTLE_PERIOD,
+                next_change(&mut inotify, &mut hangup, &watched),
+            )
+            .await
+            {}
+            if tx.send(()).is_err() {
+                break;
+            }
+        }
+        debug!("watch_config: stopping");
+    });
+    rx
+}
+
+/// Wait for the config file or a drop-in to change, or SIGHUP. Returns `false` once neither can be
+/// watched any more.
+async fn next_change(
+    inotify: &mut Option<EventStream<[u8; 1024]>>,
+    hangup: &mut Option<Signal>,
+    watched: &HashMap<WatchDescriptor, Option<OsString>>,
+) -> bool {
+    loop {
+        tokio::select! {
+            Some(event) = async { inotify.as_mut()?.next().await }, if inotify.is_some() => {
+                match event {
+                    Ok(event) if is_config_file(watched, &event.wd, event.name.as_deref()) => {
+                        debug!("watch_config: {:?} {:?}", event.name, event.mask);
+                        return true;
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        warn!("watch_config: inotify failed, SIGHUP only: {e}");
+                        *inotify = None;
+                    }
+                }
+            }
+            Some(()) = async { hangup.as_mut()?.recv().await }, if hangup.is_some() => {
+                info!("watch_config: SIGHUP, reloading the config");
+                return true;
+            }
+            else => return false,
+        }
+    }
+}
+
+fn is_config_file(
+    watched: &HashMap<WatchDescriptor, Option<OsString>>,
+    wd: &WatchDescriptor,
+    name: Option<&OsStr>,
+) -> bool {
+    match (watched.get(wd), name) {
+        (Some(Some(file)), Some(name)) => file == name,
+        (Some(None), Some(name)) => Path::new(name).extension() == Some(OsStr::new("json")),
+        _ => false,
+    }
+}
@@ -1,35 +1,99 @@
 use futures_util::lock::Mutex;
-use log::{debug, info, warn};
+use log::{debug, error, info, warn};
 use std::{
-    sync::atomic::{AtomicBool, Ordering},
+    path::PathBuf,
     sync::Arc,
+    time::{Instant, SystemTime, UNIX_EPOCH},
 };
+use tokio::sync::broadcast;
 
 use crate::{
-    actions::{StagedAction, UserActionRequired},
+    actions::{
+        LogindSessionSource, SessionSource, StagedAction, SwitchContext, SwitchEvent,
+        SwitchOutcome, SwitchStepStatus, SwitchStop, SwitchTransaction, UserActionRequired,
+    },
     pci_device::HotplugType,
 };
 use crate::{
+    command::CommandRunner,
+    diagnostics::Diagnostics,
     error::GfxError,
+    history::{HistoryEntry, HistoryStep, SwitchHistory, MAX_HISTORY_ENTRIES},
+    host_paths::HostPaths,
     pci_device::{DiscreetGpu, GfxVendor, RuntimePowerManagement},
+    polkit::{Authority, Caller},
+    runtime_state::GfxRuntimeState,
     special_asus::{asus_dgpu_disable_exists, asus_egpu_enable_exists},
     *,
 };
 
-use super::config::GfxConfig;
+use super::config::{ConfigSource, ConfigStore, GfxConfigDbus, GfxSettings};
+
+/// How far the in-flight switch has got, to decide if it can still be cancelled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SwitchPhase {
+    /// No switch is running
+    Idle,
+    /// Only waiting steps have run, the switch can be cancelled
+    Waiting,
+    /// A step that changes the system has started
+    Running,
+    /// Cancelled while waiting, the switch stops before the next step
+    Cancelled,
+}
+
+/// The phase of the switch with the transaction id `id`. A switch that ends after a
+/// newer one has started finds a different id here, and leaves the phase alone.
+#[derive(Debug, Clone, Copy)]
+struct TaggedPhase {
+    id: u64,
+    phase: SwitchPhase,
+}
 
 pub struct CtrlGraphics {
     pub(crate) dgpu: Arc<Mutex<DiscreetGpu>>,
-    pub(crate) config: Arc<Mutex<GfxConfig>>,
-    loop_exit: Arc<AtomicBool>,
+    pub(crate) config: Arc<Mutex<GfxSettings>>,
+    pub(crate) store: Arc<Mutex<ConfigStore>>,
+    pub(crate) runtime: Arc<Mutex<GfxRuntimeState>>,
+    runtime_path: PathBuf,
+    pub(crate) paths: HostPaths,
+    pub(crate) switch_outcome: Arc<Mutex<SwitchOutcome>>,
+    switch_events: broadcast::Sender<SwitchEvent>,
+    switch_phase: Arc<Mutex<TaggedPhase>>,
+    history: SwitchHistory,
+    authority: Arc<dyn Authority>,
+    /// Stops the latest switch
+    stop: SwitchStop,
+    /// Where switches watch the sessions they wait on to log out
+    pub(crate) sessions: Arc<dyn SessionSource>,
 }
 
 impl CtrlGraphics {
-    pub fn new(config: Arc<Mutex<GfxConfig>>) -> Result<CtrlGraphics, GfxError> {
+    pub fn new(
+        config: Arc<Mutex<GfxSettings>>,
+        store: ConfigStore,
+        paths: HostPaths,
+        runner: Arc<dyn CommandRunner>,
+        authority: Arc<dyn Authority>,
+    ) -> Result<CtrlGraphics, GfxError> {
+        let runtime_path = paths.runtime_state();
         Ok(CtrlGraphics {
-            dgpu: Arc::new(Mutex::new(DiscreetGpu::new()?)),
+            dgpu: Arc::new(Mutex::new(DiscreetGpu::new(paths.clone(), runner)?)),
             config,
-            loop_exit: Arc::new(AtomicBool::new(false)),
+            store: Arc::new(Mutex::new(store)),
+            runtime: Arc::new(Mutex::new(GfxRuntimeState::load(&runtime_path))),
+            runtime_path,
+            history: SwitchHistory::new(paths.history(), MAX_HISTORY_ENTRIES),
+            paths,
+            switch_outcome: Arc::new(Mutex::new(SwitchOutcome::None)),
+            switch_events: broadcast::channel(64).0,
+            switch_phase: Arc::new(Mutex::new(TaggedPhase {
+                id: 0,
+                phase: SwitchPhase::Idle,
+            })),
+            authority,
+            stop: SwitchStop::default(),
+            sessions: Arc::new(LogindSessionSource),
         })
     }
 
@@ -37,26 +101,119 @@ impl CtrlGraphics {
         self.dgpu.clone()
     }
 
+    /// Check the caller is authorized for every one of the polkit `action_ids`. If polkit
+    /// can't be reached the check fails closed and only root is allowed.
+    pub async fn authorize(&self, caller: &Caller, action_ids: &[&str]) -> Result<(), GfxError> {
+        for action_id in action_ids {
+            if !self
+                .authority
+                .check_authorization(caller, action_id)
+                .await?
+            {
+                warn!("{caller:?} is not authorized for {action_id}");
+                return Err(GfxError::NotAuthorized(action_id.to_string()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Get up to `limit` of the most recent finished switches, newest first. A `limit`
+    /// of 0 gets the whole history.
+    pub fn get_history(&self, limit: usize) -> Result<Vec<HistoryEntry>, GfxError> {
+        self.history.entries(limit)
+    }
+
+    /// Collect the device, system and config state for a bug report
+    pub async fn get_diagnostics(&self) -> Diagnostics {
+        let config = GfxConfigDbus::from(&*self.config.lock().await);
+        Diagnostics::collect(&*self.dgpu.lock().await, config)
+    }
+
+    /// Each config value and the file it came from
+    pub async fn get_config_sources(&self) -> Vec<ConfigSource> {
+        let config = self.config.lock().await;
+        self.store.lock().await.sources(&config)
+    }
+
+    /// Receive the progress and result of every mode switch started after this call
+    pub fn subscribe_switch_events(&self) -> broadcast::Receiver<SwitchEvent> {
+        self.switch_events.subscribe()
+    }
+
+    /// Check whether the last run of the daemon stopped in the middle of a switch. If it
+    /// did the switch is recorded as interrupted and its pending state is cleared, so
+    /// the following [`Self::reload`] sets up the saved mode again. The steps done before
+    /// the stop are not undone one by one, the boot tasks of the saved mode redo them.
+    /// A switch is never resumed, so any pending mode or user action left by the last
+    /// run is cleared too.
+    pub async fn recover_interrupted_switch(&self) {
+        let mut runtime = self.runtime.lock().await;
+        let Some(transaction_id) = runtime.transaction_id else {
+            if runtime.pending_mode.is_some() || runtime.pending_action.is_some() {
+                info!(
+                    "recover_interrupted_switch: Clearing the pending {:?} and {:?} left by the last run",
+                    runtime.pending_mode, runtime.pending_action
+                );
+                runtime.pending_mode = None;
+                runtime.pending_action = None;
+                runtime.save(&self.runtime_path);
+            }
+            return;
+        };
+        let from = self.config.lock().await.mode;
+        let to = runtime.pending_mode.unwrap_or(from);
+        warn!("recover_interrupted_switch: The switch from {from} to {to} (id {transaction_id}) was interrupted, setting up {from} again");
+
+        let mut entry = HistoryEntry::new(
+            from,
+            to,
+            None,
+            runtime
+                .pending_action
+                .unwrap_or(UserActionRequired::Nothing),
+        );
+        entry.outcome = SwitchOutcome::Interrupted;
+        self.history
+            .record(&entry)
+            .unwrap_or_else(|e| warn!("Could not record switch history: {e}"));
+        *self.switch_outcome.lock().await = SwitchOutcome::Interrupted;
+
+        runtime.pending_mode = None;
+        runtime.pending_action = None;
+        runtime.transaction_id = None;
+        runtime.save(&self.runtime_path);
+    }
+
     /// Force re-init of all state, including reset of device state
     pub async fn reload(&mut self) -> Result<(), GfxError> {
         let mut config = self.config.lock().await;
         let vfio_enable = config.vfio_enable;
 
-        let mode = get_kernel_cmdline_mode()?
+        // A typo on the cmdline shouldn't stop the boot tasks, the config mode is used
+        let cmdline_mode = match get_kernel_cmdline_mode(&self.paths) {
+            Err(err @ GfxError::ParseMode(_)) => {
+                error!("reload: Ignoring supergfxd.mode on the kernel cmdline: {err}");
+                None
+            }
+            res => res?,
+        };
+        let mode = cmdline_mode
             .map(|mode| {
                 warn!("reload: Graphic mode {:?} set on kernel cmdline", mode);
                 config.mode = mode;
-                config.write();
                 mode
             })
-            .unwrap_or(self.get_gfx_mode(&config)?);
+            .unwrap_or(Self::get_gfx_mode(&config, &*self.runtime.lock().await));
+        if cmdline_mode.is_some() {
+            self.store.lock().await.write(&config)?;
+        }
 
         if matches!(mode, GfxMode::Vfio) && !vfio_enable {
             warn!("reload: Tried to set vfio mode but it is not enabled");
             return Ok(());
         }
 
-        if matches!(mode, GfxMode::AsusEgpu) && !asus_egpu_enable_exists() {
+        if matches!(mode, GfxMode::AsusEgpu) && !asus_egpu_enable_exists(&self.paths) {
             warn!("reload: Tried to set egpu mode but it is not supported");
             return Ok(());
         }
@@ -69,38 +226,43 @@ impl CtrlGraphics {
     }
 
     /// Associated method to get which mode is set
-    pub(crate) fn get_gfx_mode(&self, config: &GfxConfig) -> Result<GfxMode, GfxError> {
-        if let Some(mode) = config.tmp_mode {
+    pub(crate) fn get_gfx_mode(config: &GfxSettings, runtime: &GfxRuntimeState) -> GfxMode {
+        if let Some(mode) = runtime.tmp_mode {
             dbg!(&mode);
-            return Ok(mode);
+            return mode;
         }
-        Ok(config.mode)
+        config.mode
     }
 
-    ///
+    /// Get the mode that will be set once the in-progress switch completes
     pub(crate) async fn get_pending_mode(&self) -> GfxMode {
-        let config = self.config.lock().await;
-        if let Some(mode) = config.pending_mode {
+        let runtime = self.runtime.lock().await;
+        if let Some(mode) = runtime.pending_mode {
             return mode;
         }
         GfxMode::None
     }
 
-    ///
+    /// Get the action the user must take for the in-progress switch to complete
     pub(crate) async fn get_pending_user_action(&self) -> UserActionRequired {
-        let config = self.config.lock().await;
-        if let Some(action) = config.pending_action {
+        let runtime = self.runtime.lock().await;
+        if let Some(action) = runtime.pending_action {
             return action;
         }
         UserActionRequired::Nothing
     }
 
+    /// Get how the last mode switch ended
+    pub(crate) async fn get_switch_outcome(&self) -> SwitchOutcome {
+        *self.switch_outcome.lock().await
+    }
+
     /// Associated method to get list of supported modes
     pub(crate) async fn get_supported_modes(&self) -> Vec<GfxMode> {
         let mut list = vec![GfxMode::Integrated, GfxMode::Hybrid];
 
         let dgpu = self.dgpu.lock().await;
-        if matches!(dgpu.vendor(), GfxVendor::Unknown) && !asus_dgpu_disable_exists() {
+        if matches!(dgpu.vendor(), GfxVendor::Unknown) && !asus_dgpu_disable_exists(&self.paths) {
             return vec![GfxMode::Integrated];
         }
 
@@ -109,15 +271,15 @@ impl CtrlGraphics {
             list.push(GfxMode::Vfio);
         }
 
-        if asus_egpu_enable_exists() {
+        if asus_egpu_enable_exists(&self.paths) {
             list.push(GfxMode::AsusEgpu);
         }
 
-        if asus_gpu_mux_exists() {
+        if asus_gpu_mux_exists(&self.paths) {
             list.push(GfxMode::AsusMuxDgpu);
         }
 
-        if let Ok(Some(res)) = get_kernel_cmdline_nvidia_modeset() {
+        if let Ok(Some(res)) = get_kernel_cmdline_nvidia_modeset(&self.paths) {
             if !res {
                 list.push(GfxMode::NvidiaNoModeset);
             }
@@ -135,7 +297,7 @@ impl CtrlGraphics {
     /// Perform boot tasks required to set last saved mode
     async fn do_boot_tasks(
         mut mode: GfxMode,
-        config: &mut GfxConfig,
+        config: &mut GfxSettings,
         device: &mut DiscreetGpu,
     ) -> Result<(), GfxError> {
         debug!(
@@ -143,23 +305,26 @@ impl CtrlGraphics {
             config.vfio_enable, config.hotplug_type
         );
         // Absolutely must check the ASUS dgpu_disable and gpu mux sanity on boot
-        if let Ok(checked_mode) =
-            asus_boot_safety_check(mode, config.hotplug_type == HotplugType::Asus)
-                .await
-                .map_err(|e| {
-                    error!("asus_boot_safety_check errored: {e}");
-                })
-        {
+        if let Ok(checked_mode) = asus_boot_safety_check(
+            device.paths(),
+            mode,
+            config.hotplug_type == HotplugType::Asus,
+        )
+        .await
+        .map_err(|e| {
+            error!("asus_boot_safety_check errored: {e}");
+        }) {
             config.mode = checked_mode;
             mode = checked_mode;
         }
 
-        let loop_exit = Arc::new(AtomicBool::new(false));
+        // Nothing listens for boot progress
+        let ctx = SwitchContext::new(config.clone(), broadcast::channel(1).0);
 
         let actions = StagedAction::action_list_for_boot(config, device.vendor(), mode);
 
         for action in actions {
-            let res = action.perform(mode, device, loop_exit.clone()).await;
+            let res = action.perform(mode, device, &ctx).await;
 
             match res {
                 Ok(_) => {}
@@ -171,90 +336,337 @@ impl CtrlGraphics {
         Ok(())
     }
 
+    /// The user action and staged actions a switch from the current mode to `mode` needs
+    fn plan_switch(
+        config: &GfxSettings,
+        vendor: GfxVendor,
+        mode: GfxMode,
+    ) -> (UserActionRequired, actions::Action) {
+        let user_action_required = if config.always_reboot {
+            UserActionRequired::Reboot
+        } else {
+            UserActionRequired::mode_change_action(mode, config.mode)
+        };
+        let actions = StagedAction::action_list_for_switch(config, vendor, config.mode, mode);
+        (user_action_required, actions)
+    }
+
+    /// Work out what a switch to `mode` would do, without touching the system or the
+    /// pending state. If the switch can't be done directly the list of actions is empty.
+    pub async fn plan_gfx_mode(
+        &self,
+        mode: GfxMode,
+    ) -> Result<(UserActionRequired, Vec<StagedAction>), GfxError> {
+        mode_support_check(&self.paths, &mode)?;
+
+        let vendor = self.dgpu.lock().await.vendor();
+        let config = self.config.lock().await;
+        Ok(match Self::plan_switch(&config, vendor, mode) {
+            (_, actions::Action::UserAction(u)) => (u, Vec::new()),
+            (u, actions::Action::StagedActions(actions)) => (u, actions),
+        })
+    }
+
+    /// Cancel the pending mode change if it hasn't started changing the system yet, this
+    /// also stops any wait for logout. Returns the mode that was cancelled.
+    pub async fn cancel_gfx_mode(&self) -> Result<GfxMode, GfxError> {
+        let mut switch = self.switch_phase.lock().await;
+        let mut runtime = self.runtime.lock().await;
+        let mode = runtime.pending_mode.ok_or(GfxError::NoPendingMode)?;
+        // Nothing is running if the pending switch only needed a user action
+        if runtime.transaction_id == Some(switch.id) {
+            match switch.phase {
+                SwitchPhase::Running => return Err(GfxError::SwitchPastPointOfNoReturn(mode)),
+                SwitchPhase::Waiting => switch.phase = SwitchPhase::Cancelled,
+                SwitchPhase::Idle | SwitchPhase::Cancelled => {}
+            }
+        }
+        runtime.pending_mode = None;
+        runtime.pending_action = None;
+        runtime.save(&self.runtime_path);
+        self.stop.stop();
+        Ok(mode)
+    }
+
+    /// Check a config sent over dbus can be used on this machine
+    fn validate_config(&self, config: &GfxConfigDbus) -> Result<(), GfxError> {
+        if config.mode == GfxMode::Vfio && !config.vfio_enable {
+            return Err(GfxError::VfioDisabled);
+        }
+        if config.hotplug_type == HotplugType::Asus && !asus_dgpu_disable_exists(&self.paths) {
+            return Err(GfxError::NotSupported(
+                "Asus hotplug requested but the laptop doesn't have dgpu_disable".to_string(),
+            ));
+        }
+        mode_support_check(&self.paths, &config.mode)
+    }
+
+    /// Re-read the config file after it was edited, keeping the runtime state. Every
+    /// field but the mode is applied if the new config is valid, returning the config
+    /// if anything changed. The mode is only changed by a switch, so a different mode in
+    /// the file is ignored and the current mode is saved back.
+    pub async fn reload_config(&mut self) -> Result<Option<GfxConfigDbus>, GfxError> {
+        let mut config = self.config.lock().await;
+        let mut store = self.store.lock().await;
+        let mut new = store.read()?;
+        if new.mode != config.mode {
+            warn!(
+                "reload_config: Ignoring mode {} in {}, use supergfxctl --mode to switch",
+                new.mode, store.path
+            );
+            new.mode = config.mode;
+        }
+        let new_dbus = GfxConfigDbus::from(&new);
+        self.validate_config(&new_dbus)?;
+
+        let changed = new_dbus != GfxConfigDbus::from(&*config);
+        *config = new;
+        // Puts the current mode back, and is a no-op if the file is unchanged
+        store.write(&config)?;
+        if changed {
+            info!("reload_config: Applied the edited {}", store.path);
+            Ok(Some(new_dbus))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Validate, apply and save every field of `new`. If the mode differs from the
+    /// current mode a switch to it is started as with [`Self::set_gfx_mode`], and the
+    /// user action it requires is returned.
+    pub async fn set_gfx_config(
+        &mut self,
+        new: GfxConfigDbus,
+        requested_by: Option<u32>,
+    ) -> Result<Option<UserActionRequired>, GfxError> {
+        self.validate_config(&new)?;
+
+        let do_mode_change;
+        {
+            let mut config = self.config.lock().await;
+            do_mode_change =
+                config.mode != new.mode && self.runtime.lock().await.pending_mode != Some(new.mode);
+
+            config.vfio_enable = new.vfio_enable;
+            config.vfio_save = new.vfio_save;
+            config.always_reboot = new.always_reboot;
+            config.no_logind = new.no_logind;
+            config.logout_timeout_s = new.logout_timeout_s;
+            config.hotplug_type = new.hotplug_type;
+            // The mode is only saved once the switch to it is committed
+            self.store.lock().await.write(&config)?;
+        }
+
+        if do_mode_change {
+            return self.set_gfx_mode(new.mode, requested_by).await.map(Some);
+        }
+        Ok(None)
+    }
+
     /// Initiates a mode change by starting a thread that will wait until all
     /// graphical sessions are exited before performing the tasks required
     /// to switch modes.
     ///
+    /// The switch is a transaction: if any step fails the steps already completed are
+    /// undone in reverse order and the mode is left unchanged. Progress of each step and
+    /// the final result are sent to [`Self::subscribe_switch_events`] receivers.
+    ///
+    /// Until a step that changes the system is started the switch can be cancelled
+    /// with [`Self::cancel_gfx_mode`], or replaced by calling this again. After that a
+    /// new switch is refused until it has ended.
+    ///
+    /// Once finished the switch is recorded in the history along with `requested_by`,
+    /// the uid of the caller if known.
+    ///
     /// For manually calling (not on boot/startup) via dbus
-    pub async fn set_gfx_mode(&mut self, mode: GfxMode) -> Result<UserActionRequired, GfxError> {
-        mode_support_check(&mode)?;
-
-        self.loop_exit.store(false, Ordering::Release);
+    pub async fn set_gfx_mode(
+        &mut self,
+        mode: GfxMode,
+        requested_by: Option<u32>,
+    ) -> Result<UserActionRequired, GfxError> {
+        mode_support_check(&self.paths, &mode)?;
+
+        // First, stop any previous switch that is still waiting. One that is running
+        // can't be stopped, and a new switch would be planned from a stale mode.
+        {
+            let switch = self.switch_phase.lock().await;
+            if switch.phase == SwitchPhase::Running {
+                let running = self.runtime.lock().await.pending_mode.unwrap_or(mode);
+                return Err(GfxError::SwitchPastPointOfNoReturn(running));
+            }
+            self.stop.stop();
+        }
 
         let vendor = self.dgpu.lock().await.vendor();
+        let id = transaction_id();
+        let from;
         let user_action_required;
         let actions;
+        let ctx;
         {
-            let mut config = self.config.lock().await;
-            let from = config.mode;
-
-            if config.always_reboot {
-                user_action_required = UserActionRequired::Reboot;
-            } else {
-                user_action_required = UserActionRequired::mode_change_action(mode, config.mode);
+            let config = self.config.lock().await;
+            from = config.mode;
+            (user_action_required, actions) = Self::plan_switch(&config, vendor, mode);
+
+            // A switch with no steps is either refused or already done, so there is
+            // nothing to leave pending
+            if let actions::Action::StagedActions(_) = actions {
+                let mut runtime = self.runtime.lock().await;
+                runtime.pending_mode = Some(mode);
+                runtime.pending_action = Some(user_action_required);
+                // Marks the switch as in flight until it has ended, so it is known to
+                // have been interrupted if the daemon starts again with this still set
+                runtime.transaction_id = Some(id);
+                runtime.save(&self.runtime_path);
             }
-            actions = StagedAction::action_list_for_switch(&config, vendor, from, mode);
-
-            config.pending_mode = Some(mode);
-            config.pending_action = Some(user_action_required);
+            // The steps use the config as it is now, even if it is changed mid-switch
+            ctx = SwitchContext {
+                sessions: self.sessions.clone(),
+                ..SwitchContext::new(config.clone(), self.switch_events.clone())
+            };
         }
 
         // Start a thread to perform the actions on then return the user action required
-        // First, stop all threads
-        self.loop_exit.store(true, Ordering::Release);
-
         match actions {
             actions::Action::UserAction(u) => return Ok(u),
             actions::Action::StagedActions(actions) => {
+                self.stop = ctx.stop.clone();
                 let dgpu = self.dgpu.clone();
-                // This atomixc is to force an exit of any loops
-                let loop_exit = self.loop_exit.clone();
                 let config = self.config.clone();
+                let store = self.store.clone();
+                let runtime = self.runtime.clone();
+                let runtime_path = self.runtime_path.clone();
+                let switch_outcome = self.switch_outcome.clone();
+                let events = self.switch_events.clone();
+                let phase = self.switch_phase.clone();
+                let history = self.history.clone();
+                let mut entry = HistoryEntry::new(from, mode, requested_by, user_action_required);
+                *phase.lock().await = TaggedPhase {
+                    id,
+                    phase: SwitchPhase::Waiting,
+                };
                 // This will block if required to wait for logouts, so run concurrently.
                 tokio::spawn(async move {
-                    let mut failed = false;
-                    for action in actions {
-                        debug!("Doing action: {action:?}");
-                        let mut dgpu = dgpu.lock().await;
-
-                        let res = action.perform(mode, &mut dgpu, loop_exit.clone()).await;
-                        match res {
-                            Ok(_) => {}
-                            Err(GfxError::SystemdUnitWaitTimeout(e)) => {
-                                error!("Action thread errored: {e}");
-                                failed = true;
+                    let total_steps = actions.len() as u32;
+                    let progress = |step_index: usize, action, status| {
+                        // No receivers is fine
+                        events
+                            .send(SwitchEvent::Progress {
+                                step_index: step_index as u32,
+                                total_steps,
+                                action,
+                                status,
+                            })
+                            .ok();
+                    };
+
+                    let mut transaction = SwitchTransaction::new(from, mode);
+                    let mut error = None;
+                    let mut cancelled = false;
+                    for (step_index, action) in actions.into_iter().enumerate() {
+                        {
+                            let mut switch = phase.lock().await;
+                            if switch.id != id
+                                || switch.phase == SwitchPhase::Cancelled
+                                || ctx.stop.is_stopped()
+                            {
+                                cancelled = true;
                                 break;
                             }
-                            Err(e) => {
-                                error!("Action thread errored: {e}");
-                                failed = true;
+                            if action.changes_system() {
+                                switch.phase = SwitchPhase::Running;
+                            }
+                        }
+                        debug!("Doing action: {action:?}");
+                        progress(step_index, action, SwitchStepStatus::Started);
+                        let mut dgpu = dgpu.lock().await;
+                        let start = Instant::now();
+                        let res = transaction.perform(action, &mut dgpu, &ctx).await;
+                        entry.steps.push(HistoryStep {
+                            action,
+                            success: res.is_ok(),
+                            error: res
+                                .as_ref()
+                                .err()
+                                .map(|e| e.to_string())
+                                .unwrap_or_default(),
+                            duration_ms: start.elapsed().as_millis() as u64,
+                        });
+                        if let Err(e) = res {
+                            progress(step_index, action, SwitchStepStatus::Failed);
+                            if matches!(e, GfxError::SwitchStopped) {
+                                info!("Switch to {mode} stopped during {action:?}");
+                                cancelled = true;
+                            } else {
+                                error!("Action thread errored on {action:?}: {e}");
+                                error = Some(e);
                             }
+                            break;
                         }
+                        progress(step_index, action, SwitchStepStatus::Finished);
                     }
 
-                    let mut config = config.lock().await;
-                    config.pending_mode = None;
-                    config.pending_action = None;
-                    if !failed {
-                        config.mode = mode;
-                        config.write();
+                    // A switch stopped by a newer one may have got past the waiting steps
+                    let changed_system = transaction.completed().iter().any(|a| a.changes_system());
+                    let outcome = if cancelled && !changed_system {
+                        SwitchOutcome::Cancelled
+                    } else if cancelled || error.is_some() {
+                        warn!(
+                            "Rolling back completed actions: {:?}",
+                            transaction.completed()
+                        );
+                        let mut dgpu = dgpu.lock().await;
+                        transaction.rollback(&mut dgpu, &ctx).await
                     } else {
-                        let from = config.mode;
-                        let actions =
-                            StagedAction::action_list_for_switch(&config, vendor, mode, from);
-                        if let actions::Action::StagedActions(actions) = actions {
-                            for action in actions {
-                                debug!("Doing action: {action:?}");
-                                let mut dgpu = dgpu.lock().await;
-                                if let Err(e) =
-                                    action.perform(mode, &mut dgpu, loop_exit.clone()).await
-                                {
-                                    error!("Action thread errored fallback failed: {e}");
-                                    return;
-                                }
-                            }
+                        SwitchOutcome::Committed
+                    };
+                    info!("Switch from {from} to {mode}: {}", <&str>::from(outcome));
+
+                    if outcome == SwitchOutcome::Committed {
+                        let mut config = config.lock().await;
+                        config.mode = mode;
+                        store
+                            .lock()
+                            .await
+                            .write(&config)
+                            .unwrap_or_else(|e| error!("Could not save the config: {e}"));
+                    }
+                    // The pending state and phase are left alone if a newer switch owns them
+                    {
+                        let mut runtime = runtime.lock().await;
+                        if runtime.transaction_id == Some(id) {
+                            runtime.pending_mode = None;
+                            runtime.pending_action = None;
+                            runtime.transaction_id = None;
+                            runtime.save(&runtime_path);
+                        }
+                    }
+                    {
+                        let mut switch = phase.lock().await;
+                        if switch.id == id {
+                            switch.phase = SwitchPhase::Idle;
                         }
                     }
+                    *switch_outcome.lock().await = outcome;
+
+                    entry.outcome = outcome;
+                    history
+                        .record(&entry)
+                        .unwrap_or_else(|e| warn!("Could not record switch history: {e}"));
+
+                    let success = outcome == SwitchOutcome::Committed;
+                    events
+                        .send(SwitchEvent::Result {
+                            success,
+                            mode: if success { mode } else { from },
+                            error_text: match error {
+                                Some(e) => e.to_string(),
+                                None if cancelled => format!("The change to {mode} was cancelled"),
+                                None => String::new(),
+                            },
+                            outcome,
+                        })
+                        .ok();
                 });
             }
         }
@@ -262,3 +674,11 @@ impl CtrlGraphics {
         Ok(user_action_required)
     }
 }
+
+/// An id for a new switch, the time it started in microseconds
+fn transaction_id() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_micros() as u64)
+        .unwrap_or_default()
+}
@@ -1,19 +1,25 @@
-use std::{env, sync::Arc, time::Duration};
+use std::{env, path::PathBuf, sync::Arc, time::Duration};
 
 use futures_util::{lock::Mutex, StreamExt};
-use log::{error, info, trace};
+use log::{error, info, trace, warn};
 use logind_zbus::manager::ManagerProxy;
 use supergfxctl::{
-    config::GfxConfig,
+    actions::SwitchEvent,
+    command::SystemCommandRunner,
+    config::{ConfigStore, GfxSettings},
+    config_watch::watch_config,
     controller::CtrlGraphics,
     error::GfxError,
-    pci_device::{DiscreetGpu, GfxMode, GfxPower, HotplugType},
+    host_paths::HostPaths,
+    pci_device::{DiscreetGpu, GfxMode, HotplugType},
+    polkit::SystemAuthority,
+    power_watch::watch_power_status,
     special_asus::{asus_dgpu_disable_exists, asus_dgpu_set_disabled},
-    CONFIG_PATH, DBUS_DEST_NAME, DBUS_IFACE_PATH, VERSION,
+    DBUS_DEST_NAME, DBUS_IFACE_PATH, VERSION,
 };
-use tokio::time::sleep;
+use tokio::{sync::broadcast, time::sleep};
 use zbus::Connection;
-use zbus::{object_server::SignalEmitter, zvariant::ObjectPath};
+use zbus::{object_server::InterfaceRef, zvariant::ObjectPath};
 
 #[tokio::main]
 async fn main() -> Result<(), GfxError> {
@@ -50,26 +56,42 @@ async fn start_daemon() -> Result<(), GfxError> {
     // Request dbus name after finishing initalizing all functions
     connection.request_name(DBUS_DEST_NAME).await?;
 
-    let config = GfxConfig::load(CONFIG_PATH.into());
+    let paths = HostPaths::from_env();
+    if !paths.is_system_root() {
+        info!("Using {:?} as the system root", paths.root());
+    }
+
+    let mut store = ConfigStore::new(
+        paths.config().to_string_lossy().into(),
+        paths.config_dropin_dirs(),
+    );
+    let config = store.load()?;
+    let config_path = store.path.clone();
+    let dropin_dirs = store.layers.dirs.clone();
+    let config_warning = store.warning.take();
     let use_logind = !config.no_logind;
     let config = Arc::new(Mutex::new(config));
 
     if use_logind {
-        start_logind_tasks(config.clone()).await;
+        start_logind_tasks(config.clone(), paths.clone()).await;
     }
 
     // Graphics switching requires some checks on boot specifically for g-sync capable laptops
-    match CtrlGraphics::new(config.clone()) {
+    match CtrlGraphics::new(
+        config.clone(),
+        store,
+        paths,
+        Arc::new(SystemCommandRunner),
+        Arc::new(SystemAuthority::new(connection.clone())),
+    ) {
         Ok(mut ctrl) => {
+            ctrl.recover_interrupted_switch().await;
             ctrl.reload()
                 .await
                 .unwrap_or_else(|err| error!("Gfx controller: {}", err));
 
-            let signal_context = SignalEmitter::new(&connection, DBUS_IFACE_PATH)?;
-            start_notify_status(ctrl.dgpu_arc_clone(), signal_context)
-                .await
-                .ok();
-
+            let dgpu = ctrl.dgpu_arc_clone();
+            let switch_events = ctrl.subscribe_switch_events();
             connection
                 .object_server()
                 .at(&ObjectPath::from_str_unchecked(DBUS_IFACE_PATH), ctrl)
@@ -79,6 +101,20 @@ async fn start_daemon() -> Result<(), GfxError> {
                 //     err
                 // })
                 .ok();
+
+            let iface = connection
+                .object_server()
+                .interface::<_, CtrlGraphics>(DBUS_IFACE_PATH)
+                .await?;
+            start_notify_status(dgpu, iface.clone()).await.ok();
+            if let Some(warning) = config_warning {
+                CtrlGraphics::notify_config_warning(iface.signal_emitter(), &warning)
+                    .await
+                    .map_err(|e| trace!("{e}"))
+                    .ok();
+            }
+            start_notify_switch(switch_events, iface.clone()).await.ok();
+            start_config_watch(&config_path, &dropin_dirs, iface);
         }
         Err(err) => {
             error!("Gfx control: {}", err);
@@ -95,32 +131,118 @@ async fn start_daemon() -> Result<(), GfxError> {
 
 async fn start_notify_status(
     dgpu: Arc<Mutex<DiscreetGpu>>,
-    signal_ctxt: SignalEmitter<'static>,
+    iface: InterfaceRef<CtrlGraphics>,
 ) -> Result<(), GfxError> {
+    let mut status = watch_power_status(dgpu);
     tokio::spawn(async move {
-        let mut last_status = GfxPower::Unknown;
-        loop {
-            let s = dgpu
-                .lock()
+        let signal_ctxt = iface.signal_emitter();
+        while let Some(s) = status.recv().await {
+            trace!("Notify: dGPU status = {s:?}");
+            CtrlGraphics::notify_gfx_status(signal_ctxt, &s)
+                .await
+                .map_err(|e| trace!("{e}"))
+                .ok();
+            iface
+                .get()
+                .await
+                .power_properties_changed(signal_ctxt)
                 .await
-                .get_runtime_status()
                 .map_err(|e| trace!("{e}"))
-                .unwrap_or(GfxPower::Unknown);
-            if s != last_status {
-                last_status = s;
-                trace!("Notify: dGPU status = {s:?}");
-                CtrlGraphics::notify_gfx_status(&signal_ctxt, &last_status)
+                .ok();
+        }
+    });
+    Ok(())
+}
+
+/// Reload the config when the file or a drop-in is edited, or on SIGHUP
+fn start_config_watch(
+    config_path: &str,
+    dropin_dirs: &[PathBuf],
+    iface: InterfaceRef<CtrlGraphics>,
+) {
+    let mut changes = watch_config(config_path, dropin_dirs);
+    tokio::spawn(async move {
+        while changes.recv().await.is_some() {
+            iface
+                .get_mut()
+                .await
+                .config_file_changed(iface.signal_emitter())
+                .await;
+        }
+    });
+}
+
+async fn start_notify_switch(
+    mut events: broadcast::Receiver<SwitchEvent>,
+    iface: InterfaceRef<CtrlGraphics>,
+) -> Result<(), GfxError> {
+    tokio::spawn(async move {
+        let signal_ctxt = iface.signal_emitter();
+        loop {
+            let event = match events.recv().await {
+                Ok(event) => event,
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    warn!("Notify: missed {n} switch events");
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            };
+            trace!("Notify: {event:?}");
+            let res = match event {
+                SwitchEvent::Progress {
+                    step_index,
+                    total_steps,
+                    action,
+                    status,
+                } => {
+                    CtrlGraphics::notify_switch_progress(
+                        signal_ctxt,
+                        step_index,
+                        total_steps,
+                        &action,
+                        &status,
+                    )
                     .await
-                    .map_err(|e| trace!("{e}"))
-                    .ok();
-            }
-            sleep(Duration::from_secs(1)).await;
+                }
+                SwitchEvent::WaitingForLogout { sessions } => {
+                    CtrlGraphics::notify_waiting_for_logout(signal_ctxt, &sessions).await
+                }
+                SwitchEvent::Result {
+                    success,
+                    mode,
+                    error_text,
+                    outcome,
+                } => {
+                    if success {
+                        CtrlGraphics::notify_gfx(signal_ctxt, &mode)
+                            .await
+                            .map_err(|e| trace!("{e}"))
+                            .ok();
+                    }
+                    iface
+                        .get()
+                        .await
+                        .switch_properties_changed(signal_ctxt)
+                        .await
+                        .map_err(|e| trace!("{e}"))
+                        .ok();
+                    CtrlGraphics::notify_switch_outcome(
+                        signal_ctxt,
+                        &outcome,
+                        success,
+                        &mode,
+                        &error_text,
+                    )
+                    .await
+                }
+            };
+            res.map_err(|e| trace!("{e}")).ok();
         }
     });
     Ok(())
 }
 
-async fn start_logind_tasks(config: Arc<Mutex<GfxConfig>>) {
+async fn start_logind_tasks(config: Arc<Mutex<GfxSettings>>, paths: HostPaths) {
     let connection = Connection::system()
         .await
         .expect("Controller could not create dbus connection");
@@ -138,10 +260,10 @@ async fn start_logind_tasks(config: Arc<Mutex<GfxConfig>>) {
                         let config = config.lock().await;
                         if config.mode == GfxMode::Integrated
                             && config.hotplug_type == HotplugType::Asus
-                            && asus_dgpu_disable_exists()
+                            && asus_dgpu_disable_exists(&paths)
                         {
                             info!("logind task: Waking from suspend, setting dgpu_disable");
-                            asus_dgpu_set_disabled(true)
+                            asus_dgpu_set_disabled(&paths, true)
                                 .map_err(|e| error!("logind task: {e}"))
                                 .ok();
                         }
@@ -0,0 +1,216 @@
+use std::{fmt::Display, fs, path::Path};
+
+use serde::{Deserialize, Serialize};
+use zbus::zvariant::Type;
+
+use crate::{
+    config::{GfxConfigDbus, CONFIG_KEYS},
+    host_paths::HostPaths,
+    pci_device::DiscreetGpu,
+    VERSION,
+};
+
+pub(crate) const PROC_MODULES_PATH: &str = "/proc/modules";
+
+/// Module name prefixes of the GPU drivers listed in a report
+const GPU_MODULE_PREFIXES: [&str; 7] = [
+    "nvidia", "nouveau", "amdgpu", "radeon", "i915", "xe", "vfio",
+];
+
+/// The state of one detected graphics device. Fields that could not be read are empty.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, Type)]
+pub struct DeviceDiagnostics {
+    /// System name given by the kernel, e.g `0000:01:00.0`
+    pub name: String,
+    /// Vendor:Device
+    pub pci_id: String,
+    pub vendor: String,
+    pub is_dgpu: bool,
+    pub hotplug_path: String,
+    /// Name of the bound driver
+    pub driver: String,
+    pub runtime_status: String,
+}
+
+/// A snapshot of everything that decides how a mode switch behaves, for bug reports.
+/// Files or values that don't exist on the system are empty.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Type)]
+pub struct Diagnostics {
+    pub version: String,
+    pub vendor: String,
+    pub devices: Vec<DeviceDiagnostics>,
+    pub asus_dgpu_disable: String,
+    pub asus_egpu_enable: String,
+    pub asus_gpu_mux_mode: String,
+    /// The `supergfxd.mode` kernel cmdline value
+    pub cmdline_mode: String,
+    /// The `nvidia-drm.modeset` kernel cmdline value
+    pub cmdline_nvidia_modeset: String,
+    /// Content of `/etc/modprobe.d/supergfxd.conf`
+    pub modprobe_conf: String,
+    /// `active`, `inactive` or `missing` for the Nvidia Vulkan ICD
+    pub vulkan_icd: String,
+    /// Loaded GPU driver modules
+    pub modules: Vec<String>,
+    pub config: GfxConfigDbus,
+}
+
+impl Diagnostics {
+    /// Collect the state of `dgpu` and the system it was found on. Nothing is written.
+    pub fn collect(dgpu: &DiscreetGpu, config: GfxConfigDbus) -> Self {
+        let paths = dgpu.paths();
+        let devices = dgpu
+            .devices()
+            .iter()
+            .map(|dev| DeviceDiagnostics {
+                name: dev.name().to_string(),
+                pci_id: dev.pci_id().to_string(),
+                vendor: <&str>::from(dev.vendor()).to_string(),
+                is_dgpu: dev.is_dgpu(),
+                hotplug_path: dev
+                    .hotplug_path()
+                    .map(|p| p.to_string_lossy().to_string())
+                    .unwrap_or_default(),
+                driver: dev
+                    .driver()
+                    .ok()
+                    .and_then(|p| p.file_name().map(|n| n.to_string_lossy().to_string()))
+                    .unwrap_or_default(),
+                runtime_status: read_trimmed(&dev.dev_path().join("power/runtime_status")),
+            })
+            .collect();
+
+        let cmdline = read_trimmed(&paths.kernel_cmdline());
+        Self {
+            version: VERSION.to_string(),
+            vendor: <&str>::from(dgpu.vendor()).to_string(),
+            devices,
+            asus_dgpu_disable: read_trimmed(&paths.asus_dgpu_disable()),
+            asus_egpu_enable: read_trimmed(&paths.asus_egpu_enable()),
+            asus_gpu_mux_mode: read_trimmed(&paths.asus_gpu_mux()),
+            cmdline_mode: cmdline_value(&cmdline, "supergfxd.mode"),
+            cmdline_nvidia_modeset: cmdline_value(&cmdline, "nvidia-drm.modeset"),
+            modprobe_conf: fs::read_to_string(paths.modprobe_conf()).unwrap_or_default(),
+            vulkan_icd: vulkan_icd_state(paths).to_string(),
+            modules: gpu_modules(&fs::read_to_string(paths.proc_modules()).unwrap_or_default()),
+            config,
+        }
+    }
+}
+
+fn read_trimmed(path: &Path) -> String {
+    fs::read_to_string(path)
+        .map(|s| s.trim().to_string())
+        .unwrap_or_default()
+}
+
+/// The value of `key=value` in the kernel cmdline, empty if not set
+pub fn cmdline_value(cmdline: &str, key: &str) -> String {
+    cmdline
+        .split_whitespace()
+        .find_map(|arg| arg.strip_prefix(key)?.strip_prefix('='))
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// The GPU driver modules in the content of `/proc/modules`
+pub fn gpu_modules(proc_modules: &str) -> Vec<String> {
+    proc_modules
+        .lines()
+        .filter_map(|line| line.split_whitespace().next())
+        .filter(|name| {
+            GPU_MODULE_PREFIXES
+                .iter()
+                .any(|prefix| name.starts_with(prefix))
+        })
+        .map(|name| name.to_string())
+        .collect()
+}
+
+fn vulkan_icd_state(paths: &HostPaths) -> &'static str {
+    let icd = paths.nvidia_vkicd();
+    let mut inactive = icd.clone().into_os_string();
+    inactive.push("_inactive");
+    if icd.exists() {
+        "active"
+    } else if Path::new(&inactive).exists() {
+        "inactive"
+    } else {
+        "missing"
+    }
+}
+
+fn or_unset(value: &str) -> &str {
+    if value.is_empty() {
+        "<not set>"
+    } else {
+        value
+    }
+}
+
+impl Display for Diagnostics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "supergfxd version: {}", self.version)?;
+        writeln!(f, "dGPU vendor: {}", self.vendor)?;
+        writeln!(f, "Devices:")?;
+        if self.devices.is_empty() {
+            writeln!(f, "  <none found>")?;
+        }
+        for dev in &self.devices {
+            writeln!(
+                f,
+                "  {} [{}] {}{}",
+                dev.name,
+                dev.pci_id,
+                dev.vendor,
+                if dev.is_dgpu { " (dGPU)" } else { "" }
+            )?;
+            writeln!(f, "    driver: {}", or_unset(&dev.driver))?;
+            writeln!(f, "    runtime status: {}", or_unset(&dev.runtime_status))?;
+            writeln!(f, "    hotplug path: {}", or_unset(&dev.hotplug_path))?;
+        }
+        writeln!(
+            f,
+            "ASUS dgpu_disable: {}",
+            or_unset(&self.asus_dgpu_disable)
+        )?;
+        writeln!(f, "ASUS egpu_enable: {}", or_unset(&self.asus_egpu_enable))?;
+        writeln!(
+            f,
+            "ASUS gpu_mux_mode: {}",
+            or_unset(&self.asus_gpu_mux_mode)
+        )?;
+        writeln!(f, "Kernel supergfxd.mode: {}", or_unset(&self.cmdline_mode))?;
+        writeln!(
+            f,
+            "Kernel nvidia-drm.modeset: {}",
+            or_unset(&self.cmdline_nvidia_modeset)
+        )?;
+        writeln!(f, "Nvidia Vulkan ICD: {}", self.vulkan_icd)?;
+        writeln!(
+            f,
+            "Loaded GPU modules: {}",
+            if self.modules.is_empty() {
+                "<none>".to_string()
+            } else {
+                self.modules.join(", ")
+            }
+        )?;
+        writeln!(f, "Config:")?;
+        writeln!(f, "  mode: {}", self.config.mode)?;
+        for key in CONFIG_KEYS {
+            if let Ok(value) = self.config.get(key) {
+                writeln!(f, "  {key}: {value}")?;
+            }
+        }
+        write!(f, "modprobe.d/supergfxd.conf:")?;
+        if self.modprobe_conf.trim().is_empty() {
+            write!(f, " <empty or missing>")
+        } else {
+            for line in self.modprobe_conf.trim_end().lines() {
+                write!(f, "\n  {line}")?;
+            }
+            Ok(())
+        }
+    }
+}
@@ -2,15 +2,26 @@ use std::fmt;
 use std::{error, path::PathBuf};
 
 use crate::actions::StagedAction;
+use crate::pci_device::GfxMode;
 
 #[derive(Debug)]
 pub enum GfxError {
     ParseVendor,
-    ParseMode,
+    /// The mode name that couldn't be parsed
+    ParseMode(String),
+    ParseHotplugType,
+    /// The config file content can't be parsed or migrated
+    ParseConfig(String),
+    /// A config key that can't be read or set from the CLI
+    UnknownConfigKey(String),
+    /// `InvalidConfigValue(key, value)`
+    InvalidConfigValue(String, String),
     DgpuNotFound,
     Udev(String, std::io::Error),
     SystemdUnitAction(String),
     SystemdUnitWaitTimeout(String),
+    /// Seconds waited for graphical sessions to log out
+    LogoutTimeout(u64),
     AsusGpuMuxModeDiscreet,
     VfioBuiltin,
     VfioDisabled,
@@ -26,6 +37,13 @@ pub enum GfxError {
     ZbusFdo(zbus::fdo::Error),
     /// `IncorrectActionOrder(this_action, last_action)`
     IncorrectActionOrder(StagedAction, StagedAction),
+    NoPendingMode,
+    /// The switch was stopped by a cancel or a newer mode change before it finished
+    SwitchStopped,
+    /// The switch to this mode has started changing the system and can't be cancelled
+    SwitchPastPointOfNoReturn(GfxMode),
+    /// The caller is not authorized by polkit for this action id
+    NotAuthorized(String),
 }
 
 impl GfxError {
@@ -39,7 +57,27 @@ impl fmt::Display for GfxError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             GfxError::ParseVendor => write!(f, "Could not parse vendor name"),
-            GfxError::ParseMode => write!(f, "Could not parse mode name"),
+            GfxError::ParseMode(mode) => {
+                let modes: Vec<String> = GfxMode::ALL.iter().map(|m| m.to_string()).collect();
+                write!(
+                    f,
+                    "Unknown mode {mode}, valid modes are: {} (in any case), or the aliases igpu, hybrid, dgpu, vfio, egpu and compute",
+                    modes.join(", ")
+                )
+            }
+            GfxError::ParseHotplugType => write!(
+                f,
+                "Could not parse hotplug type, valid types are Std, Asus and None"
+            ),
+            GfxError::ParseConfig(err) => write!(f, "Could not parse config: {err}"),
+            GfxError::UnknownConfigKey(key) => write!(
+                f,
+                "Unknown config key {key}, valid keys are: {}",
+                crate::config::CONFIG_KEYS.join(", ")
+            ),
+            GfxError::InvalidConfigValue(key, value) => {
+                write!(f, "Invalid value {value} for config key {key}")
+            }
             GfxError::DgpuNotFound => write!(
                 f,
                 "Didn't find dgpu. If this is an ASUS ROG/TUF laptop this is okay"
@@ -55,6 +93,10 @@ impl fmt::Display for GfxError {
                     state
                 )
             }
+            GfxError::LogoutTimeout(secs) => write!(
+                f,
+                "Timed out after {secs} seconds waiting for all graphical sessions to log out"
+            ),
             GfxError::AsusGpuMuxModeDiscreet => write!(
                 f,
                 "Can not switch gfx modes when discreet/G-Sync mode is active"
@@ -86,6 +128,15 @@ impl fmt::Display for GfxError {
                 f,
                 "The order of actions is incorrect: {last_action:?} should not be before {this_action:?}"
             ),
+            GfxError::NoPendingMode => write!(f, "There is no pending mode change to cancel"),
+            GfxError::SwitchStopped => write!(f, "The mode change was stopped"),
+            GfxError::SwitchPastPointOfNoReturn(mode) => write!(
+                f,
+                "The change to {mode} has already started and can not be cancelled"
+            ),
+            GfxError::NotAuthorized(action_id) => {
+                write!(f, "Not authorized for {action_id}")
+            }
         }
     }
 }
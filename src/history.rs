@@ -0,0 +1,186 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::{BufRead, BufReader, ErrorKind, Write},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+use zbus::zvariant::Type;
+
+use crate::{
+    actions::{StagedAction, SwitchOutcome, UserActionRequired},
+    config::write_atomic,
+    error::GfxError,
+    pci_device::GfxMode,
+};
+
+/// The switch history is daemon state, not config, so it is kept out of `/etc`
+pub const HISTORY_PATH: &str = "/var/lib/supergfxd/history";
+/// How many switches are kept in the history file
+pub const MAX_HISTORY_ENTRIES: usize = 200;
+/// The `uid` of a switch that wasn't requested over dbus, e.g by a test or on boot
+pub const UNKNOWN_UID: u32 = u32::MAX;
+
+/// The result of one step of a mode switch
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Type)]
+pub struct HistoryStep {
+    pub action: StagedAction,
+    pub success: bool,
+    /// Empty if the step succeeded
+    pub error: String,
+    pub duration_ms: u64,
+}
+
+/// One mode switch, as recorded once it has finished
+#[derive(Debug, Clone, Serialize, Deserialize, Type)]
+pub struct HistoryEntry {
+    /// Seconds since the unix epoch when the switch was requested
+    pub timestamp: u64,
+    pub from: GfxMode,
+    pub to: GfxMode,
+    /// The uid of the dbus caller which requested the switch, [`UNKNOWN_UID`] if not known
+    pub uid: u32,
+    pub user_action: UserActionRequired,
+    pub outcome: SwitchOutcome,
+    /// Every step that was attempted, in order. Steps undone by a rollback are not repeated.
+    pub steps: Vec<HistoryStep>,
+}
+
+impl HistoryEntry {
+    pub fn new(
+        from: GfxMode,
+        to: GfxMode,
+        uid: Option<u32>,
+        user_action: UserActionRequired,
+    ) -> Self {
+        Self {
+            timestamp: unix_now(),
+            from,
+            to,
+            uid: uid.unwrap_or(UNKNOWN_UID),
+            user_action,
+            outcome: SwitchOutcome::None,
+            steps: Vec::new(),
+        }
+    }
+}
+
+/// Seconds since the unix epoch
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Format seconds since the unix epoch as `YYYY-MM-DD HH:MM:SS` in UTC
+pub fn format_timestamp(timestamp: u64) -> String {
+    let days = (timestamp / 86400) as i64;
+    let secs = timestamp % 86400;
+    // Civil from days, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02}",
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+/// A bounded log of finished mode switches, stored as one JSON object per line
+#[derive(Debug, Clone)]
+pub struct SwitchHistory {
+    path: PathBuf,
+    max_entries: usize,
+}
+
+impl SwitchHistory {
+    pub fn new(path: impl Into<PathBuf>, max_entries: usize) -> Self {
+        Self {
+            path: path.into(),
+            max_entries,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Append an entry, dropping the oldest entries if there are more than the max. The
+    /// file is replaced atomically when entries are dropped, so a crash can only lose the
+    /// entry being appended.
+    pub fn record(&self, entry: &HistoryEntry) -> Result<(), GfxError> {
+        let line = serde_json::to_string(entry).map_err(|e| {
+            GfxError::Write(
+                self.path.to_string_lossy().to_string(),
+                std::io::Error::new(ErrorKind::InvalidData, e),
+            )
+        })?;
+
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)
+                .map_err(|e| GfxError::Write(dir.to_string_lossy().to_string(), e))?;
+        }
+
+        let mut entries = self.read_lines()?;
+        if entries.len() >= self.max_entries {
+            entries.drain(..=entries.len() - self.max_entries);
+            entries.push(line);
+            let mut content = entries.join("\n");
+            content.push('\n');
+            return write_atomic(&self.path.to_string_lossy(), content.as_bytes());
+        }
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(|e| GfxError::Write(self.path.to_string_lossy().to_string(), e))?;
+        writeln!(file, "{line}")
+            .map_err(|e| GfxError::Write(self.path.to_string_lossy().to_string(), e))
+    }
+
+    /// Get up to `limit` of the most recent entries, newest first. A `limit` of 0 gets
+    /// every entry. Lines that can't be parsed are skipped.
+    pub fn entries(&self, limit: usize) -> Result<Vec<HistoryEntry>, GfxError> {
+        let limit = if limit == 0 { usize::MAX } else { limit };
+        Ok(self
+            .read_lines()?
+            .iter()
+            .rev()
+            .filter_map(|line| {
+                serde_json::from_str(line)
+                    .map_err(|e| warn!("Skipping bad history entry in {:?}: {e}", self.path))
+                    .ok()
+            })
+            .take(limit)
+            .collect())
+    }
+
+    fn read_lines(&self) -> Result<Vec<String>, GfxError> {
+        let file = match fs::File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(GfxError::Read(self.path.to_string_lossy().to_string(), e)),
+        };
+        let mut lines = Vec::new();
+        for line in BufReader::new(file).lines() {
+            let line =
+                line.map_err(|e| GfxError::Read(self.path.to_string_lossy().to_string(), e))?;
+            if !line.trim().is_empty() {
+                lines.push(line);
+            }
+        }
+        Ok(lines)
+    }
+}
@@ -0,0 +1,137 @@
+use std::{
+    env,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    diagnostics::PROC_MODULES_PATH,
+    history::HISTORY_PATH,
+    pci_device::PCI_BUS_PATH,
+    runtime_state::RUNTIME_STATE_PATH,
+    special_asus::{
+        ASUS_DGPU_DISABLE_PATH, ASUS_EGPU_ALT_ENABLE_PATH, ASUS_EGPU_ENABLE_PATH,
+        ASUS_GPU_MUX_PATH, ASUS_MODULES_LOAD_PATH,
+    },
+    CONFIG_DROPIN_DIRS, CONFIG_NVIDIA_VKICD, CONFIG_PATH, KERNEL_CMDLINE, MODPROBE_PATH, SLOTS,
+};
+
+/// Environment variable that can be set to run the daemon against a fake hardware tree
+pub const HOST_ROOT_ENV: &str = "SUPERGFXD_ROOT";
+
+const NVIDIA_DEV_PATH: &str = "/dev/nvidia0";
+const LSOF_PATH: &str = "/usr/bin/lsof";
+
+/// All the sysfs, procfs and config paths the daemon touches, resolved against a root
+/// directory. The root is `/` on a real system, but can be pointed at a temporary
+/// directory populated with fake sysfs files for testing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostPaths {
+    root: PathBuf,
+}
+
+impl Default for HostPaths {
+    fn default() -> Self {
+        Self::new("/")
+    }
+}
+
+impl HostPaths {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    /// Use the root set in `SUPERGFXD_ROOT` if any, otherwise `/`
+    pub fn from_env() -> Self {
+        env::var_os(HOST_ROOT_ENV)
+            .map(Self::new)
+            .unwrap_or_default()
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// True if this is the real system root and not a fake tree
+    pub fn is_system_root(&self) -> bool {
+        self.root == Path::new("/")
+    }
+
+    /// Resolve an absolute system path against the root
+    pub fn resolve(&self, path: impl AsRef<Path>) -> PathBuf {
+        let path = path.as_ref();
+        self.root.join(path.strip_prefix("/").unwrap_or(path))
+    }
+
+    pub fn pci_bus(&self) -> PathBuf {
+        self.resolve(PCI_BUS_PATH)
+    }
+
+    pub fn pci_devices(&self) -> PathBuf {
+        self.pci_bus().join("devices")
+    }
+
+    pub fn pci_slots(&self) -> PathBuf {
+        self.resolve(SLOTS)
+    }
+
+    pub fn kernel_cmdline(&self) -> PathBuf {
+        self.resolve(KERNEL_CMDLINE)
+    }
+
+    pub fn proc_modules(&self) -> PathBuf {
+        self.resolve(PROC_MODULES_PATH)
+    }
+
+    pub fn modprobe_conf(&self) -> PathBuf {
+        self.resolve(MODPROBE_PATH)
+    }
+
+    pub fn config(&self) -> PathBuf {
+        self.resolve(CONFIG_PATH)
+    }
+
+    pub fn config_dropin_dirs(&self) -> Vec<PathBuf> {
+        CONFIG_DROPIN_DIRS.iter().map(|d| self.resolve(d)).collect()
+    }
+
+    pub fn runtime_state(&self) -> PathBuf {
+        self.resolve(RUNTIME_STATE_PATH)
+    }
+
+    pub fn history(&self) -> PathBuf {
+        self.resolve(HISTORY_PATH)
+    }
+
+    pub fn nvidia_vkicd(&self) -> PathBuf {
+        self.resolve(CONFIG_NVIDIA_VKICD)
+    }
+
+    pub fn nvidia_dev(&self) -> PathBuf {
+        self.resolve(NVIDIA_DEV_PATH)
+    }
+
+    pub fn lsof(&self) -> PathBuf {
+        self.resolve(LSOF_PATH)
+    }
+
+    pub fn asus_dgpu_disable(&self) -> PathBuf {
+        self.resolve(ASUS_DGPU_DISABLE_PATH)
+    }
+
+    /// The egpu_enable path moved in newer kernels, this returns whichever exists
+    pub fn asus_egpu_enable(&self) -> PathBuf {
+        let alt = self.resolve(ASUS_EGPU_ALT_ENABLE_PATH);
+        if alt.exists() {
+            return alt;
+        }
+        self.resolve(ASUS_EGPU_ENABLE_PATH)
+    }
+
+    pub fn asus_gpu_mux(&self) -> PathBuf {
+        self.resolve(ASUS_GPU_MUX_PATH)
+    }
+
+    pub fn asus_modules_load(&self) -> PathBuf {
+        self.resolve(ASUS_MODULES_LOAD_PATH)
+    }
+}
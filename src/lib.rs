@@ -2,22 +2,45 @@ use std::{
     fs::OpenOptions,
     io::Read,
     path::{Path, PathBuf},
-    process::Command,
     str::FromStr,
 };
 
 use log::{debug, error, info, warn};
 use pci_device::GfxVendor;
 
-use crate::{error::GfxError, pci_device::GfxMode, special_asus::*};
+use crate::{
+    command::{command_line, CommandRunner},
+    error::GfxError,
+    host_paths::HostPaths,
+    pci_device::GfxMode,
+    special_asus::*,
+};
 
+/// Abstraction over spawning external commands so they can be faked in tests
+pub mod command;
+/// Shell completion scripts and man page generation for the CLI
+pub mod completions;
 /// The configuration for graphics. This should be saved and loaded on boot.
 pub mod config;
 mod config_old;
+/// Watching the config file for edits and SIGHUP to reload it
+pub mod config_watch;
 /// Control functions for setting graphics.
 pub mod controller;
+/// Collection of the system state that affects switching, for bug reports
+pub mod diagnostics;
 /// Error: 404
 pub mod error;
+/// Persistent log of finished mode switches
+pub mod history;
+/// Resolution of all system paths against a configurable root
+pub mod host_paths;
+/// Polkit authorization of callers
+pub mod polkit;
+/// Event driven watching of the dGPU power status
+pub mod power_watch;
+/// The daemon state that isn't saved in the config, such as a pending mode change
+pub mod runtime_state;
 /// Special-case functions for check/read/write of key functions on unique laptops
 /// such as the G-Sync mode available on some ASUS ROG laptops
 pub mod special_asus;
@@ -43,6 +66,8 @@ mod tests;
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 /// Generic path that is used to save the daemon config state
 pub const CONFIG_PATH: &str = "/etc/supergfxd.conf";
+/// Directories of `*.json` config drop-ins layered under the config, lowest priority first
+pub const CONFIG_DROPIN_DIRS: [&str; 2] = ["/usr/lib/supergfxd.conf.d", "/etc/supergfxd.conf.d"];
 /// Destination name to be used in the daemon when setting up DBUS connection
 pub const DBUS_DEST_NAME: &str = "org.supergfxctl.Daemon";
 /// Generic icd-profile (vulkan)
@@ -54,8 +79,6 @@ pub const KERNEL_CMDLINE: &str = "/proc/cmdline";
 
 const SLOTS: &str = "/sys/bus/pci/slots";
 
-const NOUVEAU_DRIVERS: [&str; 1] = ["nouveau"];
-
 const NVIDIA_DRIVERS: [&str; 5] = [
     "nvidia_drm",
     "nvidia_modeset",
@@ -121,8 +144,8 @@ impl From<DriverAction> for &str {
 }
 
 /// Basic check for support. If `()` returned everything is kosher.
-fn mode_support_check(mode: &GfxMode) -> Result<(), GfxError> {
-    if matches!(mode, GfxMode::AsusEgpu) && !asus_egpu_enable_exists() {
+fn mode_support_check(paths: &HostPaths, mode: &GfxMode) -> Result<(), GfxError> {
+    if matches!(mode, GfxMode::AsusEgpu) && !asus_egpu_enable_exists(paths) {
         let text = "Egpu mode requested when either the laptop doesn't support it or the kernel is not recent enough".to_string();
         return Err(GfxError::NotSupported(text));
     }
@@ -130,14 +153,17 @@ fn mode_support_check(mode: &GfxMode) -> Result<(), GfxError> {
 }
 
 /// Add or remove driver modules
-fn do_driver_action(driver: &str, action: DriverAction) -> Result<(), GfxError> {
-    let mut cmd = Command::new(<&str>::from(action));
-
+fn do_driver_action(
+    runner: &dyn CommandRunner,
+    driver: &str,
+    action: DriverAction,
+) -> Result<(), GfxError> {
+    let program = <&str>::from(action);
+    let mut args = Vec::new();
     if driver == "nvidia_drm" {
-        cmd.arg("-f");
+        args.push("-f");
     }
-
-    cmd.arg(driver);
+    args.push(driver);
 
     let mut count = 0;
     const MAX_TRIES: i32 = 6;
@@ -152,10 +178,10 @@ fn do_driver_action(driver: &str, action: DriverAction) -> Result<(), GfxError>
             break; //Err(GfxError::Modprobe(msg));
         }
 
-        let output = cmd
-            .output()
-            .map_err(|err| GfxError::Command(format!("{:?}", cmd), err))?;
-        if !output.status.success() {
+        let output = runner
+            .run(program, &args)
+            .map_err(|err| GfxError::Command(command_line(program, &args), err))?;
+        if !output.success {
             if output
                 .stderr
                 .ends_with("is not currently loaded\n".as_bytes())
@@ -193,7 +219,7 @@ fn do_driver_action(driver: &str, action: DriverAction) -> Result<(), GfxError>
                 );
                 return Err(GfxError::Modprobe(msg));
             }
-        } else if output.status.success() {
+        } else {
             debug!("Did {} for driver {driver}", <&str>::from(action));
             break;
         }
@@ -204,63 +230,55 @@ fn do_driver_action(driver: &str, action: DriverAction) -> Result<(), GfxError>
     Ok(())
 }
 
-pub fn toggle_nvidia_powerd(run: bool, vendor: GfxVendor) -> Result<(), GfxError> {
-    if vendor == GfxVendor::Nvidia {
-        let mut cmd = Command::new("systemctl");
-        if run {
-            cmd.arg("start");
-        } else {
-            cmd.arg("stop");
-        }
-        cmd.arg("nvidia-powerd.service");
-
-        let status = cmd.status()?;
-        if !status.success() {
-            warn!("{run} nvidia-powerd.service failed: {:?}", status.code());
-        }
-        debug!("Did {:?}", cmd.get_args());
+/// Start or stop a systemd unit that only exists with the Nvidia drivers. Failure is not fatal.
+fn toggle_nvidia_unit(runner: &dyn CommandRunner, run: bool, unit: &str) -> Result<(), GfxError> {
+    let args = [if run { "start" } else { "stop" }, unit];
+    let output = runner
+        .run("systemctl", &args)
+        .map_err(|err| GfxError::Command(command_line("systemctl", &args), err))?;
+    if !output.success {
+        warn!("{run} {unit} failed: {:?}", output.code);
     }
+    debug!("Did {:?}", args);
     Ok(())
 }
 
-pub fn toggle_nvidia_persistenced(run: bool, vendor: GfxVendor) -> Result<(), GfxError> {
+pub fn toggle_nvidia_powerd(
+    runner: &dyn CommandRunner,
+    run: bool,
+    vendor: GfxVendor,
+) -> Result<(), GfxError> {
     if vendor == GfxVendor::Nvidia {
-        let mut cmd = Command::new("systemctl");
-        if run {
-            cmd.arg("start");
-        } else {
-            cmd.arg("stop");
-        }
-        cmd.arg("nvidia-persistenced.service");
+        toggle_nvidia_unit(runner, run, "nvidia-powerd.service")?;
+    }
+    Ok(())
+}
 
-        let status = cmd.status()?;
-        if !status.success() {
-            warn!(
-                "{run} nvidia-persistenced.service failed: {:?}",
-                status.code()
-            );
-        }
-        debug!("Did {:?}", cmd.get_args());
+pub fn toggle_nvidia_persistenced(
+    runner: &dyn CommandRunner,
+    run: bool,
+    vendor: GfxVendor,
+) -> Result<(), GfxError> {
+    if vendor == GfxVendor::Nvidia {
+        toggle_nvidia_unit(runner, run, "nvidia-persistenced.service")?;
     }
     Ok(())
 }
 
-pub fn kill_nvidia_lsof() -> Result<(), GfxError> {
-    if !PathBuf::from("/dev/nvidia0").exists() {
+pub fn kill_nvidia_lsof(paths: &HostPaths, runner: &dyn CommandRunner) -> Result<(), GfxError> {
+    if !paths.nvidia_dev().exists() {
         return Ok(());
     }
 
-    if !PathBuf::from("/usr/bin/lsof").exists() {
+    if !paths.lsof().exists() {
         warn!("The lsof util is missing from your system, please ensure it is available so processes hogging Nvidia can be nuked");
         return Ok(());
     }
 
-    let mut cmd = Command::new("lsof");
-    cmd.arg("/dev/nvidia0");
-
-    let output = cmd
-        .output()
-        .map_err(|err| GfxError::Command(format!("{:?}", cmd), err))?;
+    let args = ["/dev/nvidia0"];
+    let output = runner
+        .run("lsof", &args)
+        .map_err(|err| GfxError::Command(command_line("lsof", &args), err))?;
 
     let st = String::from_utf8_lossy(&output.stdout);
 
@@ -270,13 +288,12 @@ pub fn kill_nvidia_lsof() -> Result<(), GfxError> {
             if let Some(pid) = split.next() {
                 if let Ok(pid) = pid.parse::<u32>() {
                     warn!("pid {pid} ({c}) is holding /dev/nvidia0. Killing");
-                    let mut cmd = Command::new("kill");
-                    cmd.arg("-9");
-                    cmd.arg(format!("{pid}"));
-                    let status = cmd
-                        .status()
-                        .map_err(|err| GfxError::Command(format!("{:?}", cmd), err))?;
-                    if !status.success() {
+                    let pid = format!("{pid}");
+                    let args = ["-9", pid.as_str()];
+                    let output = runner
+                        .run("kill", &args)
+                        .map_err(|err| GfxError::Command(command_line("kill", &args), err))?;
+                    if !output.success {
                         warn!("Killing pid {pid} failed");
                     }
                 }
@@ -287,12 +304,12 @@ pub fn kill_nvidia_lsof() -> Result<(), GfxError> {
     Ok(())
 }
 
-pub fn get_kernel_cmdline_mode() -> Result<Option<GfxMode>, GfxError> {
-    let path = Path::new(KERNEL_CMDLINE);
+pub fn get_kernel_cmdline_mode(paths: &HostPaths) -> Result<Option<GfxMode>, GfxError> {
+    let path = paths.kernel_cmdline();
     let mut file = OpenOptions::new()
         .read(true)
-        .open(path)
-        .map_err(|err| GfxError::Path(KERNEL_CMDLINE.to_string(), err))?;
+        .open(&path)
+        .map_err(|err| GfxError::Path(path.to_string_lossy().to_string(), err))?;
     let mut buf = String::new();
     file.read_to_string(&mut buf)?;
 
@@ -309,12 +326,12 @@ pub fn get_kernel_cmdline_mode() -> Result<Option<GfxMode>, GfxError> {
     Ok(None)
 }
 
-pub fn get_kernel_cmdline_nvidia_modeset() -> Result<Option<bool>, GfxError> {
-    let path = Path::new(KERNEL_CMDLINE);
+pub fn get_kernel_cmdline_nvidia_modeset(paths: &HostPaths) -> Result<Option<bool>, GfxError> {
+    let path = paths.kernel_cmdline();
     let mut file = OpenOptions::new()
         .read(true)
-        .open(path)
-        .map_err(|err| GfxError::Path(KERNEL_CMDLINE.to_string(), err))?;
+        .open(&path)
+        .map_err(|err| GfxError::Path(path.to_string_lossy().to_string(), err))?;
     let mut buf = String::new();
     file.read_to_string(&mut buf)?;
 
@@ -331,10 +348,9 @@ pub fn get_kernel_cmdline_nvidia_modeset() -> Result<Option<bool>, GfxError> {
     Ok(None)
 }
 
-pub fn find_slot_power(address: &str) -> Result<PathBuf, GfxError> {
+pub fn find_slot_power(paths: &HostPaths, address: &str) -> Result<PathBuf, GfxError> {
     let mut buf = Vec::new();
-    let path = PathBuf::from_str(SLOTS).unwrap();
-    for path in path.read_dir()? {
+    for path in paths.pci_slots().read_dir()? {
         let path = path.unwrap().path();
 
         let mut address_path = path.to_path_buf();
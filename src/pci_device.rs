@@ -2,12 +2,14 @@ use log::{debug, info, trace, warn};
 use std::fmt::Display;
 use std::fs::{self, OpenOptions};
 use std::io::{Read, Write};
-use std::process::Command;
 use std::str::FromStr;
+use std::sync::Arc;
 use std::time::Duration;
 use std::{fs::write, path::PathBuf};
 
+use crate::command::{command_line, CommandRunner};
 use crate::error::GfxError;
+use crate::host_paths::HostPaths;
 use crate::special_asus::{
     asus_dgpu_disable_exists, asus_dgpu_disabled, asus_gpu_mux_exists, asus_gpu_mux_mode,
     AsusGpuMuxMode,
@@ -18,18 +20,37 @@ use crate::{
 };
 
 use serde_derive::{Deserialize, Serialize};
-use zbus::zvariant::Type;
+use zbus::zvariant::{OwnedValue, Type, Value};
 
-const PCI_BUS_PATH: &str = "/sys/bus/pci";
+pub(crate) const PCI_BUS_PATH: &str = "/sys/bus/pci";
 
-#[derive(Debug, Type, PartialEq, Eq, Copy, Clone, Deserialize, Serialize)]
+#[derive(Debug, Type, Value, OwnedValue, PartialEq, Eq, Copy, Clone, Deserialize, Serialize)]
 pub enum HotplugType {
     /// Use only kernel level hotplug feature
-    Std,
+    Std = 0,
     /// Use ASUS dgpu_disable
-    Asus,
+    Asus = 1,
     /// Do not use hotplugging
-    None,
+    None = 2,
+}
+
+impl Display for HotplugType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl FromStr for HotplugType {
+    type Err = GfxError;
+
+    fn from_str(s: &str) -> Result<Self, GfxError> {
+        match s.trim() {
+            "Std" => Ok(Self::Std),
+            "Asus" => Ok(Self::Asus),
+            "None" => Ok(Self::None),
+            _ => Err(GfxError::ParseHotplugType),
+        }
+    }
 }
 
 #[derive(Debug, Type, PartialEq, Eq, Copy, Clone)]
@@ -58,15 +79,17 @@ impl From<HotplugState> for &str {
     }
 }
 
-#[derive(Debug, Default, Type, PartialEq, Eq, Copy, Clone, Deserialize, Serialize)]
+#[derive(
+    Debug, Default, Type, Value, OwnedValue, PartialEq, Eq, Copy, Clone, Deserialize, Serialize,
+)]
 pub enum GfxPower {
-    Active,
-    Suspended,
-    Off,
-    AsusDisabled,
-    AsusMuxDiscreet,
+    Active = 0,
+    Suspended = 1,
+    Off = 2,
+    AsusDisabled = 3,
+    AsusMuxDiscreet = 4,
     #[default]
-    Unknown,
+    Unknown = 5,
 }
 
 impl FromStr for GfxPower {
@@ -151,19 +174,33 @@ impl From<&GfxVendor> for &str {
 
 /// All the available modes. Every mode except `None` and `AsusMuxDgpu` should assume that either
 /// the ASUS specific `gpu_mux_mode` sysfs entry is not available or is set to iGPU mode.
-#[derive(Debug, Default, Type, PartialEq, Eq, Copy, Clone, Deserialize, Serialize)]
+#[derive(
+    Debug, Default, Type, Value, OwnedValue, PartialEq, Eq, Copy, Clone, Deserialize, Serialize,
+)]
 pub enum GfxMode {
-    Hybrid,
-    Integrated,
+    Hybrid = 0,
+    Integrated = 1,
     /// This mode is for folks using `nomodeset=0` on certain hardware. It allows hot unloading of nvidia
-    NvidiaNoModeset,
-    Vfio,
+    NvidiaNoModeset = 2,
+    Vfio = 3,
     /// The ASUS EGPU is in use
-    AsusEgpu,
+    AsusEgpu = 4,
     /// The ASUS GPU MUX is set to dGPU mode
-    AsusMuxDgpu,
+    AsusMuxDgpu = 5,
     #[default]
-    None,
+    None = 6,
+}
+
+impl GfxMode {
+    /// Every mode that can be switched to, i.e all but `None`
+    pub const ALL: [GfxMode; 6] = [
+        GfxMode::Hybrid,
+        GfxMode::Integrated,
+        GfxMode::NvidiaNoModeset,
+        GfxMode::Vfio,
+        GfxMode::AsusEgpu,
+        GfxMode::AsusMuxDgpu,
+    ];
 }
 
 impl Display for GfxMode {
@@ -183,30 +220,37 @@ impl Display for GfxMode {
 impl FromStr for GfxMode {
     type Err = GfxError;
 
+    /// Parse a mode name in any case, or one of the aliases:
+    /// - `igpu`: `Integrated`
+    /// - `hybrid`, `compute`: `Hybrid`
+    /// - `dgpu`: `AsusMuxDgpu`
+    /// - `vfio`: `Vfio`
+    /// - `egpu`: `AsusEgpu`
     fn from_str(s: &str) -> Result<Self, GfxError> {
-        match s.trim() {
-            "Hybrid" => Ok(GfxMode::Hybrid),
-            "Integrated" => Ok(GfxMode::Integrated),
-            "NvidiaNoModeset" => Ok(GfxMode::NvidiaNoModeset),
-            "Vfio" => Ok(GfxMode::Vfio),
-            "AsusEgpu" => Ok(GfxMode::AsusEgpu),
-            "AsusMuxDgpu" => Ok(GfxMode::AsusMuxDgpu),
-            _ => Err(GfxError::ParseMode),
+        match s.trim().to_ascii_lowercase().as_str() {
+            "hybrid" | "compute" => Ok(GfxMode::Hybrid),
+            "integrated" | "igpu" => Ok(GfxMode::Integrated),
+            "nvidianomodeset" => Ok(GfxMode::NvidiaNoModeset),
+            "vfio" => Ok(GfxMode::Vfio),
+            "asusegpu" | "egpu" => Ok(GfxMode::AsusEgpu),
+            "asusmuxdgpu" | "dgpu" => Ok(GfxMode::AsusMuxDgpu),
+            _ => Err(GfxError::ParseMode(s.trim().to_string())),
         }
     }
 }
 
 /// Will rescan the device tree, which adds all removed devices back
-pub fn rescan_pci_bus() -> Result<(), GfxError> {
-    let path = PathBuf::from(PCI_BUS_PATH).join("rescan");
+pub fn rescan_pci_bus(paths: &HostPaths) -> Result<(), GfxError> {
+    let path = paths.pci_bus().join("rescan");
     write(&path, "1").map_err(|e| GfxError::from_io(e, path))
 }
 
-fn lscpi(vendor_device: &str) -> Result<String, GfxError> {
-    let mut cmd = Command::new("lspci");
-    cmd.args(["-d", vendor_device]);
-    let s = String::from_utf8_lossy(&cmd.output()?.stdout).into_owned();
-    Ok(s)
+fn lscpi(runner: &dyn CommandRunner, vendor_device: &str) -> Result<String, GfxError> {
+    let args = ["-d", vendor_device];
+    let output = runner
+        .run("lspci", &args)
+        .map_err(|err| GfxError::Command(command_line("lspci", &args), err))?;
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
 }
 
 pub fn lscpi_dgpu_check(label: &str) -> bool {
@@ -225,6 +269,93 @@ pub fn lscpi_dgpu_check(label: &str) -> bool {
     false
 }
 
+/// The properties of a PCI device required to decide if it is a dGPU, gathered either
+/// from udev or directly from a sysfs tree
+struct PciCandidate {
+    /// System name given by kernel, e.g `0000:01:00.0`
+    sysname: String,
+    syspath: PathBuf,
+    /// Vendor:Device in uppercase hex, e.g `10DE:249C`
+    pci_id: Option<String>,
+    /// Class in uppercase hex without leading zeroes, e.g `30200`
+    class: Option<String>,
+    /// The `ID_MODEL_FROM_DATABASE` label, udev only
+    model: Option<String>,
+}
+
+impl PciCandidate {
+    fn scan_udev() -> Result<Vec<Self>, GfxError> {
+        let mut enumerator = udev::Enumerator::new().map_err(|err| {
+            warn!("{}", err);
+            GfxError::Udev("enumerator failed".into(), err)
+        })?;
+
+        enumerator.match_subsystem("pci").map_err(|err| {
+            warn!("{}", err);
+            GfxError::Udev("match_subsystem failed".into(), err)
+        })?;
+
+        let candidates = enumerator
+            .scan_devices()
+            .map_err(|err| {
+                warn!("{}", err);
+                GfxError::Udev("scan_devices failed".into(), err)
+            })?
+            .map(|device| Self {
+                sysname: device.sysname().to_string_lossy().to_string(),
+                syspath: PathBuf::from(device.syspath()),
+                pci_id: device
+                    .property_value("PCI_ID")
+                    .map(|s| s.to_string_lossy().to_string()),
+                class: device
+                    .property_value("PCI_CLASS")
+                    .map(|s| s.to_string_lossy().to_string()),
+                model: device
+                    .property_value("ID_MODEL_FROM_DATABASE")
+                    .map(|s| s.to_string_lossy().to_string()),
+            })
+            .collect();
+        Ok(candidates)
+    }
+
+    /// Walk `<root>/sys/bus/pci/devices` instead of asking udev. Used when the root is
+    /// not the real system root, e.g for a fake tree in tests.
+    fn scan_sysfs(paths: &HostPaths) -> Result<Vec<Self>, GfxError> {
+        let read_hex = |path: PathBuf| -> Option<u32> {
+            let s = fs::read_to_string(path).ok()?;
+            u32::from_str_radix(s.trim().trim_start_matches("0x"), 16).ok()
+        };
+
+        let dir = paths.pci_devices();
+        let mut entries = dir
+            .read_dir()
+            .map_err(|e| GfxError::from_io(e, dir.clone()))?
+            .filter_map(|e| e.ok())
+            .collect::<Vec<_>>();
+        // udev enumerates in order of sysname, keep that behaviour
+        entries.sort_by_key(|e| e.file_name());
+
+        let candidates = entries
+            .into_iter()
+            .map(|entry| {
+                let syspath = fs::canonicalize(entry.path()).unwrap_or_else(|_| entry.path());
+                let pci_id = read_hex(syspath.join("vendor"))
+                    .zip(read_hex(syspath.join("device")))
+                    .map(|(v, d)| format!("{v:04X}:{d:04X}"));
+                let class = read_hex(syspath.join("class")).map(|c| format!("{c:X}"));
+                Self {
+                    sysname: entry.file_name().to_string_lossy().to_string(),
+                    syspath,
+                    pci_id,
+                    class,
+                    model: None,
+                }
+            })
+            .collect();
+        Ok(candidates)
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Device {
     /// Concrete path to the device control
@@ -256,6 +387,14 @@ impl Device {
         &self.pci_id
     }
 
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn hotplug_path(&self) -> Option<&PathBuf> {
+        self.hotplug_path.as_ref()
+    }
+
     fn set_hotplug(&self, state: HotplugState) -> Result<(), GfxError> {
         if let Some(path) = self.hotplug_path.as_ref() {
             info!("set_hotplug: Setting hotplug power to {state:?}");
@@ -270,41 +409,31 @@ impl Device {
         Ok(())
     }
 
-    pub fn find() -> Result<Vec<Self>, GfxError> {
+    pub fn find(paths: &HostPaths, runner: &dyn CommandRunner) -> Result<Vec<Self>, GfxError> {
         let mut devices = Vec::new();
         let mut parent = String::new();
 
-        let mut enumerator = udev::Enumerator::new().map_err(|err| {
-            warn!("{}", err);
-            GfxError::Udev("enumerator failed".into(), err)
-        })?;
-
-        enumerator.match_subsystem("pci").map_err(|err| {
-            warn!("{}", err);
-            GfxError::Udev("match_subsystem failed".into(), err)
-        })?;
-
-        let get_parent = |dev: &udev::Device| -> String {
-            dev.sysname()
-                .to_string_lossy()
+        let get_parent = |dev: &PciCandidate| -> String {
+            dev.sysname
                 .trim_end_matches(char::is_numeric)
                 .trim_end_matches('.')
                 .to_string()
         };
 
-        for device in enumerator.scan_devices().map_err(|err| {
-            warn!("{}", err);
-            GfxError::Udev("scan_devices failed".into(), err)
-        })? {
-            let sysname = device.sysname().to_string_lossy();
+        let candidates = if paths.is_system_root() {
+            PciCandidate::scan_udev()?
+        } else {
+            PciCandidate::scan_sysfs(paths)?
+        };
+
+        for device in candidates {
+            let sysname = device.sysname.as_str();
             debug!("Looking at PCI device {:?}", sysname);
             // PCI_ID can be given directly to lspci to get a database label
             // This is the same as ID_MODEL_FROM_DATABASE
-            if let Some(id) = device.property_value("PCI_ID") {
-                if let Some(class) = device.property_value("PCI_CLASS") {
-                    let id = id.to_string_lossy();
+            if let Some(id) = device.pci_id.as_deref() {
+                if let Some(class) = device.class.as_deref() {
                     // class can be 0x030200 or 0x030000
-                    let class = class.to_string_lossy();
                     // Match only      Nvidia or AMD
                     if id.starts_with("10DE") || id.starts_with("1002") {
                         if let Some(vendor) = id.split(':').next() {
@@ -315,30 +444,27 @@ impl Device {
                             // to the GPU at all. Since eDP-1 is *always* connected this means we
                             // can assume that the checked device is not iGPU
                             let displays =
-                                find_connected_displays(device.syspath()).unwrap_or_default();
+                                find_connected_displays(&device.syspath).unwrap_or_default();
                             // eDP-1 is the internal panel connection which is so far always on iGPU
                             if !displays.contains(&"eDP-1".to_string()) {
                                 info!(
                                     "Matched dGPU {id} at {:?} by checking display connections",
-                                    device.sysname()
+                                    sysname
                                 );
                                 dgpu = class.starts_with("30")
                                     && (id.starts_with("10DE") || id.starts_with("1002"));
                             } else {
-                                info!(
-                                    "Device {id} at {:?} appears to be the iGPU",
-                                    device.sysname()
-                                );
+                                info!("Device {id} at {:?} appears to be the iGPU", sysname);
                             }
                             if !dgpu && id.starts_with("1002") {
                                 debug!(
                                     "Found dGPU Device {id} without boot_vga attribute at {:?}",
-                                    device.sysname()
+                                    sysname
                                 );
                                 // Sometimes AMD iGPU doesn't get a boot_vga attribute even in Hybrid mode
                                 // Fallback to the following method for telling iGPU apart from dGPU:
                                 // https://github.com/fastfetch-cli/fastfetch/blob/fed2c87f67de43e3672d1a4a7767d59e7ff22ba2/src/detection/gpu/gpu_linux.c#L148
-                                let mut dev_path = PathBuf::from(device.syspath());
+                                let mut dev_path = device.syspath.clone();
                                 dev_path.push("hwmon");
 
                                 let hwmon_n_opt = match dev_path.read_dir() {
@@ -356,29 +482,28 @@ impl Device {
                                 }
                             }
                             if !dgpu {
-                                if let Some(label) = device.property_value("ID_MODEL_FROM_DATABASE")
-                                {
+                                if let Some(label) = device.model.as_deref() {
                                     debug!(
-                                    "Found ID_MODEL_FROM_DATABASE property {id} at {:?} : {label:?}",
-                                    device.sysname()
-                                );
-                                    lscpi_dgpu_check(&label.to_string_lossy())
+                                        "Found ID_MODEL_FROM_DATABASE property {id} at {:?} : {label:?}",
+                                        sysname
+                                    );
+                                    lscpi_dgpu_check(label)
                                 } else {
                                     // last resort - this is typically only required if ID_MODEL_FROM_DATABASE is
                                     // missing due to dgpu_disable being on at boot
-                                    debug!("Didn't find dGPU with standard methods, using last resort for id:{id} at {:?}", device.sysname());
-                                    lscpi_dgpu_check(&lscpi(&id)?)
+                                    debug!("Didn't find dGPU with standard methods, using last resort for id:{id} at {:?}", sysname);
+                                    lscpi_dgpu_check(&lscpi(runner, id)?)
                                 };
                             }
 
                             if dgpu || !parent.is_empty() && sysname.contains(&parent) {
                                 let mut hotplug_path = None;
                                 if dgpu {
-                                    info!("Found dgpu {id} at {:?}", device.sysname());
-                                    match find_slot_power(&sysname) {
+                                    info!("Found dgpu {id} at {:?}", sysname);
+                                    match find_slot_power(paths, sysname) {
                                         Ok(slot) => hotplug_path = Some(slot),
                                         Err(e) => {
-                                            if let Ok(c) = asus_gpu_mux_mode() {
+                                            if let Ok(c) = asus_gpu_mux_mode(paths) {
                                                 debug!(
                                                     "Laptop is in dGPU MUX mode? {}",
                                                     c == AsusGpuMuxMode::Discreet
@@ -391,11 +516,11 @@ impl Device {
                                         }
                                     }
                                 } else {
-                                    info!("Found additional device {id} at {:?}", device.sysname());
+                                    info!("Found additional device {id} at {:?}", sysname);
                                 }
                                 parent = get_parent(&device);
                                 devices.push(Self {
-                                    dev_path: PathBuf::from(device.syspath()),
+                                    dev_path: device.syspath.clone(),
                                     hotplug_path,
                                     vendor: vendor.into(),
                                     is_dgpu: dgpu,
@@ -540,14 +665,16 @@ pub struct DiscreetGpu {
     vendor: GfxVendor,
     dgpu_index: usize,
     devices: Vec<Device>,
+    paths: HostPaths,
+    runner: Arc<dyn CommandRunner>,
 }
 
 impl DiscreetGpu {
-    pub fn new() -> Result<DiscreetGpu, GfxError> {
+    pub fn new(paths: HostPaths, runner: Arc<dyn CommandRunner>) -> Result<DiscreetGpu, GfxError> {
         info!("DiscreetGpu::new: Rescanning PCI bus");
-        rescan_pci_bus()?;
+        rescan_pci_bus(&paths)?;
 
-        if let Ok(device) = Device::find() {
+        if let Ok(device) = Device::find(&paths, runner.as_ref()) {
             let mut vendor = GfxVendor::Unknown;
             let mut dgpu_index = 0;
             for (idx, dev) in device.iter().enumerate() {
@@ -560,15 +687,17 @@ impl DiscreetGpu {
                 vendor,
                 dgpu_index,
                 devices: device,
+                paths,
+                runner,
             })
         } else {
             warn!("DiscreetGpu::new: no devices??");
             let mut vendor = GfxVendor::Unknown;
-            if asus_dgpu_disable_exists() && asus_dgpu_disabled().unwrap_or(false) {
+            if asus_dgpu_disable_exists(&paths) && asus_dgpu_disabled(&paths).unwrap_or(false) {
                 warn!("ASUS dGPU appears to be disabled");
                 vendor = GfxVendor::AsusDgpuDisabled;
-            } else if asus_gpu_mux_exists()
-                && if let Ok(c) = asus_gpu_mux_mode() {
+            } else if asus_gpu_mux_exists(&paths)
+                && if let Ok(c) = asus_gpu_mux_mode(&paths) {
                     c == AsusGpuMuxMode::Discreet
                 } else {
                     false
@@ -581,6 +710,8 @@ impl DiscreetGpu {
                 vendor,
                 dgpu_index: 0,
                 devices: Vec::new(),
+                paths,
+                runner,
             })
         }
     }
@@ -589,6 +720,20 @@ impl DiscreetGpu {
         self.vendor
     }
 
+    /// The paths this device set was discovered under
+    pub fn paths(&self) -> &HostPaths {
+        &self.paths
+    }
+
+    /// The runner used for any commands required to control this device set
+    pub fn runner(&self) -> &dyn CommandRunner {
+        self.runner.as_ref()
+    }
+
+    pub fn runner_arc_clone(&self) -> Arc<dyn CommandRunner> {
+        self.runner.clone()
+    }
+
     pub fn devices(&self) -> &[Device] {
         &self.devices
     }
@@ -614,16 +759,16 @@ impl DiscreetGpu {
             } else if self.vendor != GfxVendor::Unknown {
                 return self.devices[self.dgpu_index].get_runtime_status();
             }
-        } else if asus_dgpu_disable_exists() {
-            if let Ok(disabled) = asus_dgpu_disabled() {
+        } else if asus_dgpu_disable_exists(&self.paths) {
+            if let Ok(disabled) = asus_dgpu_disabled(&self.paths) {
                 trace!("No dGPU tracked. Maybe booted with dgpu_disable=1 or gpu_mux_mode=0");
                 // info!("Is ASUS laptop, dgpu_disable = {disabled}");
                 if disabled {
                     return Ok(GfxPower::AsusDisabled);
                 }
             }
-        } else if asus_gpu_mux_exists() {
-            if let Ok(mode) = asus_gpu_mux_mode() {
+        } else if asus_gpu_mux_exists(&self.paths) {
+            if let Ok(mode) = asus_gpu_mux_mode(&self.paths) {
                 if mode == AsusGpuMuxMode::Discreet {
                     return Ok(GfxPower::AsusMuxDiscreet);
                 }
@@ -712,7 +857,7 @@ impl DiscreetGpu {
         );
         if self.is_nvidia() {
             for driver in NVIDIA_DRIVERS.iter() {
-                do_driver_action(driver, action)?;
+                do_driver_action(self.runner(), driver, action)?;
             }
         }
         Ok(())
@@ -0,0 +1,176 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use futures_util::future::BoxFuture;
+use log::{trace, warn};
+use serde::Serialize;
+use zbus::{
+    proxy,
+    zvariant::{Type, Value},
+    Connection,
+};
+
+use crate::{error::GfxError, pci_device::GfxMode};
+
+/// Switching the graphics mode, or cancelling a pending switch
+pub const ACTION_SET_MODE: &str = "org.supergfxctl.set-mode";
+/// Changing the daemon config
+pub const ACTION_SET_CONFIG: &str = "org.supergfxctl.set-config";
+/// Enabling VFIO in the config, or switching to the VFIO mode
+pub const ACTION_ENABLE_VFIO: &str = "org.supergfxctl.enable-vfio";
+
+/// Polkit `CheckAuthorization` flag to let the agent prompt the user for auth
+const ALLOW_USER_INTERACTION: u32 = 1;
+
+/// The process that called a dbus method
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Caller {
+    /// Unique bus name of the caller, e.g `:1.42`
+    pub bus_name: String,
+    pub uid: Option<u32>,
+}
+
+/// The polkit actions required to switch to `mode`
+pub fn actions_for_mode(mode: GfxMode) -> Vec<&'static str> {
+    let mut actions = vec![ACTION_SET_MODE];
+    if mode == GfxMode::Vfio {
+        actions.push(ACTION_ENABLE_VFIO);
+    }
+    actions
+}
+
+/// The polkit actions required to change the config, `enabling_vfio` if `vfio_enable`
+/// goes from false to true
+pub fn actions_for_config(enabling_vfio: bool) -> Vec<&'static str> {
+    let mut actions = vec![ACTION_SET_CONFIG];
+    if enabling_vfio {
+        actions.push(ACTION_ENABLE_VFIO);
+    }
+    actions
+}
+
+/// Decides if a caller may perform a polkit action. This is polkit on a real system,
+/// or a [`MockAuthority`] in tests.
+pub trait Authority: Send + Sync {
+    fn check_authorization<'a>(
+        &'a self,
+        caller: &'a Caller,
+        action_id: &'a str,
+    ) -> BoxFuture<'a, Result<bool, GfxError>>;
+}
+
+#[proxy(
+    interface = "org.freedesktop.PolicyKit1.Authority",
+    default_service = "org.freedesktop.PolicyKit1",
+    default_path = "/org/freedesktop/PolicyKit1/Authority"
+)]
+trait PolkitAuthority {
+    fn check_authorization(
+        &self,
+        subject: &Subject<'_>,
+        action_id: &str,
+        details: HashMap<&str, &str>,
+        flags: u32,
+        cancellation_id: &str,
+    ) -> zbus::Result<(bool, bool, HashMap<String, String>)>;
+}
+
+/// A polkit subject, `(kind, details)`
+#[derive(Debug, Serialize, Type)]
+struct Subject<'a> {
+    kind: &'a str,
+    details: HashMap<&'a str, Value<'a>>,
+}
+
+/// Checks authorization with `org.freedesktop.PolicyKit1.Authority` on the system bus
+pub struct SystemAuthority {
+    connection: Connection,
+}
+
+impl SystemAuthority {
+    pub fn new(connection: Connection) -> Self {
+        Self { connection }
+    }
+
+    async fn check(&self, caller: &Caller, action_id: &str) -> Result<bool, GfxError> {
+        let proxy = PolkitAuthorityProxy::new(&self.connection).await?;
+        let subject = Subject {
+            kind: "system-bus-name",
+            details: HashMap::from([("name", Value::from(caller.bus_name.as_str()))]),
+        };
+        let (authorized, _, _) = proxy
+            .check_authorization(
+                &subject,
+                action_id,
+                HashMap::new(),
+                ALLOW_USER_INTERACTION,
+                "",
+            )
+            .await?;
+        Ok(authorized)
+    }
+}
+
+impl Authority for SystemAuthority {
+    fn check_authorization<'a>(
+        &'a self,
+        caller: &'a Caller,
+        action_id: &'a str,
+    ) -> BoxFuture<'a, Result<bool, GfxError>> {
+        Box::pin(async move {
+            match self.check(caller, action_id).await {
+                Ok(authorized) => Ok(authorized),
+                Err(err) => {
+                    // Without polkit only root may make changes
+                    warn!("polkit check of {action_id} failed, only allowing root: {err}");
+                    Ok(caller.uid == Some(0))
+                }
+            }
+        })
+    }
+}
+
+/// A fake authority which allows only the actions it was told to, and records every
+/// check made
+#[derive(Debug, Default)]
+pub struct MockAuthority {
+    allowed: Mutex<Vec<String>>,
+    checks: Mutex<Vec<String>>,
+}
+
+impl MockAuthority {
+    /// An authority which denies everything
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// An authority which allows every supergfxd action
+    pub fn allow_all() -> Self {
+        let authority = Self::new();
+        for action_id in [ACTION_SET_MODE, ACTION_SET_CONFIG, ACTION_ENABLE_VFIO] {
+            authority.allow(action_id);
+        }
+        authority
+    }
+
+    pub fn allow(&self, action_id: &str) {
+        self.allowed.lock().unwrap().push(action_id.to_string());
+    }
+
+    /// All action ids checked so far, in order
+    pub fn checks(&self) -> Vec<String> {
+        self.checks.lock().unwrap().clone()
+    }
+}
+
+impl Authority for MockAuthority {
+    fn check_authorization<'a>(
+        &'a self,
+        caller: &'a Caller,
+        action_id: &'a str,
+    ) -> BoxFuture<'a, Result<bool, GfxError>> {
+        trace!("check (mocked): {action_id} for {caller:?}");
+        self.checks.lock().unwrap().push(action_id.to_string());
+        let allowed = self.allowed.lock().unwrap().iter().any(|a| a == action_id);
+        Box::pin(async move { Ok(allowed) })
+    }
+}
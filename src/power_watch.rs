@@ -0,0 +1,134 @@
+use std::{os::fd::AsRawFd, sync::Arc, time::Duration};
+
+use futures_util::lock::Mutex;
+use log::{debug, trace, warn};
+use tokio::{sync::mpsc, time::sleep};
+
+use crate::pci_device::{DiscreetGpu, GfxPower};
+
+/// The fastest the status is polled, used right after any change
+pub const MIN_POLL_PERIOD: Duration = Duration::from_secs(1);
+/// The slowest the status is polled once it has been stable for a while
+pub const MAX_POLL_PERIOD: Duration = Duration::from_secs(16);
+
+/// Runtime PM transitions don't always generate udev events, and sysfs attributes never
+/// generate inotify events, so the status is still polled. The period doubles each time nothing changed, up to `MAX_POLL_PERIOD`,
+/// and goes back to `MIN_POLL_PERIOD` on any event or change.
+pub fn next_poll_period(current: Duration, changed: bool) -> Duration {
+    if changed {
+        MIN_POLL_PERIOD
+    } else {
+        (current * 2).min(MAX_POLL_PERIOD)
+    }
+}
+
+/// Start watching the dGPU power status. Only transitions are sent, starting from
+/// `GfxPower::Unknown`, so the first status read is always sent.
+///
+/// The status is re-read on udev events for the PCI subsystem (only on the real system
+/// root) and on an adaptive poll.
+pub fn watch_power_status(dgpu: Arc<Mutex<DiscreetGpu>>) -> mpsc::UnboundedReceiver<GfxPower> {
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let paths = dgpu.lock().await.paths().clone();
+        let mut udev_events = if paths.is_system_root() {
+            start_udev_monitor()
+                .map_err(|e| warn!("watch_power_status: udev monitor failed, polling only: {e}"))
+                .ok()
+        } else {
+            None
+        };
+        let mut last_status = GfxPower::Unknown;
+        let mut period = MIN_POLL_PERIOD;
+        loop {
+            let status = dgpu
+                .lock()
+                .await
+                .get_runtime_status()
+                .map_err(|e| trace!("{e}"))
+                .unwrap_or(GfxPower::Unknown);
+
+            let changed = status != last_status;
+            if changed {
+                trace!("watch_power_status: dGPU status = {status:?}");
+                last_status = status;
+                if tx.send(status).is_err() {
+                    break;
+                }
+            }
+            period = next_poll_period(period, changed);
+
+            tokio::select! {
+                event = next_udev_event(&mut udev_events) => {
+                    trace!("watch_power_status: udev event");
+                    period = MIN_POLL_PERIOD;
+                    if event.is_none() {
+                        warn!("watch_power_status: udev monitor stopped, polling only");
+                        udev_events = None;
+                    }
+                }
+                _ = sleep(period) => {}
+            }
+        }
+        debug!("watch_power_status: receiver dropped, stopping");
+    });
+    rx
+}
+
+async fn next_udev_event(events: &mut Option<mpsc::UnboundedReceiver<()>>) -> Option<()> {
+    match events.as_mut() {
+        Some(events) => events.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// The udev socket can't be sent between threads, so it is created and read on its own
+/// thread which forwards a notice for every PCI event
+fn start_udev_monitor() -> std::io::Result<mpsc::UnboundedReceiver<()>> {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let (setup_tx, setup_rx) = std::sync::mpsc::sync_channel(1);
+    std::thread::Builder::new()
+        .name("supergfxd-udev".into())
+        .spawn(move || {
+            let socket = match udev::MonitorBuilder::new()
+                .and_then(|m| m.match_subsystem("pci"))
+                .and_then(|m| m.listen())
+            {
+                Ok(socket) => {
+                    setup_tx.send(Ok(())).ok();
+                    socket
+                }
+                Err(e) => {
+                    setup_tx.send(Err(e)).ok();
+                    return;
+                }
+            };
+            let mut fds = libc::pollfd {
+                fd: socket.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            loop {
+                // Block until the socket has events
+                let res = unsafe { libc::poll(&mut fds, 1, -1) };
+                if res < 0 {
+                    let err = std::io::Error::last_os_error();
+                    if err.kind() == std::io::ErrorKind::Interrupted {
+                        continue;
+                    }
+                    warn!("udev monitor: poll: {err}");
+                    return;
+                }
+                for event in socket.iter() {
+                    trace!("udev monitor: {} {:?}", event.event_type(), event.sysname());
+                }
+                if tx.send(()).is_err() {
+                    return;
+                }
+            }
+        })?;
+    setup_rx.recv().map_err(|_| {
+        std::io::Error::new(std::io::ErrorKind::Other, "udev monitor thread exited")
+    })??;
+    Ok(rx)
+}
@@ -0,0 +1,63 @@
+use std::path::Path;
+
+use log::warn;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{
+    actions::UserActionRequired, config::write_atomic, error::GfxError, pci_device::GfxMode,
+};
+
+/// Where the runtime state is saved. It is on a tmpfs so it outlives a daemon restart
+/// but not a reboot.
+pub const RUNTIME_STATE_PATH: &str = "/run/supergfxd/state.json";
+
+/// The daemon state that isn't a user preference and is reset by a reboot. It is saved
+/// on every change so that a daemon which stopped mid-switch can be recovered on restart.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct GfxRuntimeState {
+    /// Only for temporary modes like compute or vfio
+    pub tmp_mode: Option<GfxMode>,
+    /// Just for tracking the requested mode change in rebootless mode
+    pub pending_mode: Option<GfxMode>,
+    /// Just for tracking the required user action
+    pub pending_action: Option<UserActionRequired>,
+    /// The id of the switch being run, set from when its steps start until it has ended.
+    /// If this is set on start the daemon stopped in the middle of that switch.
+    pub transaction_id: Option<u64>,
+}
+
+impl GfxRuntimeState {
+    /// Read the state saved by the last run of the daemon, the default if there is none
+    pub fn load(path: &Path) -> Self {
+        let buf = match std::fs::read_to_string(path) {
+            Ok(buf) => buf,
+            Err(err) => {
+                if err.kind() != std::io::ErrorKind::NotFound {
+                    warn!("Could not read the runtime state {path:?}: {err}");
+                }
+                return Self::default();
+            }
+        };
+        serde_json::from_str(&buf).unwrap_or_else(|err| {
+            warn!("Could not parse the runtime state {path:?}: {err}");
+            Self::default()
+        })
+    }
+
+    /// Save the state. It is only needed to recover from a crash, so failing to save is
+    /// logged and otherwise ignored.
+    pub fn save(&self, path: &Path) {
+        if let Some(dir) = path.parent() {
+            if let Err(err) = std::fs::create_dir_all(dir) {
+                warn!("Could not save the runtime state, creating {dir:?} failed: {err}");
+                return;
+            }
+        }
+        let res = serde_json::to_string(self)
+            .map_err(|err| GfxError::ParseConfig(err.to_string()))
+            .and_then(|json| write_atomic(&path.to_string_lossy(), json.as_bytes()));
+        if let Err(err) = res {
+            warn!("Could not save the runtime state: {err}");
+        }
+    }
+}
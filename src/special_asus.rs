@@ -9,14 +9,16 @@ use tokio::time::sleep;
 
 use crate::{
     error::GfxError,
+    host_paths::HostPaths,
     pci_device::{rescan_pci_bus, GfxMode},
 };
 
-const ASUS_DGPU_DISABLE_PATH: &str = "/sys/devices/platform/asus-nb-wmi/dgpu_disable";
-const ASUS_EGPU_ENABLE_PATH: &str = "/sys/devices/platform/asus-nb-wmi/egpu_enable";
-const ASUS_GPU_MUX_PATH: &str = "/sys/devices/platform/asus-nb-wmi/gpu_mux_mode";
+pub(crate) const ASUS_DGPU_DISABLE_PATH: &str = "/sys/devices/platform/asus-nb-wmi/dgpu_disable";
+pub(crate) const ASUS_EGPU_ENABLE_PATH: &str = "/sys/devices/platform/asus-nb-wmi/egpu_enable";
+pub(crate) const ASUS_GPU_MUX_PATH: &str = "/sys/devices/platform/asus-nb-wmi/gpu_mux_mode";
 
-const ASUS_EGPU_ALT_ENABLE_PATH: &str = "/sys/bus/platform/devices/asus-nb-wmi/egpu_enable";
+pub(crate) const ASUS_EGPU_ALT_ENABLE_PATH: &str =
+    "/sys/bus/platform/devices/asus-nb-wmi/egpu_enable";
 
 pub const ASUS_MODULES_LOAD_PATH: &str = "/etc/modules-load.d/asus.conf";
 pub const ASUS_MODULES_LOAD: &[u8] = br#"
@@ -25,9 +27,10 @@ asus-nb-wmi
 "#;
 
 /// Create the config. Returns true if it already existed.
-pub fn create_asus_modules_load_conf() -> Result<bool, GfxError> {
-    if Path::new(ASUS_MODULES_LOAD_PATH).exists() {
-        info!("{} exists", ASUS_MODULES_LOAD_PATH);
+pub fn create_asus_modules_load_conf(paths: &HostPaths) -> Result<bool, GfxError> {
+    let path = paths.asus_modules_load();
+    if path.exists() {
+        info!("{} exists", path.display());
         return Ok(true);
     }
 
@@ -35,13 +38,13 @@ pub fn create_asus_modules_load_conf() -> Result<bool, GfxError> {
         .create(true)
         .truncate(true)
         .write(true)
-        .open(ASUS_MODULES_LOAD_PATH)
-        .map_err(|err| GfxError::Path(ASUS_MODULES_LOAD_PATH.into(), err))?;
+        .open(&path)
+        .map_err(|err| GfxError::Path(path.to_string_lossy().to_string(), err))?;
 
-    info!("Writing {}", ASUS_MODULES_LOAD_PATH);
+    info!("Writing {}", path.display());
     file.write_all(ASUS_MODULES_LOAD)
         .and_then(|_| file.sync_all())
-        .map_err(|err| GfxError::Write(ASUS_MODULES_LOAD_PATH.into(), err))?;
+        .map_err(|err| GfxError::Write(path.to_string_lossy().to_string(), err))?;
 
     Ok(false)
 }
@@ -70,21 +73,21 @@ impl From<char> for AsusGpuMuxMode {
     }
 }
 
-pub fn asus_gpu_mux_exists() -> bool {
-    Path::new(ASUS_GPU_MUX_PATH).exists()
+pub fn asus_gpu_mux_exists(paths: &HostPaths) -> bool {
+    paths.asus_gpu_mux().exists()
 }
 
-pub fn asus_gpu_mux_mode() -> Result<AsusGpuMuxMode, GfxError> {
-    let path = ASUS_GPU_MUX_PATH;
+pub fn asus_gpu_mux_mode(paths: &HostPaths) -> Result<AsusGpuMuxMode, GfxError> {
+    let path = paths.asus_gpu_mux();
     let mut file = OpenOptions::new()
         .read(true)
-        .open(path)
-        .map_err(|err| GfxError::Path(path.into(), err))?;
+        .open(&path)
+        .map_err(|err| GfxError::Path(path.to_string_lossy().to_string(), err))?;
 
     let mut data = Vec::new();
     let res = file
         .read_to_end(&mut data)
-        .map_err(|err| GfxError::Read(path.into(), err))?;
+        .map_err(|err| GfxError::Read(path.to_string_lossy().to_string(), err))?;
     if res == 0 {
         return Err(GfxError::Read(
             "Failed to read gpu_mux_mode".to_owned(),
@@ -101,26 +104,26 @@ pub fn asus_gpu_mux_mode() -> Result<AsusGpuMuxMode, GfxError> {
     ))
 }
 
-pub fn asus_gpu_mux_set_igpu(igpu_on: bool) -> Result<(), GfxError> {
+pub fn asus_gpu_mux_set_igpu(paths: &HostPaths, igpu_on: bool) -> Result<(), GfxError> {
     debug!("asus_gpu_mux_set_igpu: {igpu_on}");
-    asus_gpu_toggle(igpu_on, ASUS_GPU_MUX_PATH)?;
+    asus_gpu_toggle(igpu_on, &paths.asus_gpu_mux())?;
     debug!("asus_gpu_mux_set_igpu: success");
     Ok(())
 }
 
-pub fn asus_dgpu_disable_exists() -> bool {
-    if Path::new(ASUS_DGPU_DISABLE_PATH).exists() {
+pub fn asus_dgpu_disable_exists(paths: &HostPaths) -> bool {
+    if paths.asus_dgpu_disable().exists() {
         return true;
     }
     false
 }
 
-pub fn asus_dgpu_disabled() -> Result<bool, GfxError> {
-    let path = Path::new(ASUS_DGPU_DISABLE_PATH);
+pub fn asus_dgpu_disabled(paths: &HostPaths) -> Result<bool, GfxError> {
+    let path = paths.asus_dgpu_disable();
     let mut file = OpenOptions::new()
         .read(true)
-        .open(path)
-        .map_err(|err| GfxError::Path(ASUS_DGPU_DISABLE_PATH.to_string(), err))?;
+        .open(&path)
+        .map_err(|err| GfxError::Path(path.to_string_lossy().to_string(), err))?;
     let mut buf = String::new();
     file.read_to_string(&mut buf)?;
     if buf.contains('1') {
@@ -130,9 +133,9 @@ pub fn asus_dgpu_disabled() -> Result<bool, GfxError> {
 }
 
 /// Special ASUS only feature. On toggle to `off` it will rescan the PCI bus.
-pub fn asus_dgpu_set_disabled(disabled: bool) -> Result<(), GfxError> {
+pub fn asus_dgpu_set_disabled(paths: &HostPaths, disabled: bool) -> Result<(), GfxError> {
     // Do not try to set it again if it has already been changed
-    if asus_dgpu_disabled()? == disabled {
+    if asus_dgpu_disabled(paths)? == disabled {
         debug!("asus_dgpu_set_disabled: already set to {disabled}. Early return");
         return Ok(());
     }
@@ -141,40 +144,32 @@ pub fn asus_dgpu_set_disabled(disabled: bool) -> Result<(), GfxError> {
     // enable, and the deivces require at least a touch of time to finish powering up/down
     std::thread::sleep(Duration::from_millis(500));
     // Need to set, scan, set to ensure mode is correctly set
-    asus_gpu_toggle(disabled, ASUS_DGPU_DISABLE_PATH)?;
+    asus_gpu_toggle(disabled, &paths.asus_dgpu_disable())?;
     if !disabled {
         // Purposefully blocking here. Need to force enough time for things to wake
         std::thread::sleep(Duration::from_millis(50));
-        rescan_pci_bus()?;
+        rescan_pci_bus(paths)?;
     }
     debug!("asus_dgpu_set_disabled: success");
     Ok(())
 }
 
-pub fn asus_egpu_enable_path() -> &'static str {
-    if Path::new(ASUS_EGPU_ALT_ENABLE_PATH).exists() {
-        return ASUS_EGPU_ALT_ENABLE_PATH;
-    }
-
-    return ASUS_EGPU_ENABLE_PATH;
-}
-
-pub fn asus_egpu_enable_exists() -> bool {
-    if Path::new(ASUS_EGPU_ENABLE_PATH).exists() {
+pub fn asus_egpu_enable_exists(paths: &HostPaths) -> bool {
+    if paths.resolve(ASUS_EGPU_ENABLE_PATH).exists() {
         return true;
     }
-    if Path::new(ASUS_EGPU_ALT_ENABLE_PATH).exists() {
+    if paths.resolve(ASUS_EGPU_ALT_ENABLE_PATH).exists() {
         return true;
     }
     false
 }
 
-pub fn asus_egpu_enabled() -> Result<bool, GfxError> {
-    let path = Path::new(asus_egpu_enable_path());
+pub fn asus_egpu_enabled(paths: &HostPaths) -> Result<bool, GfxError> {
+    let path = paths.asus_egpu_enable();
     let mut file = OpenOptions::new()
         .read(true)
-        .open(path)
-        .map_err(|err| GfxError::Path(asus_egpu_enable_path().to_string(), err))?;
+        .open(&path)
+        .map_err(|err| GfxError::Path(path.to_string_lossy().to_string(), err))?;
     let mut buf = String::new();
     file.read_to_string(&mut buf)?;
     if buf.contains('1') {
@@ -184,8 +179,8 @@ pub fn asus_egpu_enabled() -> Result<bool, GfxError> {
 }
 
 /// Special ASUS only feature. On toggle to `on` it will rescan the PCI bus.
-pub fn asus_egpu_set_enabled(enabled: bool) -> Result<(), GfxError> {
-    if asus_egpu_enabled()? == enabled {
+pub fn asus_egpu_set_enabled(paths: &HostPaths, enabled: bool) -> Result<(), GfxError> {
+    if asus_egpu_enabled(paths)? == enabled {
         // Do not try to set it again if it has already been changedif asus_egpu_enabled()? {
         return Ok(());
     }
@@ -194,26 +189,25 @@ pub fn asus_egpu_set_enabled(enabled: bool) -> Result<(), GfxError> {
     // enable, and the deivces require at least a touch of time to finish powering up
     std::thread::sleep(Duration::from_millis(500));
     // Need to set, scan, set to ensure mode is correctly set
-    asus_gpu_toggle(enabled, asus_egpu_enable_path())?;
+    asus_gpu_toggle(enabled, &paths.asus_egpu_enable())?;
     if enabled {
         // Purposefully blocking here. Need to force enough time for things to wake
         std::thread::sleep(Duration::from_millis(50));
-        rescan_pci_bus()?;
+        rescan_pci_bus(paths)?;
     }
     debug!("asus_egpu_set_enabled: success");
     Ok(())
 }
 
-fn asus_gpu_toggle(status: bool, path: &str) -> Result<(), GfxError> {
-    let pathbuf = Path::new(path);
+fn asus_gpu_toggle(status: bool, path: &Path) -> Result<(), GfxError> {
     let mut file = OpenOptions::new()
         .write(true)
-        .open(pathbuf)
-        .map_err(|err| GfxError::Path(path.to_string(), err))?;
+        .open(path)
+        .map_err(|err| GfxError::Path(path.to_string_lossy().to_string(), err))?;
     let status = if status { 1 } else { 0 };
     file.write_all(status.to_string().as_bytes())
-        .map_err(|err| GfxError::Write(path.to_string(), err))?;
-    debug!("switched {path} to {status}");
+        .map_err(|err| GfxError::Write(path.to_string_lossy().to_string(), err))?;
+    debug!("switched {} to {status}", path.display());
     Ok(())
 }
 
@@ -223,34 +217,35 @@ fn asus_gpu_toggle(status: bool, path: &str) -> Result<(), GfxError> {
 /// The returned mode may be different to the requested mode depending on the bios settings active,
 /// the differing value *must* be used.
 pub async fn asus_boot_safety_check(
+    paths: &HostPaths,
     mode: GfxMode,
     asus_use_dgpu_disable: bool,
 ) -> Result<GfxMode, GfxError> {
     debug!("asus_reload: asus_use_dgpu_disable: {asus_use_dgpu_disable}");
     // This is a bit of a crap cycle to ensure that dgpu_disable is there before setting it.
-    if asus_use_dgpu_disable && !asus_dgpu_disable_exists() {
-        if !create_asus_modules_load_conf()? {
+    if asus_use_dgpu_disable && !asus_dgpu_disable_exists(paths) {
+        if !create_asus_modules_load_conf(paths)? {
             warn!(
                 "asus_boot_safety_check: Reboot required due to {} creation",
-                ASUS_MODULES_LOAD_PATH
+                paths.asus_modules_load().display()
             );
             // let mut cmd = Command::new("reboot");
             // cmd.spawn()?;
         }
         warn!("asus_boot_safety_check: HotPlug type Asus is set but asus-wmi appear not loaded yet. Trying for 2 seconds. If there are issues you may need to add asus_nb_wmi to modules.load.d");
         let mut count = 2000 / 50;
-        while !asus_dgpu_disable_exists() && count != 0 {
+        while !asus_dgpu_disable_exists(paths) && count != 0 {
             sleep(Duration::from_millis(50)).await;
             count -= 1;
         }
     }
 
-    if asus_gpu_mux_exists() {
-        match asus_gpu_mux_mode()? {
+    if asus_gpu_mux_exists(paths) {
+        match asus_gpu_mux_mode(paths)? {
             AsusGpuMuxMode::Discreet => {
-                if asus_dgpu_disable_exists() && asus_dgpu_disabled()? {
+                if asus_dgpu_disable_exists(paths) && asus_dgpu_disabled(paths)? {
                     error!("asus_boot_safety_check: dgpu_disable is on while gpu_mux_mode is descrete, can't continue safely, attempting to set dgpu_disable off");
-                    asus_dgpu_set_disabled(false)?;
+                    asus_dgpu_set_disabled(paths, false)?;
                 } else {
                     info!("asus_boot_safety_check: dgpu_disable is off");
                 }
@@ -266,12 +261,12 @@ pub async fn asus_boot_safety_check(
     }
 
     // Need to always check if dgpu_disable exists since GA401I series and older doesn't have this
-    if asus_dgpu_disable_exists() {
-        let dgpu_disabled = asus_dgpu_disabled()?;
+    if asus_dgpu_disable_exists(paths) {
+        let dgpu_disabled = asus_dgpu_disabled(paths)?;
         // If dgpu_disable is hard set then users won't have a dgpu at all, try set dgpu enabled
         if !asus_use_dgpu_disable && dgpu_disabled {
             warn!("It appears dgpu_disable is true on boot with HotPlug type not set to Asus, will attempt to re-enable dgpu");
-            if asus_dgpu_set_disabled(false)
+            if asus_dgpu_set_disabled(paths, false)
                 .map_err(|e| error!("asus_dgpu_set_disabled: {e:?}"))
                 .is_ok()
             {
@@ -285,13 +280,13 @@ pub async fn asus_boot_safety_check(
         }
     }
 
-    if asus_egpu_enable_exists() {
-        if asus_egpu_enabled()? && mode != GfxMode::AsusEgpu {
+    if asus_egpu_enable_exists(paths) {
+        if asus_egpu_enabled(paths)? && mode != GfxMode::AsusEgpu {
             warn!("asus_boot_safety_check: egpu_enable is on but the mode isn't AsusEgpu, setting mode to AsusEgpu");
             return Ok(GfxMode::AsusEgpu);
         } else if asus_use_dgpu_disable // using asus hotplug?
-            && asus_dgpu_disable_exists()
-            && asus_dgpu_disabled()?
+            && asus_dgpu_disable_exists(paths)
+            && asus_dgpu_disabled(paths)?
         // and dgpu is disabled?
         {
             return Ok(GfxMode::Integrated); // really should be in this mode if dgpu disabled
@@ -1,6 +1,8 @@
-use crate::error::GfxError;
+use crate::{
+    command::{command_line, CommandRunner},
+    error::GfxError,
+};
 use log::info;
-use std::process::Command;
 
 /// An action for `systemctl`
 #[derive(Debug, Copy, Clone)]
@@ -36,30 +38,33 @@ impl From<SystemdUnitState> for &str {
 }
 
 /// Change the state of a systemd unit. Blocks while running command.
-pub fn do_systemd_unit_action(action: SystemdUnitAction, unit: &str) -> Result<(), GfxError> {
-    let mut cmd = Command::new("systemctl");
-    cmd.arg(<&str>::from(action));
-    cmd.arg(unit);
+pub fn do_systemd_unit_action(
+    runner: &dyn CommandRunner,
+    action: SystemdUnitAction,
+    unit: &str,
+) -> Result<(), GfxError> {
+    let args = [<&str>::from(action), unit];
     info!("Running systemctl command {action:?} on {unit}");
-    let status = cmd
-        .status()
-        .map_err(|err| GfxError::Command(format!("{:?}", cmd), err))?;
-    if !status.success() {
-        let msg = format!("systemctl {action:?} {unit} failed: {status:?}",);
+    let output = runner
+        .run("systemctl", &args)
+        .map_err(|err| GfxError::Command(command_line("systemctl", &args), err))?;
+    if !output.success {
+        let msg = format!("systemctl {action:?} {unit} failed: {:?}", output.code);
         return Err(GfxError::SystemdUnitAction(msg));
     }
     Ok(())
 }
 
 /// Get systemd unit state. Blocks while command is run.
-pub fn is_systemd_unit_state(state: SystemdUnitState, unit: &str) -> Result<bool, GfxError> {
-    let mut cmd = Command::new("systemctl");
-    cmd.arg("is-active");
-    cmd.arg(unit);
-
-    let output = cmd
-        .output()
-        .map_err(|err| GfxError::Command(format!("{:?}", cmd), err))?;
+pub fn is_systemd_unit_state(
+    runner: &dyn CommandRunner,
+    state: SystemdUnitState,
+    unit: &str,
+) -> Result<bool, GfxError> {
+    let args = ["is-active", unit];
+    let output = runner
+        .run("systemctl", &args)
+        .map_err(|err| GfxError::Command(command_line("systemctl", &args), err))?;
     if output.stdout.starts_with(<&str>::from(state).as_bytes()) {
         return Ok(true);
     }
@@ -67,19 +72,16 @@ pub fn is_systemd_unit_state(state: SystemdUnitState, unit: &str) -> Result<bool
 }
 
 /// Wait for a systemd unit to change to `state`. Checks state every 250ms for 3 seconds. Blocks while running wait.
-pub fn wait_systemd_unit_state(state: SystemdUnitState, unit: &str) -> Result<(), GfxError> {
-    let mut cmd = Command::new("systemctl");
-    cmd.arg("is-active");
-    cmd.arg(unit);
-
+pub fn wait_systemd_unit_state(
+    runner: &dyn CommandRunner,
+    state: SystemdUnitState,
+    unit: &str,
+) -> Result<(), GfxError> {
     let mut count = 0;
 
     while count <= (4 * 3) {
         // 3 seconds max
-        let output = cmd
-            .output()
-            .map_err(|err| GfxError::Command(format!("{:?}", cmd), err))?;
-        if output.stdout.starts_with(<&str>::from(state).as_bytes()) {
+        if is_systemd_unit_state(runner, state, unit)? {
             return Ok(());
         }
         // fine to block here, nobody doing shit now
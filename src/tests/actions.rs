@@ -22,8 +22,16 @@ impl StagedAction {
             ]
             .contains(&previous_action),
 
+            StagedAction::SendDetachEvent => [
+                StagedAction::KillNvidia,
+                StagedAction::KillAmd,
+                StagedAction::NotNvidia,
+            ]
+            .contains(&previous_action),
+
             StagedAction::LoadGpuDrivers => previous_action == StagedAction::RescanPci,
             StagedAction::UnloadGpuDrivers => [
+                StagedAction::SendDetachEvent,
                 StagedAction::StopDisplayManager,
                 StagedAction::DisableNvidiaPowerd,
                 StagedAction::KillNvidia,
@@ -51,6 +59,7 @@ impl StagedAction {
             .contains(&previous_action),
 
             StagedAction::EnableNvidiaPowerd => [
+                StagedAction::EnableNvidiaPersistenced,
                 StagedAction::DevTreeManaged,
                 StagedAction::LoadGpuDrivers,
                 StagedAction::None,
@@ -58,6 +67,7 @@ impl StagedAction {
             .contains(&previous_action),
 
             StagedAction::DisableNvidiaPowerd => [
+                StagedAction::DisableNvidiaPersistenced,
                 StagedAction::StopDisplayManager,
                 StagedAction::NoLogind,
                 StagedAction::RescanPci,
@@ -204,13 +214,19 @@ impl StagedAction {
             ]
             .contains(&next_allowed_action),
 
+            StagedAction::SendDetachEvent => {
+                [StagedAction::UnloadGpuDrivers].contains(&next_allowed_action)
+            }
+
             StagedAction::KillNvidia => [
+                StagedAction::SendDetachEvent,
                 StagedAction::UnloadGpuDrivers,
                 StagedAction::UnloadVfioDrivers,
             ]
             .contains(&next_allowed_action),
 
             StagedAction::KillAmd => [
+                StagedAction::SendDetachEvent,
                 StagedAction::UnloadGpuDrivers,
                 StagedAction::UnloadVfioDrivers,
             ]
@@ -229,6 +245,7 @@ impl StagedAction {
             }
 
             StagedAction::EnableNvidiaPersistenced => [
+                StagedAction::EnableNvidiaPowerd,
                 StagedAction::StartDisplayManager,
                 StagedAction::AsusMuxDgpu,
                 StagedAction::NoLogind,
@@ -236,9 +253,12 @@ impl StagedAction {
             ]
             .contains(&next_allowed_action),
 
-            StagedAction::DisableNvidiaPersistenced => {
-                [StagedAction::KillNvidia, StagedAction::KillAmd].contains(&next_allowed_action)
-            }
+            StagedAction::DisableNvidiaPersistenced => [
+                StagedAction::DisableNvidiaPowerd,
+                StagedAction::KillNvidia,
+                StagedAction::KillAmd,
+            ]
+            .contains(&next_allowed_action),
             StagedAction::LoadVfioDrivers => [StagedAction::None].contains(&next_allowed_action),
             StagedAction::UnloadVfioDrivers => [
                 StagedAction::UnbindRemoveGpu,
@@ -309,6 +329,7 @@ impl StagedAction {
             .contains(&next_allowed_action),
 
             StagedAction::NotNvidia => [
+                StagedAction::SendDetachEvent,
                 StagedAction::KillAmd,
                 StagedAction::StartDisplayManager,
                 StagedAction::NoLogind,
@@ -345,18 +366,14 @@ impl StagedAction {
 mod tests {
     use crate::{
         actions::{Action, StagedAction},
-        config::GfxConfig,
+        config::GfxSettings,
         pci_device::{GfxMode, GfxVendor, HotplugType},
     };
 
     #[test]
     fn verify_hybrid_to_integrated_action_order() {
-        let mut config = GfxConfig {
-            config_path: Default::default(),
+        let mut config = GfxSettings {
             mode: crate::pci_device::GfxMode::Hybrid,
-            tmp_mode: None,
-            pending_mode: None,
-            pending_action: None,
             vfio_enable: false,
             vfio_save: false,
             always_reboot: false,
@@ -415,12 +432,8 @@ mod tests {
 
     #[test]
     fn verify_integrated_to_hybrid_action_order() {
-        let mut config = GfxConfig {
-            config_path: Default::default(),
+        let mut config = GfxSettings {
             mode: crate::pci_device::GfxMode::Integrated,
-            tmp_mode: None,
-            pending_mode: None,
-            pending_action: None,
             vfio_enable: false,
             vfio_save: false,
             always_reboot: false,
@@ -489,12 +502,8 @@ mod tests {
             GfxMode::None,
         ];
 
-        let mut config = GfxConfig {
-            config_path: Default::default(),
+        let mut config = GfxSettings {
             mode: crate::pci_device::GfxMode::Hybrid,
-            tmp_mode: None,
-            pending_mode: None,
-            pending_action: None,
             vfio_enable: false,
             vfio_save: false,
             always_reboot: false,
@@ -503,7 +512,7 @@ mod tests {
             hotplug_type: crate::pci_device::HotplugType::None,
         };
 
-        let run = |config: &GfxConfig| {
+        let run = |config: &GfxSettings| {
             for from in modes {
                 for to in modes {
                     for vendor in [GfxVendor::Nvidia, GfxVendor::Amd] {
@@ -568,12 +577,8 @@ mod tests {
             GfxMode::None,
         ];
 
-        let mut config = GfxConfig {
-            config_path: Default::default(),
+        let mut config = GfxSettings {
             mode: crate::pci_device::GfxMode::Hybrid,
-            tmp_mode: None,
-            pending_mode: None,
-            pending_action: None,
             vfio_enable: false,
             vfio_save: false,
             always_reboot: false,
@@ -582,7 +587,7 @@ mod tests {
             hotplug_type: crate::pci_device::HotplugType::None,
         };
 
-        let run = |config: &GfxConfig| {
+        let run = |config: &GfxSettings| {
             for from in modes {
                 for to in modes {
                     for vendor in [GfxVendor::Nvidia, GfxVendor::Amd] {
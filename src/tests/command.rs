@@ -0,0 +1,124 @@
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{
+        actions::{Action, StagedAction},
+        command::{CommandOutput, RecordingCommandRunner},
+        error::GfxError,
+        pci_device::GfxMode,
+        tests::fake_host::FakeHost,
+    };
+
+    #[tokio::test]
+    async fn hybrid_to_integrated_commands() {
+        let host = FakeHost::nvidia_laptop();
+        host.write("/dev/nvidia0", "");
+        host.write("/usr/bin/lsof", "");
+        let runner = Arc::new(RecordingCommandRunner::new());
+        runner.reply(
+            "lsof /dev/nvidia0",
+            CommandOutput::ok("COMMAND PID USER\nsteam 1234 user\n"),
+        );
+        let mut dgpu = host.dgpu(runner.clone());
+        let ctx = host.context(GfxMode::Hybrid);
+
+        let config = host.config(GfxMode::Hybrid);
+        let Action::StagedActions(actions) = StagedAction::action_list_for_switch(
+            &config,
+            dgpu.vendor(),
+            GfxMode::Hybrid,
+            GfxMode::Integrated,
+        ) else {
+            panic!("Should be a list of actions");
+        };
+        for action in actions {
+            action
+                .perform(GfxMode::Integrated, &mut dgpu, &ctx)
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(
+            runner.calls(),
+            [
+                "systemctl stop nvidia-persistenced.service",
+                "systemctl stop nvidia-powerd.service",
+                "lsof /dev/nvidia0",
+                "kill -9 1234",
+                "rmmod -f nvidia_drm",
+                "rmmod nvidia_modeset",
+                "rmmod nvidia_uvm",
+                "rmmod nvidia",
+                "rmmod nvidia_wmi_ec_backlight",
+            ]
+        );
+        assert_eq!(host.read("/sys/bus/pci/devices/0000:01:00.0/remove"), "1");
+    }
+
+    #[tokio::test]
+    async fn vfio_builtin_is_reported() {
+        let host = FakeHost::nvidia_laptop();
+        let runner = Arc::new(RecordingCommandRunner::new());
+        runner.reply(
+            "modprobe vfio-pci",
+            CommandOutput::failed(1, "modprobe: FATAL: Module vfio-pci is builtin.\n"),
+        );
+        let mut dgpu = host.dgpu(runner.clone());
+
+        let res = StagedAction::LoadVfioDrivers
+            .perform(GfxMode::Vfio, &mut dgpu, &host.context(GfxMode::Hybrid))
+            .await;
+        assert!(matches!(res, Err(GfxError::VfioBuiltin)));
+        assert_eq!(runner.calls(), ["modprobe vfio-pci"]);
+    }
+
+    #[tokio::test]
+    async fn missing_module_is_reported() {
+        let host = FakeHost::nvidia_laptop();
+        let runner = Arc::new(RecordingCommandRunner::new());
+        runner.reply(
+            "modprobe -f nvidia_drm",
+            CommandOutput::failed(
+                1,
+                "modprobe: FATAL: Module nvidia_drm not found in directory /lib/modules/6.1.0\n",
+            ),
+        );
+        let mut dgpu = host.dgpu(runner.clone());
+
+        let res = StagedAction::LoadGpuDrivers
+            .perform(GfxMode::Hybrid, &mut dgpu, &host.context(GfxMode::Hybrid))
+            .await;
+        assert!(
+            matches!(res, Err(GfxError::MissingModule(ref m)) if m == "nvidia_drm"),
+            "{res:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn display_manager_stop_waits_for_inactive() {
+        let host = FakeHost::nvidia_laptop();
+        let runner = Arc::new(RecordingCommandRunner::new());
+        runner.reply(
+            "systemctl is-active display-manager.service",
+            CommandOutput::ok("inactive\n"),
+        );
+        let mut dgpu = host.dgpu(runner.clone());
+
+        StagedAction::StopDisplayManager
+            .perform(
+                GfxMode::Integrated,
+                &mut dgpu,
+                &host.context(GfxMode::Hybrid),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            runner.calls(),
+            [
+                "systemctl stop display-manager.service",
+                "systemctl is-active display-manager.service",
+            ]
+        );
+    }
+}
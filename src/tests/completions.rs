@@ -0,0 +1,135 @@
+#[cfg(test)]
+mod tests {
+    use crate::completions::{parse_commands, parse_options, CliCommand, CliOption, CliSpec};
+
+    const USAGE: &str = "Usage: supergfxctl [OPTIONS]
+
+Optional arguments:
+  -h, --help          print help message
+  -m, --mode MODE     Set graphics mode
+  -d, --dry-run       With --mode, print the steps
+  --long-only         Only has a long name
+  -t, --timeout SECS  With --wait, give up after this many seconds
+";
+
+    fn spec() -> CliSpec {
+        let mut commands = parse_commands(
+            "  config       Get or set the daemon config
+  completions  Print a completion script",
+        );
+        commands[0].commands = parse_commands("  get  Print a key\n  set  Set a key");
+        commands[0].commands[0].values = vec!["vfio_enable".to_string()];
+        commands[1].values = vec!["bash".to_string(), "zsh".to_string()];
+        CliSpec {
+            name: "supergfxctl".to_string(),
+            version: "1.2.3".to_string(),
+            about: "control the graphics mode".to_string(),
+            options: parse_options(USAGE),
+            commands,
+            mode_option: "mode".to_string(),
+            mode_query: "--supported".to_string(),
+            fallback_modes: vec!["Hybrid".to_string(), "Integrated".to_string()],
+            sections: vec![(
+                "Exit status".to_string(),
+                "0 on success.\n\n.1 is odd".to_string(),
+            )],
+        }
+    }
+
+    #[test]
+    fn options_from_usage() {
+        let options = parse_options(USAGE);
+        assert_eq!(options.len(), 5);
+        assert_eq!(
+            options[1],
+            CliOption {
+                short: Some('m'),
+                long: Some("mode".to_string()),
+                meta: Some("MODE".to_string()),
+                help: "Set graphics mode".to_string(),
+            }
+        );
+        assert_eq!(options[2].long.as_deref(), Some("dry-run"));
+        assert_eq!(options[2].meta, None);
+        assert_eq!(options[2].help, "With --mode, print the steps");
+        assert_eq!(options[3].short, None);
+        assert_eq!(options[3].long.as_deref(), Some("long-only"));
+        assert_eq!(options[4].meta.as_deref(), Some("SECS"));
+    }
+
+    #[test]
+    fn commands_from_list() {
+        assert_eq!(
+            parse_commands(
+                "  config  Get or set the daemon config\n  man     Print the man page\n"
+            ),
+            [
+                CliCommand {
+                    name: "config".to_string(),
+                    help: "Get or set the daemon config".to_string(),
+                    ..Default::default()
+                },
+                CliCommand {
+                    name: "man".to_string(),
+                    help: "Print the man page".to_string(),
+                    ..Default::default()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn bash_completes_modes_and_commands() {
+        let spec = spec();
+        let script = spec.completions("bash").unwrap();
+        assert!(script.contains("supergfxctl --supported 2>/dev/null"));
+        assert!(script.contains("modes=\"Hybrid Integrated\""));
+        assert!(script.contains("        -m|--mode)\n"));
+        assert!(script.contains("        -t|--timeout)\n"));
+        assert!(script.contains("--long-only"));
+        assert!(script.contains("\" config get\")"));
+        assert!(script.contains("\"--help vfio_enable\""));
+        assert!(script.contains("\"--help bash zsh\""));
+        assert!(script.ends_with("complete -F _supergfxctl supergfxctl\n"));
+        assert!(spec.completions("tcsh").is_none());
+    }
+
+    #[test]
+    fn zsh_escapes_descriptions() {
+        let mut spec = spec();
+        spec.options[0].help = "it's [here]: now".to_string();
+        let script = spec.completions("zsh").unwrap();
+        assert!(script.starts_with("#compdef supergfxctl\n"));
+        assert!(script.contains("'(-h --help)'{-h,--help}'[it'\\''s \\[here\\]\\: now]'"));
+        assert!(script.contains("'[Set graphics mode]:MODE:_supergfxctl_modes'"));
+        assert!(script.contains("'--long-only[Only has a long name]'"));
+        assert!(script.contains("config) _supergfxctl_config ;;"));
+        assert!(script.contains("_supergfxctl_config_get() {"));
+        assert!(script.contains("'1:value:(bash zsh)'"));
+    }
+
+    #[test]
+    fn fish_uses_subcommand_conditions() {
+        let script = spec().completions("fish").unwrap();
+        assert!(script.contains(
+            "complete -c supergfxctl -n __fish_use_subcommand -s m -l mode -r -a '(__supergfxctl_modes)' -d 'Set graphics mode'"
+        ));
+        assert!(script.contains(
+            "-n '__fish_seen_subcommand_from config; and not __fish_seen_subcommand_from get set' -a get"
+        ));
+        assert!(script.contains("-a 'bash zsh'"));
+    }
+
+    #[test]
+    fn man_page_is_escaped() {
+        let page = spec().man_page();
+        assert!(
+            page.starts_with(".TH SUPERGFXCTL 1 \"\" \"supergfxctl 1.2.3\" \"User Commands\"\n")
+        );
+        assert!(page
+            .contains(".TP\n\\fB\\-m\\fR, \\fB\\-\\-mode\\fR \\fIMODE\\fR\nSet graphics mode\n"));
+        assert!(page.contains("\\fB\\-\\-long\\-only\\fR\n"));
+        assert!(page.contains("\\fBconfig get\\fR <\\fIvfio_enable\\fR>"));
+        assert!(page.contains(".SH EXIT STATUS\n0 on success.\n.PP\n\\&.1 is odd\n"));
+    }
+}
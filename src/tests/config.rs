@@ -0,0 +1,530 @@
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use zbus::zvariant::{serialized::Context, to_bytes, Type, LE};
+
+    use crate::{
+        actions::{SwitchEvent, UserActionRequired},
+        command::RecordingCommandRunner,
+        config::{
+            ConfigStore, GfxConfigDbus, GfxSettings, CONFIG_KEYS, CONFIG_VERSION, DEFAULT_ORIGIN,
+        },
+        error::GfxError,
+        pci_device::{GfxMode, HotplugType},
+        tests::fake_host::FakeHost,
+    };
+
+    fn dbus_config(mode: GfxMode) -> GfxConfigDbus {
+        GfxConfigDbus {
+            mode,
+            vfio_enable: true,
+            vfio_save: true,
+            always_reboot: false,
+            no_logind: true,
+            logout_timeout_s: 42,
+            hotplug_type: HotplugType::Std,
+        }
+    }
+
+    #[test]
+    fn dbus_round_trip() {
+        assert_eq!(GfxConfigDbus::SIGNATURE.to_string(), "(ubbbbtu)");
+
+        let config = dbus_config(GfxMode::Vfio);
+        let ctxt = Context::new_dbus(LE, 0);
+        let encoded = to_bytes(ctxt, &config).unwrap();
+        let (decoded, _): (GfxConfigDbus, _) = encoded.deserialize().unwrap();
+        assert_eq!(decoded, config);
+    }
+
+    #[tokio::test]
+    async fn every_field_is_saved() {
+        let host = FakeHost::nvidia_laptop();
+        let mut ctrl = host.controller(
+            host.config(GfxMode::Hybrid),
+            Arc::new(RecordingCommandRunner::new()),
+        );
+
+        let new = GfxConfigDbus {
+            vfio_enable: false,
+            always_reboot: true,
+            hotplug_type: HotplugType::Asus,
+            ..dbus_config(GfxMode::Hybrid)
+        };
+        let action = ctrl.set_gfx_config(new.clone(), None).await.unwrap();
+        assert!(action.is_none());
+        assert_eq!(GfxConfigDbus::from(&*ctrl.config.lock().await), new);
+
+        let saved = host.store().load().unwrap();
+        assert_eq!(GfxConfigDbus::from(&saved), new);
+    }
+
+    #[tokio::test]
+    async fn mode_change_starts_switch() {
+        let host = FakeHost::nvidia_laptop();
+        let mut ctrl = host.controller(
+            host.config(GfxMode::Hybrid),
+            Arc::new(RecordingCommandRunner::new()),
+        );
+        let mut rx = ctrl.subscribe_switch_events();
+
+        let action = ctrl
+            .set_gfx_config(dbus_config(GfxMode::Integrated), Some(1000))
+            .await
+            .unwrap();
+        assert!(matches!(action, Some(UserActionRequired::Nothing)));
+
+        loop {
+            let event = tokio::time::timeout(Duration::from_secs(10), rx.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            if let SwitchEvent::Result { success, mode, .. } = event {
+                assert!(success);
+                assert_eq!(mode, GfxMode::Integrated);
+                break;
+            }
+        }
+        let saved = host.store().load().unwrap();
+        assert_eq!(
+            GfxConfigDbus::from(&saved),
+            dbus_config(GfxMode::Integrated)
+        );
+        assert_eq!(ctrl.get_history(1).unwrap()[0].uid, 1000);
+    }
+
+    #[tokio::test]
+    async fn invalid_config_is_rejected() {
+        let host = FakeHost::nvidia_laptop();
+        let mut ctrl = host.controller(
+            host.config(GfxMode::Hybrid),
+            Arc::new(RecordingCommandRunner::new()),
+        );
+        let before = GfxConfigDbus::from(&*ctrl.config.lock().await);
+
+        let vfio_disabled = GfxConfigDbus {
+            vfio_enable: false,
+            ..dbus_config(GfxMode::Vfio)
+        };
+        assert!(matches!(
+            ctrl.set_gfx_config(vfio_disabled, None).await,
+            Err(GfxError::VfioDisabled)
+        ));
+
+        std::fs::remove_file(host.path("/sys/devices/platform/asus-nb-wmi/dgpu_disable")).unwrap();
+        let asus_hotplug = GfxConfigDbus {
+            hotplug_type: HotplugType::Asus,
+            ..dbus_config(GfxMode::Hybrid)
+        };
+        assert!(matches!(
+            ctrl.set_gfx_config(asus_hotplug, None).await,
+            Err(GfxError::NotSupported(_))
+        ));
+
+        assert_eq!(GfxConfigDbus::from(&*ctrl.config.lock().await), before);
+        assert_eq!(ctrl.get_pending_mode().await, GfxMode::None);
+    }
+
+    #[test]
+    fn keys_get_and_set() {
+        let mut config = dbus_config(GfxMode::Hybrid);
+        for (key, value) in [
+            ("vfio_enable", "false"),
+            ("vfio_save", "false"),
+            ("always_reboot", "true"),
+            ("no_logind", "false"),
+            ("logout_timeout_s", "0"),
+            ("hotplug_type", "Asus"),
+        ] {
+            assert_ne!(config.get(key).unwrap(), value);
+            config.set(key, value).unwrap();
+            assert_eq!(config.get(key).unwrap(), value);
+        }
+        for key in CONFIG_KEYS {
+            config.get(key).unwrap();
+        }
+        assert_eq!(config.mode, GfxMode::Hybrid);
+    }
+
+    #[test]
+    fn bad_keys_and_values() {
+        let mut config = dbus_config(GfxMode::Hybrid);
+        assert!(matches!(
+            config.get("mode"),
+            Err(GfxError::UnknownConfigKey(key)) if key == "mode"
+        ));
+        assert!(matches!(
+            config.set("mode", "Vfio"),
+            Err(GfxError::UnknownConfigKey(_))
+        ));
+        for (key, value) in [
+            ("vfio_enable", "yes"),
+            ("logout_timeout_s", "-1"),
+            ("hotplug_type", "asus"),
+        ] {
+            assert!(matches!(
+                config.set(key, value),
+                Err(GfxError::InvalidConfigValue(k, v)) if k == key && v == value
+            ));
+        }
+        assert_eq!(config, dbus_config(GfxMode::Hybrid));
+    }
+
+    /// Write `content` as the config, load it and return the config and the saved file
+    /// Load `content` as the config file, returning the settings, the warning and the
+    /// file as saved after loading
+    fn load_config(
+        host: &FakeHost,
+        content: &str,
+    ) -> (GfxSettings, Option<String>, serde_json::Value) {
+        host.write("/etc/supergfxd.conf", content);
+        let mut store = host.store();
+        let config = store.load().unwrap();
+        let saved = std::fs::read_to_string(host.path("/etc/supergfxd.conf")).unwrap();
+        (config, store.warning, serde_json::from_str(&saved).unwrap())
+    }
+
+    #[test]
+    fn every_old_format_is_migrated() {
+        let v300 = r#"{"gfx_mode":"Egpu","gfx_managed":true,"gfx_vfio_enable":true}"#;
+        let v402 = r#"{"mode":"Integrated","vfio_enable":true,"vfio_save":true,
+            "compute_save":false,"always_reboot":true}"#;
+        let v405 = r#"{"mode":"Vfio","vfio_enable":true,"vfio_save":false,"compute_save":false,
+            "always_reboot":false,"no_logind":true,"logout_timeout_s":30}"#;
+        let v500 = r#"{"mode":"Hybrid","vfio_enable":false,"vfio_save":false,"compute_save":false,
+            "always_reboot":false,"no_logind":false,"logout_timeout_s":60,"hotplug_type":"Asus"}"#;
+
+        for (content, version, expected) in [
+            (
+                v300,
+                1,
+                GfxConfigDbus {
+                    mode: GfxMode::AsusEgpu,
+                    vfio_enable: true,
+                    vfio_save: false,
+                    always_reboot: false,
+                    no_logind: false,
+                    logout_timeout_s: 180,
+                    hotplug_type: HotplugType::None,
+                },
+            ),
+            (
+                v402,
+                2,
+                GfxConfigDbus {
+                    mode: GfxMode::Integrated,
+                    vfio_enable: true,
+                    vfio_save: true,
+                    always_reboot: true,
+                    no_logind: false,
+                    logout_timeout_s: 180,
+                    hotplug_type: HotplugType::None,
+                },
+            ),
+            (
+                v405,
+                3,
+                GfxConfigDbus {
+                    mode: GfxMode::Vfio,
+                    vfio_enable: true,
+                    vfio_save: false,
+                    always_reboot: false,
+                    no_logind: true,
+                    logout_timeout_s: 30,
+                    hotplug_type: HotplugType::None,
+                },
+            ),
+            (
+                v500,
+                4,
+                GfxConfigDbus {
+                    mode: GfxMode::Hybrid,
+                    vfio_enable: false,
+                    vfio_save: false,
+                    always_reboot: false,
+                    no_logind: false,
+                    logout_timeout_s: 60,
+                    hotplug_type: HotplugType::Asus,
+                },
+            ),
+        ] {
+            let host = FakeHost::nvidia_laptop();
+            let (config, _, saved) = load_config(&host, content);
+            assert_eq!(GfxConfigDbus::from(&config), expected, "version {version}");
+            assert_eq!(saved["version"], CONFIG_VERSION, "version {version}");
+            assert!(saved.get("compute_save").is_none());
+
+            let backup = host.path(&format!("/etc/supergfxd.conf.v{version}.bak"));
+            assert_eq!(std::fs::read_to_string(backup).unwrap(), content);
+        }
+    }
+
+    #[test]
+    fn current_format_is_not_backed_up() {
+        let host = FakeHost::nvidia_laptop();
+        let config = host.config(GfxMode::Vfio);
+        host.store().write(&config).unwrap();
+        let (loaded, _, saved) = load_config(
+            &host,
+            &std::fs::read_to_string(host.path("/etc/supergfxd.conf")).unwrap(),
+        );
+        assert_eq!(GfxConfigDbus::from(&loaded), GfxConfigDbus::from(&config));
+        assert_eq!(saved["version"], CONFIG_VERSION);
+
+        // Saved by a release before the version was added
+        let mut unversioned = saved.clone();
+        unversioned.as_object_mut().unwrap().remove("version");
+        let (loaded, ..) = load_config(&host, &unversioned.to_string());
+        assert_eq!(GfxConfigDbus::from(&loaded), GfxConfigDbus::from(&config));
+
+        let backups = std::fs::read_dir(host.path("/etc"))
+            .unwrap()
+            .filter(|e| {
+                e.as_ref()
+                    .unwrap()
+                    .file_name()
+                    .to_string_lossy()
+                    .contains(".conf.v")
+            })
+            .count();
+        assert_eq!(backups, 0);
+    }
+
+    #[test]
+    fn broken_format_falls_back_to_defaults() {
+        let host = FakeHost::nvidia_laptop();
+        for content in [r#"{"version":0}"#, "[]"] {
+            let (config, warning, saved) = load_config(&host, content);
+            assert_eq!(config.mode, GfxMode::Hybrid, "{content}");
+            assert!(warning.unwrap().contains("reset to the defaults"));
+            assert_eq!(saved["version"], CONFIG_VERSION);
+        }
+    }
+
+    #[test]
+    fn newer_format_is_loaded_read_only() {
+        let host = FakeHost::nvidia_laptop();
+        let mut newer = serde_json::to_value(host.config(GfxMode::Vfio)).unwrap();
+        newer["version"] = (CONFIG_VERSION + 1).into();
+        newer["vfio_enable"] = true.into();
+        newer["logout_timeout_s"] = "soon".into();
+        newer["future_key"] = 1.into();
+        let content = newer.to_string();
+        host.write("/etc/supergfxd.conf", &content);
+
+        let mut store = host.store();
+        let mut config = store.load().unwrap();
+        assert_eq!(config.mode, GfxMode::Vfio);
+        assert!(config.vfio_enable);
+        assert_eq!(
+            config.logout_timeout_s,
+            GfxSettings::default().logout_timeout_s
+        );
+        assert!(store.read_only);
+        assert!(store
+            .warning
+            .take()
+            .unwrap()
+            .contains("newer config version"));
+        assert_eq!(host.read("/etc/supergfxd.conf"), content);
+
+        config.mode = GfxMode::Integrated;
+        store.write(&config).unwrap();
+        assert_eq!(host.read("/etc/supergfxd.conf"), content);
+        assert!(!host.exists("/etc/supergfxd.conf.bak"));
+        assert!(!host.exists("/etc/supergfxd.conf.corrupt"));
+
+        // Once the file is back to a known version it is saved again
+        host.write("/etc/supergfxd.conf", r#"{"mode":"Hybrid"}"#);
+        store.read().unwrap();
+        assert!(!store.read_only);
+        store.write(&config).unwrap();
+        assert!(host.read("/etc/supergfxd.conf").contains("Integrated"));
+    }
+
+    #[test]
+    fn write_keeps_the_last_good_config() {
+        let host = FakeHost::nvidia_laptop();
+        let path = host.path("/etc/supergfxd.conf");
+        let store = host.store();
+        let mut config = host.config(GfxMode::Hybrid);
+        store.write(&config).unwrap();
+        let first = std::fs::read_to_string(&path).unwrap();
+        assert!(!host.path("/etc/supergfxd.conf.bak").exists());
+
+        config.mode = GfxMode::Integrated;
+        store.write(&config).unwrap();
+        let bak = std::fs::read_to_string(host.path("/etc/supergfxd.conf.bak")).unwrap();
+        assert_eq!(bak, first);
+        assert!(!host.path("/etc/supergfxd.conf.tmp").exists());
+
+        // An invalid config is never kept as the backup
+        host.write("/etc/supergfxd.conf", "{\"mode\":");
+        store.write(&config).unwrap();
+        let bak = std::fs::read_to_string(host.path("/etc/supergfxd.conf.bak")).unwrap();
+        assert_eq!(bak, first);
+    }
+
+    #[test]
+    fn corrupt_config_is_restored_from_backup() {
+        let host = FakeHost::nvidia_laptop();
+        let store = host.store();
+        let mut config = host.config(GfxMode::Vfio);
+        store.write(&config).unwrap();
+        config.mode = GfxMode::Integrated;
+        store.write(&config).unwrap();
+
+        // Truncated by a crash mid-write with the old non-atomic write
+        let (loaded, warning, saved) =
+            load_config(&host, "{\n  \"version\": 5,\n  \"mode\": \"Integ");
+        assert_eq!(loaded.mode, GfxMode::Vfio);
+        assert_eq!(saved["mode"], "Vfio");
+        let warning = warning.unwrap();
+        assert!(
+            warning.contains("restored the last good config"),
+            "{warning}"
+        );
+        assert_eq!(
+            std::fs::read_to_string(host.path("/etc/supergfxd.conf.corrupt")).unwrap(),
+            "{\n  \"version\": 5,\n  \"mode\": \"Integ"
+        );
+    }
+
+    #[test]
+    fn empty_config_is_restored_from_backup() {
+        let host = FakeHost::nvidia_laptop();
+        let store = host.store();
+        let mut config = host.config(GfxMode::Vfio);
+        store.write(&config).unwrap();
+        config.mode = GfxMode::Integrated;
+        store.write(&config).unwrap();
+
+        for content in ["", " \n"] {
+            let (loaded, warning, saved) = load_config(&host, content);
+            assert_eq!(loaded.mode, GfxMode::Vfio, "{content:?}");
+            assert_eq!(saved["mode"], "Vfio");
+            assert!(warning.unwrap().contains("restored the last good config"));
+        }
+        host.write("/etc/supergfxd.conf", "");
+        assert!(matches!(host.store().read(), Err(GfxError::ParseConfig(_))));
+    }
+
+    #[test]
+    fn config_io_errors_are_returned() {
+        let host = FakeHost::nvidia_laptop();
+        let path = host.path("/missing/supergfxd.conf");
+        assert!(matches!(
+            ConfigStore::new(path.to_string_lossy().into(), host.paths().config_dropin_dirs())
+                .load(),
+            Err(GfxError::Write(p, _)) if p == path.to_string_lossy()
+        ));
+
+        let mut store = host.store();
+        store.write(&host.config(GfxMode::Vfio)).unwrap();
+        host.write("/etc/supergfxd.conf", "not json");
+        assert!(matches!(store.read(), Err(GfxError::ParseConfig(_))));
+
+        host.write(
+            "/etc/supergfxd.conf",
+            &serde_json::to_string(&host.config(GfxMode::Integrated)).unwrap(),
+        );
+        assert_eq!(store.read().unwrap().mode, GfxMode::Integrated);
+        assert!(store.path.ends_with("/etc/supergfxd.conf"));
+    }
+
+    #[test]
+    fn dropins_are_layered_in_order() {
+        let host = FakeHost::nvidia_laptop();
+        host.write(
+            "/usr/lib/supergfxd.conf.d/10-oem.json",
+            r#"{"hotplug_type": "Asus", "logout_timeout_s": 60}"#,
+        );
+        host.write(
+            "/usr/lib/supergfxd.conf.d/50-broken.json",
+            r#"{"logout_timeout_s": "soon"}"#,
+        );
+        host.write(
+            "/usr/lib/supergfxd.conf.d/60-unknown.json",
+            r#"{"always_reboot": true, "gpu": "fast"}"#,
+        );
+        host.write("/usr/lib/supergfxd.conf.d/README", "not a drop-in");
+        host.write(
+            "/etc/supergfxd.conf.d/20-admin.json",
+            r#"{"logout_timeout_s": 30}"#,
+        );
+
+        let path = host.path("/etc/supergfxd.conf");
+        let mut store = host.store();
+        let mut config = store.load().unwrap();
+        assert_eq!(config.hotplug_type, HotplugType::Asus);
+        assert_eq!(config.logout_timeout_s, 30);
+        assert!(config.always_reboot);
+        assert_eq!(config.mode, GfxMode::Hybrid);
+
+        // Nothing differs from the drop-ins, so the state file only has the version
+        let saved: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(saved, serde_json::json!({ "version": CONFIG_VERSION }));
+
+        config.mode = GfxMode::Integrated;
+        config.hotplug_type = HotplugType::Std;
+        store.write(&config).unwrap();
+        let saved: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(
+            saved,
+            serde_json::json!({ "version": CONFIG_VERSION, "mode": "Integrated", "hotplug_type": "Std" })
+        );
+
+        let origin = |config: &GfxSettings, key: &str| {
+            let sources = store.sources(config);
+            let source = sources.iter().find(|s| s.key == key).unwrap();
+            (source.value.clone(), source.origin.clone())
+        };
+        let state = path.to_string_lossy().to_string();
+        let dropin = |p: &str| host.path(p).to_string_lossy().to_string();
+        assert_eq!(store.sources(&config).len(), CONFIG_KEYS.len() + 1);
+        assert_eq!(
+            origin(&config, "mode"),
+            ("Integrated".to_string(), state.clone())
+        );
+        assert_eq!(origin(&config, "hotplug_type"), ("Std".to_string(), state));
+        assert_eq!(
+            origin(&config, "logout_timeout_s"),
+            (
+                "30".to_string(),
+                dropin("/etc/supergfxd.conf.d/20-admin.json")
+            )
+        );
+        assert_eq!(
+            origin(&config, "always_reboot"),
+            (
+                "true".to_string(),
+                dropin("/usr/lib/supergfxd.conf.d/60-unknown.json")
+            )
+        );
+        assert_eq!(
+            origin(&config, "vfio_enable"),
+            ("false".to_string(), DEFAULT_ORIGIN.to_string())
+        );
+
+        // Back to the drop-in value, so it follows the drop-in again
+        config.hotplug_type = HotplugType::Asus;
+        store.write(&config).unwrap();
+        assert_eq!(
+            origin(&config, "hotplug_type"),
+            (
+                "Asus".to_string(),
+                dropin("/usr/lib/supergfxd.conf.d/10-oem.json")
+            )
+        );
+        host.write(
+            "/usr/lib/supergfxd.conf.d/10-oem.json",
+            r#"{"hotplug_type": "None"}"#,
+        );
+        let config = store.read().unwrap();
+        assert_eq!(config.hotplug_type, HotplugType::None);
+        assert_eq!(config.mode, GfxMode::Integrated);
+    }
+}
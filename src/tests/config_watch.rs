@@ -0,0 +1,131 @@
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use tokio::time::timeout;
+
+    use crate::{
+        actions::UserActionRequired,
+        command::RecordingCommandRunner,
+        config::GfxSettings,
+        config_watch::watch_config,
+        error::GfxError,
+        pci_device::{GfxMode, HotplugType},
+        tests::fake_host::FakeHost,
+    };
+
+    const WAIT: Duration = Duration::from_secs(5);
+
+    /// The config as saved, with some fields changed
+    fn edited(host: &FakeHost, edit: impl FnOnce(&mut GfxSettings)) -> String {
+        let mut config = host.config(GfxMode::Hybrid);
+        edit(&mut config);
+        serde_json::to_string_pretty(&config).unwrap()
+    }
+
+    #[tokio::test]
+    async fn edits_and_replacements_are_seen() {
+        let host = FakeHost::nvidia_laptop();
+        let store = host.store();
+        let mut config = host.config(GfxMode::Hybrid);
+        store.write(&config).unwrap();
+        host.mkdir("/etc/supergfxd.conf.d");
+        let mut changes = watch_config(&store.path, &store.layers.dirs);
+
+        // Other files in the directories are ignored
+        host.write("/etc/supergfxd.conf.bak", "{}");
+        host.write("/etc/supergfxd.conf.d/notes.txt", "{}");
+        assert!(timeout(Duration::from_millis(500), changes.recv())
+            .await
+            .is_err());
+
+        // Written in place by an editor
+        host.write(
+            "/etc/supergfxd.conf",
+            &edited(&host, |c| c.no_logind = false),
+        );
+        timeout(WAIT, changes.recv()).await.unwrap().unwrap();
+
+        // Replaced by a rename, as the daemon saves it
+        config.logout_timeout_s = 99;
+        store.write(&config).unwrap();
+        timeout(WAIT, changes.recv()).await.unwrap().unwrap();
+
+        // Drop-ins being added and removed
+        host.write("/etc/supergfxd.conf.d/10-local.json", "{}");
+        timeout(WAIT, changes.recv()).await.unwrap().unwrap();
+        std::fs::remove_file(host.path("/etc/supergfxd.conf.d/10-local.json")).unwrap();
+        timeout(WAIT, changes.recv()).await.unwrap().unwrap();
+        assert!(changes.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn reload_applies_settings_and_keeps_runtime_state() {
+        let host = FakeHost::nvidia_laptop();
+        let config = host.config(GfxMode::Hybrid);
+        host.store().write(&config).unwrap();
+        let mut ctrl = host.controller(config, Arc::new(RecordingCommandRunner::new()));
+        {
+            let mut runtime = ctrl.runtime.lock().await;
+            runtime.tmp_mode = Some(GfxMode::Vfio);
+            runtime.pending_mode = Some(GfxMode::Integrated);
+            runtime.pending_action = Some(UserActionRequired::Logout);
+        }
+
+        assert_eq!(ctrl.reload_config().await.unwrap(), None);
+
+        host.write(
+            "/etc/supergfxd.conf",
+            &edited(&host, |c| {
+                c.mode = GfxMode::Integrated;
+                c.logout_timeout_s = 30;
+                c.hotplug_type = HotplugType::Std;
+            }),
+        );
+        let new = ctrl.reload_config().await.unwrap().unwrap();
+        assert_eq!(new.mode, GfxMode::Hybrid);
+        assert_eq!(new.logout_timeout_s, 30);
+        assert_eq!(new.hotplug_type, HotplugType::Std);
+
+        assert_eq!(ctrl.config.lock().await.logout_timeout_s, 30);
+        let runtime = ctrl.runtime.lock().await.clone();
+        assert_eq!(runtime.tmp_mode, Some(GfxMode::Vfio));
+        assert_eq!(runtime.pending_mode, Some(GfxMode::Integrated));
+        assert!(matches!(
+            runtime.pending_action,
+            Some(UserActionRequired::Logout)
+        ));
+
+        // The mode in the file is put back to the current mode
+        let saved = host.store().load().unwrap();
+        assert_eq!(saved.mode, GfxMode::Hybrid);
+        assert_eq!(saved.logout_timeout_s, 30);
+    }
+
+    #[tokio::test]
+    async fn invalid_edits_are_not_applied() {
+        let host = FakeHost::nvidia_laptop();
+        let config = host.config(GfxMode::Vfio);
+        host.store().write(&config).unwrap();
+        let mut ctrl = host.controller(config, Arc::new(RecordingCommandRunner::new()));
+
+        host.write("/etc/supergfxd.conf", "{\"mode\": ");
+        assert!(matches!(
+            ctrl.reload_config().await,
+            Err(GfxError::ParseConfig(_))
+        ));
+
+        host.write(
+            "/etc/supergfxd.conf",
+            &edited(&host, |c| {
+                c.mode = GfxMode::Vfio;
+                c.vfio_enable = false;
+            }),
+        );
+        assert!(matches!(
+            ctrl.reload_config().await,
+            Err(GfxError::VfioDisabled)
+        ));
+        assert!(ctrl.config.lock().await.vfio_enable);
+    }
+}
@@ -0,0 +1,304 @@
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use crate::{
+        actions::{
+            BlockingSession, StagedAction, SwitchEvent, SwitchOutcome, SwitchStepStatus,
+            UserActionRequired,
+        },
+        command::RecordingCommandRunner,
+        error::GfxError,
+        pci_device::GfxMode,
+        tests::fake_host::{FakeHost, StuckSessions},
+    };
+
+    /// Wait for the next event that `is_match`, returning it
+    async fn next_event(
+        rx: &mut tokio::sync::broadcast::Receiver<SwitchEvent>,
+        is_match: impl Fn(&SwitchEvent) -> bool,
+    ) -> SwitchEvent {
+        loop {
+            let event = tokio::time::timeout(Duration::from_secs(10), rx.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            if is_match(&event) {
+                return event;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn plan_does_not_switch() {
+        let host = FakeHost::nvidia_laptop();
+        let runner = Arc::new(RecordingCommandRunner::new());
+        let ctrl = host.controller(host.config(GfxMode::Hybrid), runner.clone());
+
+        let (action, steps) = ctrl.plan_gfx_mode(GfxMode::Integrated).await.unwrap();
+        assert!(matches!(action, UserActionRequired::Nothing));
+        assert_eq!(
+            steps,
+            [
+                StagedAction::DisableNvidiaPersistenced,
+                StagedAction::DisableNvidiaPowerd,
+                StagedAction::KillNvidia,
+                StagedAction::SendDetachEvent,
+                StagedAction::UnloadGpuDrivers,
+                StagedAction::UnbindRemoveGpu,
+                StagedAction::WriteModprobeConf,
+                StagedAction::CheckVulkanIcd,
+                StagedAction::DevTreeManaged,
+            ]
+        );
+
+        assert!(runner.calls().is_empty());
+        assert_eq!(ctrl.get_pending_mode().await, GfxMode::None);
+        assert!(matches!(
+            ctrl.get_pending_user_action().await,
+            UserActionRequired::Nothing
+        ));
+        assert!(!host.exists("/etc/modprobe.d/supergfxd.conf"));
+    }
+
+    #[tokio::test]
+    async fn plan_user_action_has_no_steps() {
+        let host = FakeHost::nvidia_laptop();
+        let ctrl = host.controller(
+            host.config(GfxMode::Hybrid),
+            Arc::new(RecordingCommandRunner::new()),
+        );
+
+        let (action, steps) = ctrl.plan_gfx_mode(GfxMode::Vfio).await.unwrap();
+        assert!(matches!(action, UserActionRequired::SwitchToIntegrated));
+        assert!(steps.is_empty());
+
+        let mut config = host.config(GfxMode::Integrated);
+        config.always_reboot = true;
+        let ctrl = host.controller(config, Arc::new(RecordingCommandRunner::new()));
+        let (action, steps) = ctrl.plan_gfx_mode(GfxMode::Hybrid).await.unwrap();
+        assert!(matches!(action, UserActionRequired::Reboot));
+        assert_eq!(steps.first(), Some(&StagedAction::WriteModprobeConf));
+    }
+
+    #[test]
+    fn staged_action_dbus_signature() {
+        use zbus::zvariant::Type;
+        assert_eq!(
+            <(UserActionRequired, Vec<StagedAction>)>::SIGNATURE.to_string(),
+            "(uau)"
+        );
+    }
+
+    #[tokio::test]
+    async fn cancel_without_pending_mode() {
+        let host = FakeHost::nvidia_laptop();
+        let ctrl = host.controller(
+            host.config(GfxMode::Hybrid),
+            Arc::new(RecordingCommandRunner::new()),
+        );
+        assert!(matches!(
+            ctrl.cancel_gfx_mode().await,
+            Err(GfxError::NoPendingMode)
+        ));
+    }
+
+    #[tokio::test]
+    async fn switch_without_steps_leaves_nothing_pending() {
+        let host = FakeHost::nvidia_laptop();
+        let runner = Arc::new(RecordingCommandRunner::new());
+        let mut ctrl = host.controller(host.config(GfxMode::Hybrid), runner.clone());
+
+        for (mode, expected) in [
+            (GfxMode::Hybrid, UserActionRequired::Nothing),
+            (GfxMode::Vfio, UserActionRequired::SwitchToIntegrated),
+        ] {
+            let action = ctrl.set_gfx_mode(mode, None).await.unwrap();
+            assert_eq!(<&str>::from(action), <&str>::from(expected));
+            assert_eq!(ctrl.get_pending_mode().await, GfxMode::None);
+            assert!(matches!(
+                ctrl.get_pending_user_action().await,
+                UserActionRequired::Nothing
+            ));
+            let runtime = ctrl.runtime.lock().await;
+            assert!(runtime.pending_mode.is_none() && runtime.transaction_id.is_none());
+        }
+        assert!(matches!(
+            ctrl.cancel_gfx_mode().await,
+            Err(GfxError::NoPendingMode)
+        ));
+        assert!(runner.calls().is_empty());
+        assert!(!host.exists("/run/supergfxd/state.json"));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn cancel_refused_past_point_of_no_return() {
+        let host = FakeHost::nvidia_laptop();
+        let mut ctrl = host.controller(
+            host.config(GfxMode::Hybrid),
+            Arc::new(RecordingCommandRunner::new()),
+        );
+        let mut rx = ctrl.subscribe_switch_events();
+
+        ctrl.set_gfx_mode(GfxMode::Integrated, None).await.unwrap();
+        // The detach event step takes a second, cancel while it runs
+        loop {
+            let event = tokio::time::timeout(Duration::from_secs(10), rx.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            if event
+                == (SwitchEvent::Progress {
+                    step_index: 3,
+                    total_steps: 9,
+                    action: StagedAction::SendDetachEvent,
+                    status: SwitchStepStatus::Started,
+                })
+            {
+                break;
+            }
+        }
+
+        assert!(matches!(
+            ctrl.cancel_gfx_mode().await,
+            Err(GfxError::SwitchPastPointOfNoReturn(GfxMode::Integrated))
+        ));
+        assert_eq!(ctrl.get_pending_mode().await, GfxMode::Integrated);
+    }
+
+    #[tokio::test]
+    async fn new_switch_refused_while_one_runs() {
+        let host = FakeHost::nvidia_laptop();
+        let runner = Arc::new(RecordingCommandRunner::new());
+        let mut ctrl = host.controller(host.config(GfxMode::Hybrid), runner.clone());
+        let mut rx = ctrl.subscribe_switch_events();
+
+        // Hold the dGPU so the switch stops at its first step, which changes the system
+        let dgpu = ctrl.dgpu_arc_clone();
+        let held = dgpu.lock().await;
+        ctrl.set_gfx_mode(GfxMode::Integrated, None).await.unwrap();
+        next_event(&mut rx, |e| {
+            matches!(
+                e,
+                SwitchEvent::Progress {
+                    step_index: 0,
+                    status: SwitchStepStatus::Started,
+                    ..
+                }
+            )
+        })
+        .await;
+
+        assert!(matches!(
+            ctrl.set_gfx_mode(GfxMode::Hybrid, None).await,
+            Err(GfxError::SwitchPastPointOfNoReturn(GfxMode::Integrated))
+        ));
+        assert_eq!(ctrl.get_pending_mode().await, GfxMode::Integrated);
+
+        drop(held);
+        assert!(matches!(
+            next_event(&mut rx, |e| matches!(e, SwitchEvent::Result { .. })).await,
+            SwitchEvent::Result {
+                outcome: SwitchOutcome::Committed,
+                mode: GfxMode::Integrated,
+                ..
+            }
+        ));
+        assert_eq!(ctrl.config.lock().await.mode, GfxMode::Integrated);
+        assert_eq!(ctrl.get_pending_mode().await, GfxMode::None);
+    }
+
+    #[tokio::test]
+    async fn newer_switch_stops_a_waiting_switch() {
+        let host = FakeHost::nvidia_laptop();
+        host.write("/sys/devices/platform/asus-nb-wmi/egpu_enable", "0");
+        let runner = Arc::new(RecordingCommandRunner::new());
+        let mut config = host.config(GfxMode::Hybrid);
+        config.no_logind = false;
+        config.logout_timeout_s = 0;
+        let mut ctrl = host.controller(config, runner.clone());
+        ctrl.sessions = Arc::new(StuckSessions(vec![BlockingSession {
+            id: "c2".to_string(),
+            user: "luke".to_string(),
+            seat: "seat0".to_string(),
+        }]));
+        let mut rx = ctrl.subscribe_switch_events();
+
+        let action = ctrl.set_gfx_mode(GfxMode::AsusEgpu, None).await.unwrap();
+        assert!(matches!(action, UserActionRequired::Logout));
+        next_event(&mut rx, |e| {
+            matches!(e, SwitchEvent::WaitingForLogout { .. })
+        })
+        .await;
+
+        ctrl.set_gfx_mode(GfxMode::Integrated, None).await.unwrap();
+        let mut results = Vec::new();
+        while results.len() < 2 {
+            if let SwitchEvent::Result { mode, outcome, .. } =
+                next_event(&mut rx, |e| matches!(e, SwitchEvent::Result { .. })).await
+            {
+                results.push((mode, outcome));
+            }
+        }
+        assert!(results.contains(&(GfxMode::Hybrid, SwitchOutcome::Cancelled)));
+        assert!(results.contains(&(GfxMode::Integrated, SwitchOutcome::Committed)));
+
+        // Nothing after the wait for logout of the first switch was done
+        assert!(!runner.calls().iter().any(|c| c.contains("display-manager")));
+        assert_eq!(
+            host.read("/sys/devices/platform/asus-nb-wmi/egpu_enable"),
+            "0"
+        );
+        assert_eq!(ctrl.config.lock().await.mode, GfxMode::Integrated);
+    }
+
+    #[tokio::test]
+    async fn stopped_switch_leaves_the_newer_switch_pending() {
+        let host = FakeHost::nvidia_laptop();
+        host.write("/sys/devices/platform/asus-nb-wmi/egpu_enable", "0");
+        let runner = Arc::new(RecordingCommandRunner::new());
+        let mut config = host.config(GfxMode::Hybrid);
+        config.no_logind = false;
+        config.logout_timeout_s = 0;
+        let mut ctrl = host.controller(config, runner.clone());
+        ctrl.sessions = Arc::new(StuckSessions(vec![BlockingSession {
+            id: "c2".to_string(),
+            user: "luke".to_string(),
+            seat: "seat0".to_string(),
+        }]));
+        let mut rx = ctrl.subscribe_switch_events();
+        let is_result = |e: &SwitchEvent| matches!(e, SwitchEvent::Result { .. });
+
+        ctrl.set_gfx_mode(GfxMode::AsusEgpu, None).await.unwrap();
+        next_event(&mut rx, |e| {
+            matches!(e, SwitchEvent::WaitingForLogout { .. })
+        })
+        .await;
+        ctrl.set_gfx_mode(GfxMode::AsusEgpu, None).await.unwrap();
+
+        // The first switch ends while the second waits, and leaves its state alone
+        next_event(&mut rx, is_result).await;
+        assert_eq!(ctrl.get_pending_mode().await, GfxMode::AsusEgpu);
+        assert!(ctrl.runtime.lock().await.transaction_id.is_some());
+
+        assert_eq!(ctrl.cancel_gfx_mode().await.unwrap(), GfxMode::AsusEgpu);
+        assert!(matches!(
+            next_event(&mut rx, is_result).await,
+            SwitchEvent::Result {
+                outcome: SwitchOutcome::Cancelled,
+                ..
+            }
+        ));
+        assert_eq!(ctrl.get_pending_mode().await, GfxMode::None);
+        assert!(ctrl.runtime.lock().await.transaction_id.is_none());
+        assert!(!runner.calls().iter().any(|c| c.contains("display-manager")));
+    }
+
+    #[test]
+    fn waiting_steps_do_not_change_system() {
+        assert!(!StagedAction::WaitLogout.changes_system());
+        assert!(!StagedAction::NoLogind.changes_system());
+        assert!(StagedAction::StopDisplayManager.changes_system());
+        assert!(StagedAction::DisableNvidiaPersistenced.changes_system());
+    }
+}
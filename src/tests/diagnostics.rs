@@ -0,0 +1,95 @@
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{
+        command::RecordingCommandRunner,
+        config::GfxConfigDbus,
+        diagnostics::{cmdline_value, gpu_modules, Diagnostics},
+        pci_device::GfxMode,
+        tests::fake_host::FakeHost,
+    };
+
+    #[test]
+    fn cmdline_values() {
+        let cmdline = "BOOT_IMAGE=/vmlinuz supergfxd.mode=Integrated nvidia-drm.modeset=1 quiet";
+        assert_eq!(cmdline_value(cmdline, "supergfxd.mode"), "Integrated");
+        assert_eq!(cmdline_value(cmdline, "nvidia-drm.modeset"), "1");
+        assert_eq!(cmdline_value(cmdline, "quiet"), "");
+        assert_eq!(cmdline_value(cmdline, "nvidia-drm"), "");
+    }
+
+    #[test]
+    fn only_gpu_modules_are_listed() {
+        let proc_modules = "nvidia_drm 102400 2 - Live 0x0000000000000000
+nvidia 56762368 1 nvidia_drm, Live 0x0000000000000000
+snd_hda_intel 61440 2 - Live 0x0000000000000000
+amdgpu 13361152 17 - Live 0x0000000000000000
+vfio_pci 16384 0 - Live 0x0000000000000000
+";
+        assert_eq!(
+            gpu_modules(proc_modules),
+            ["nvidia_drm", "nvidia", "amdgpu", "vfio_pci"]
+        );
+    }
+
+    #[tokio::test]
+    async fn collects_system_state() {
+        let host = FakeHost::nvidia_laptop();
+        host.write(
+            "/proc/cmdline",
+            "BOOT_IMAGE=/vmlinuz supergfxd.mode=Hybrid nvidia-drm.modeset=1\n",
+        );
+        host.write("/sys/devices/platform/asus-nb-wmi/gpu_mux_mode", "1\n");
+        host.write("/etc/modprobe.d/supergfxd.conf", "blacklist nouveau\n");
+        host.write("/usr/share/vulkan/icd.d/nvidia_icd.json_inactive", "{}");
+        host.write(
+            "/proc/modules",
+            "nvidia 56762368 1 - Live 0x0000000000000000\n",
+        );
+        host.mkdir("/sys/bus/pci/drivers/nvidia");
+        std::os::unix::fs::symlink(
+            host.path("/sys/bus/pci/drivers/nvidia"),
+            host.path("/sys/bus/pci/devices/0000:01:00.0/driver"),
+        )
+        .unwrap();
+
+        let config = host.config(GfxMode::Hybrid);
+        let expected_config = GfxConfigDbus::from(&config);
+        let dgpu = host.dgpu(Arc::new(RecordingCommandRunner::new()));
+        let diag = Diagnostics::collect(&dgpu, expected_config.clone());
+
+        assert_eq!(diag.vendor, "Nvidia");
+        assert_eq!(diag.devices.len(), 1);
+        let dev = &diag.devices[0];
+        assert_eq!(dev.name, "0000:01:00.0");
+        assert_eq!(dev.pci_id, "10DE:25A2");
+        assert!(dev.is_dgpu);
+        assert_eq!(dev.driver, "nvidia");
+        assert_eq!(dev.runtime_status, "suspended");
+        assert!(dev.hotplug_path.ends_with("/sys/bus/pci/slots/1/power"));
+
+        assert_eq!(diag.asus_dgpu_disable, "0");
+        assert_eq!(diag.asus_egpu_enable, "");
+        assert_eq!(diag.asus_gpu_mux_mode, "1");
+        assert_eq!(diag.cmdline_mode, "Hybrid");
+        assert_eq!(diag.cmdline_nvidia_modeset, "1");
+        assert_eq!(diag.modprobe_conf, "blacklist nouveau\n");
+        assert_eq!(diag.vulkan_icd, "inactive");
+        assert_eq!(diag.modules, ["nvidia"]);
+        assert_eq!(diag.config, expected_config);
+
+        let report = diag.to_string();
+        assert!(report.contains("0000:01:00.0 [10DE:25A2] Nvidia (dGPU)"));
+        assert!(report.contains("Kernel supergfxd.mode: Hybrid"));
+        assert!(report.contains("ASUS egpu_enable: <not set>"));
+        assert!(report.contains("  logout_timeout_s: 10"));
+        assert!(report.ends_with("\n  blacklist nouveau"));
+
+        let ctrl = host.controller(
+            host.config(GfxMode::Hybrid),
+            Arc::new(RecordingCommandRunner::new()),
+        );
+        assert_eq!(ctrl.get_diagnostics().await, diag);
+    }
+}
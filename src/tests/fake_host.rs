@@ -0,0 +1,178 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use futures_util::{future::BoxFuture, lock::Mutex, FutureExt};
+
+use crate::{
+    actions::{BlockingSession, SessionSource, SessionWatch, SwitchContext},
+    command::{CommandRunner, RecordingCommandRunner},
+    config::{ConfigStore, GfxSettings},
+    controller::CtrlGraphics,
+    error::GfxError,
+    host_paths::HostPaths,
+    pci_device::{DiscreetGpu, GfxMode, HotplugType},
+    polkit::MockAuthority,
+};
+
+static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// A temporary root directory populated with fake sysfs/procfs files. Removed on drop.
+pub(crate) struct FakeHost {
+    root: PathBuf,
+}
+
+impl FakeHost {
+    pub fn new() -> Self {
+        let root = std::env::temp_dir().join(format!(
+            "supergfxd-test-{}-{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir_all(&root).unwrap();
+        let host = Self { root };
+        host.write("/sys/bus/pci/rescan", "");
+        host.mkdir("/sys/bus/pci/slots");
+        host.mkdir("/etc/modprobe.d");
+        host
+    }
+
+    pub fn paths(&self) -> HostPaths {
+        HostPaths::new(&self.root)
+    }
+
+    pub fn path(&self, path: &str) -> PathBuf {
+        self.paths().resolve(path)
+    }
+
+    pub fn mkdir(&self, path: &str) {
+        fs::create_dir_all(self.path(path)).unwrap();
+    }
+
+    pub fn write(&self, path: &str, content: &str) {
+        let path = self.path(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+
+    pub fn read(&self, path: &str) -> String {
+        fs::read_to_string(self.path(path)).unwrap()
+    }
+
+    pub fn exists(&self, path: &str) -> bool {
+        self.path(path).exists()
+    }
+
+    /// Add a PCI function under `/sys/bus/pci/devices/<name>`
+    pub fn add_pci_device(&self, name: &str, vendor: u16, device: u16, class: u32) {
+        let dev = format!("/sys/bus/pci/devices/{name}");
+        self.write(&format!("{dev}/vendor"), &format!("0x{vendor:04x}\n"));
+        self.write(&format!("{dev}/device"), &format!("0x{device:04x}\n"));
+        self.write(&format!("{dev}/class"), &format!("0x{class:06x}\n"));
+        self.write(&format!("{dev}/power/control"), "auto\n");
+        self.write(&format!("{dev}/power/runtime_status"), "suspended\n");
+        self.write(&format!("{dev}/remove"), "");
+    }
+
+    /// Add a hotplug slot whose address matches the PCI device `name`
+    pub fn add_hotplug_slot(&self, slot: &str, name: &str) {
+        let address = name.rsplit_once('.').map(|(a, _)| a).unwrap_or(name);
+        self.write(&format!("/sys/bus/pci/slots/{slot}/address"), address);
+        self.write(&format!("/sys/bus/pci/slots/{slot}/power"), "1");
+    }
+
+    /// Discover the devices in this tree, all commands go to `runner`
+    pub fn dgpu(&self, runner: Arc<RecordingCommandRunner>) -> DiscreetGpu {
+        DiscreetGpu::new(self.paths(), runner as Arc<dyn CommandRunner>).unwrap()
+    }
+
+    /// A config stored in this tree with logind disabled so switches never wait
+    pub fn config(&self, mode: GfxMode) -> GfxSettings {
+        GfxSettings {
+            mode,
+            vfio_enable: true,
+            vfio_save: false,
+            always_reboot: false,
+            no_logind: true,
+            logout_timeout_s: 10,
+            hotplug_type: HotplugType::None,
+        }
+    }
+
+    /// The store of the config file in this tree
+    pub fn store(&self) -> ConfigStore {
+        ConfigStore::new(
+            self.paths().config().to_string_lossy().into(),
+            self.paths().config_dropin_dirs(),
+        )
+    }
+
+    /// A context for performing steps directly, with the config from [`Self::config`]
+    pub fn context(&self, mode: GfxMode) -> SwitchContext {
+        SwitchContext::new(self.config(mode), tokio::sync::broadcast::channel(16).0)
+    }
+
+    /// A controller for this tree, all commands go to `runner`
+    pub fn controller(
+        &self,
+        config: GfxSettings,
+        runner: Arc<RecordingCommandRunner>,
+    ) -> CtrlGraphics {
+        CtrlGraphics::new(
+            Arc::new(Mutex::new(config)),
+            self.store(),
+            self.paths(),
+            runner as Arc<dyn CommandRunner>,
+            Arc::new(MockAuthority::allow_all()),
+        )
+        .unwrap()
+    }
+
+    /// A typical Nvidia laptop dGPU with a hotplug slot and ASUS dgpu_disable
+    pub fn nvidia_laptop() -> Self {
+        let host = Self::new();
+        host.add_pci_device("0000:01:00.0", 0x10de, 0x25a2, 0x030000);
+        host.add_hotplug_slot("1", "0000:01:00.0");
+        host.write("/sys/devices/platform/asus-nb-wmi/dgpu_disable", "0");
+        host.write(
+            "/proc/cmdline",
+            "BOOT_IMAGE=/vmlinuz root=/dev/sda1 quiet\n",
+        );
+        host
+    }
+}
+
+impl Drop for FakeHost {
+    fn drop(&mut self) {
+        if self.root.starts_with(std::env::temp_dir()) && self.root != Path::new("/") {
+            fs::remove_dir_all(&self.root).ok();
+        }
+    }
+}
+
+/// Sessions that never log out
+#[derive(Clone)]
+pub(crate) struct StuckSessions(pub Vec<BlockingSession>);
+
+impl SessionWatch for StuckSessions {
+    fn blocking_sessions(&mut self) -> BoxFuture<'_, Result<Vec<BlockingSession>, GfxError>> {
+        let sessions = self.0.clone();
+        async move { Ok(sessions) }.boxed()
+    }
+
+    fn changed(&mut self) -> BoxFuture<'_, ()> {
+        std::future::pending().boxed()
+    }
+}
+
+impl SessionSource for StuckSessions {
+    fn watch(&self) -> BoxFuture<'_, Result<Box<dyn SessionWatch>, GfxError>> {
+        let watch = self.clone();
+        async move { Ok(Box::new(watch) as Box<dyn SessionWatch>) }.boxed()
+    }
+}
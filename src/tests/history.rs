@@ -0,0 +1,133 @@
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use crate::{
+        actions::{StagedAction, SwitchEvent, SwitchOutcome, UserActionRequired},
+        command::{CommandOutput, RecordingCommandRunner},
+        history::{format_timestamp, HistoryEntry, SwitchHistory, HISTORY_PATH, UNKNOWN_UID},
+        pci_device::GfxMode,
+        tests::fake_host::FakeHost,
+    };
+
+    fn entry(from: GfxMode, to: GfxMode) -> HistoryEntry {
+        HistoryEntry::new(from, to, Some(1000), UserActionRequired::Nothing)
+    }
+
+    #[test]
+    fn newest_first_with_limit() {
+        let host = FakeHost::new();
+        let history = SwitchHistory::new(host.path(HISTORY_PATH), 10);
+        assert!(history.entries(0).unwrap().is_empty());
+
+        history
+            .record(&entry(GfxMode::Hybrid, GfxMode::Integrated))
+            .unwrap();
+        history
+            .record(&entry(GfxMode::Integrated, GfxMode::Vfio))
+            .unwrap();
+        history
+            .record(&entry(GfxMode::Vfio, GfxMode::Hybrid))
+            .unwrap();
+
+        let all = history.entries(0).unwrap();
+        assert_eq!(
+            all.iter().map(|e| e.to).collect::<Vec<_>>(),
+            [GfxMode::Hybrid, GfxMode::Vfio, GfxMode::Integrated]
+        );
+        let last = history.entries(1).unwrap();
+        assert_eq!(last.len(), 1);
+        assert_eq!(last[0].from, GfxMode::Vfio);
+        assert_eq!(last[0].uid, 1000);
+    }
+
+    #[test]
+    fn oldest_entries_are_dropped() {
+        let host = FakeHost::new();
+        let history = SwitchHistory::new(host.path(HISTORY_PATH), 3);
+        let modes = [
+            GfxMode::Hybrid,
+            GfxMode::Integrated,
+            GfxMode::Vfio,
+            GfxMode::AsusEgpu,
+            GfxMode::AsusMuxDgpu,
+        ];
+        for to in modes {
+            history.record(&entry(GfxMode::None, to)).unwrap();
+        }
+        assert_eq!(
+            history
+                .entries(0)
+                .unwrap()
+                .iter()
+                .map(|e| e.to)
+                .collect::<Vec<_>>(),
+            [GfxMode::AsusMuxDgpu, GfxMode::AsusEgpu, GfxMode::Vfio]
+        );
+        assert_eq!(host.read(HISTORY_PATH).lines().count(), 3);
+        assert!(!host.exists(&format!("{HISTORY_PATH}.tmp")));
+    }
+
+    #[test]
+    fn bad_lines_are_skipped() {
+        let host = FakeHost::new();
+        let history = SwitchHistory::new(host.path(HISTORY_PATH), 10);
+        history
+            .record(&entry(GfxMode::Hybrid, GfxMode::Integrated))
+            .unwrap();
+        let mut content = host.read(HISTORY_PATH);
+        content.push_str("{\"truncated\n");
+        host.write(HISTORY_PATH, &content);
+
+        let all = history.entries(0).unwrap();
+        assert_eq!(all.len(), 1);
+        assert_eq!(all[0].to, GfxMode::Integrated);
+    }
+
+    #[test]
+    fn timestamps() {
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00:00");
+        assert_eq!(format_timestamp(951_827_696), "2000-02-29 12:34:56");
+        assert_eq!(format_timestamp(1_792_195_200), "2026-10-17 00:00:00");
+    }
+
+    #[tokio::test]
+    async fn switch_is_recorded() {
+        let host = FakeHost::nvidia_laptop();
+        let runner = Arc::new(RecordingCommandRunner::new());
+        runner.reply(
+            "rmmod nvidia_uvm",
+            CommandOutput::failed(1, "rmmod: ERROR: Module nvidia_uvm is in use\n"),
+        );
+        let mut ctrl = host.controller(host.config(GfxMode::Hybrid), runner);
+
+        let mut rx = ctrl.subscribe_switch_events();
+        ctrl.set_gfx_mode(GfxMode::Integrated, None).await.unwrap();
+        loop {
+            let event = tokio::time::timeout(Duration::from_secs(10), rx.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            if matches!(event, SwitchEvent::Result { .. }) {
+                break;
+            }
+        }
+
+        let history = ctrl.get_history(0).unwrap();
+        assert_eq!(history.len(), 1);
+        let entry = &history[0];
+        assert_eq!(
+            (entry.from, entry.to),
+            (GfxMode::Hybrid, GfxMode::Integrated)
+        );
+        assert_eq!(entry.uid, UNKNOWN_UID);
+        assert_eq!(entry.outcome, SwitchOutcome::RolledBack);
+        let failed = entry.steps.last().unwrap();
+        assert_eq!(failed.action, StagedAction::UnloadGpuDrivers);
+        assert!(!failed.success);
+        assert!(failed.error.contains("nvidia_uvm is in use"));
+        assert!(entry.steps[..entry.steps.len() - 1]
+            .iter()
+            .all(|s| s.success && s.error.is_empty()));
+    }
+}
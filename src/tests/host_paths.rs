@@ -0,0 +1,103 @@
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{
+        actions::StagedAction,
+        command::RecordingCommandRunner,
+        get_kernel_cmdline_mode, get_kernel_cmdline_nvidia_modeset,
+        host_paths::HostPaths,
+        pci_device::{GfxMode, GfxPower, GfxVendor},
+        special_asus::{asus_dgpu_disable_exists, asus_egpu_enable_exists},
+        tests::fake_host::FakeHost,
+    };
+
+    #[test]
+    fn resolve_against_root() {
+        let paths = HostPaths::default();
+        assert!(paths.is_system_root());
+        assert_eq!(paths.kernel_cmdline().to_str(), Some("/proc/cmdline"));
+
+        let paths = HostPaths::new("/tmp/fake");
+        assert!(!paths.is_system_root());
+        assert_eq!(
+            paths.modprobe_conf().to_str(),
+            Some("/tmp/fake/etc/modprobe.d/supergfxd.conf")
+        );
+        assert_eq!(
+            paths.asus_gpu_mux().to_str(),
+            Some("/tmp/fake/sys/devices/platform/asus-nb-wmi/gpu_mux_mode")
+        );
+        assert_eq!(
+            paths.config().to_str(),
+            Some("/tmp/fake/etc/supergfxd.conf")
+        );
+        assert_eq!(
+            paths.history().to_str(),
+            Some("/tmp/fake/var/lib/supergfxd/history")
+        );
+    }
+
+    #[test]
+    fn discover_fake_dgpu() {
+        let host = FakeHost::nvidia_laptop();
+        let dgpu = host.dgpu(Arc::new(RecordingCommandRunner::new()));
+
+        assert_eq!(dgpu.vendor(), GfxVendor::Nvidia);
+        assert_eq!(dgpu.devices().len(), 1);
+        assert_eq!(dgpu.devices()[0].pci_id(), "10DE:25A2");
+        assert_eq!(dgpu.get_runtime_status().unwrap(), GfxPower::Suspended);
+        assert!(asus_dgpu_disable_exists(&host.paths()));
+        assert!(!asus_egpu_enable_exists(&host.paths()));
+    }
+
+    #[test]
+    fn kernel_cmdline_from_fake_root() {
+        let host = FakeHost::nvidia_laptop();
+        assert_eq!(get_kernel_cmdline_mode(&host.paths()).unwrap(), None);
+
+        host.write(
+            "/proc/cmdline",
+            "quiet supergfxd.mode=Integrated nvidia-drm.modeset=0\n",
+        );
+        assert_eq!(
+            get_kernel_cmdline_mode(&host.paths()).unwrap(),
+            Some(GfxMode::Integrated)
+        );
+        assert_eq!(
+            get_kernel_cmdline_nvidia_modeset(&host.paths()).unwrap(),
+            Some(false)
+        );
+    }
+
+    #[tokio::test]
+    async fn actions_write_to_fake_root() {
+        let host = FakeHost::nvidia_laptop();
+        host.write("/usr/share/vulkan/icd.d/nvidia_icd.json", "{}");
+        let mut dgpu = host.dgpu(Arc::new(RecordingCommandRunner::new()));
+        let ctx = host.context(GfxMode::Hybrid);
+
+        for action in [
+            StagedAction::WriteModprobeConf,
+            StagedAction::CheckVulkanIcd,
+            StagedAction::HotplugUnplug,
+            StagedAction::AsusDgpuDisable,
+        ] {
+            action
+                .perform(GfxMode::Integrated, &mut dgpu, &ctx)
+                .await
+                .unwrap();
+        }
+
+        assert!(host
+            .read("/etc/modprobe.d/supergfxd.conf")
+            .contains("blacklist nvidia_drm"));
+        assert!(!host.exists("/usr/share/vulkan/icd.d/nvidia_icd.json"));
+        assert!(host.exists("/usr/share/vulkan/icd.d/nvidia_icd.json_inactive"));
+        assert_eq!(host.read("/sys/bus/pci/slots/1/power"), "0");
+        assert_eq!(
+            host.read("/sys/devices/platform/asus-nb-wmi/dgpu_disable"),
+            "1"
+        );
+    }
+}
@@ -1 +1,17 @@
 pub(crate) mod actions;
+mod command;
+mod completions;
+mod config;
+mod config_watch;
+mod controller;
+mod diagnostics;
+pub(crate) mod fake_host;
+mod history;
+mod host_paths;
+mod pci_device;
+mod polkit;
+mod power_watch;
+mod properties;
+mod runtime_state;
+mod transaction;
+mod wait_logout;
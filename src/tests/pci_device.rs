@@ -0,0 +1,103 @@
+#[cfg(test)]
+mod tests {
+    use std::{str::FromStr, sync::Arc};
+
+    use crate::{
+        command::RecordingCommandRunner, error::GfxError, get_kernel_cmdline_mode,
+        pci_device::GfxMode, tests::fake_host::FakeHost,
+    };
+
+    #[test]
+    fn mode_names_in_any_case() {
+        for mode in GfxMode::ALL {
+            let name = mode.to_string();
+            assert_eq!(GfxMode::from_str(&name).unwrap(), mode);
+            assert_eq!(GfxMode::from_str(&name.to_lowercase()).unwrap(), mode);
+            assert_eq!(GfxMode::from_str(&name.to_uppercase()).unwrap(), mode);
+        }
+        assert_eq!(
+            GfxMode::from_str(" nvidiaNoModeset\n").unwrap(),
+            GfxMode::NvidiaNoModeset
+        );
+    }
+
+    #[test]
+    fn mode_aliases() {
+        for (alias, mode) in [
+            ("igpu", GfxMode::Integrated),
+            ("hybrid", GfxMode::Hybrid),
+            ("compute", GfxMode::Hybrid),
+            ("dgpu", GfxMode::AsusMuxDgpu),
+            ("vfio", GfxMode::Vfio),
+            ("egpu", GfxMode::AsusEgpu),
+            ("iGPU", GfxMode::Integrated),
+            ("EGPU", GfxMode::AsusEgpu),
+        ] {
+            assert_eq!(GfxMode::from_str(alias).unwrap(), mode, "{alias}");
+        }
+    }
+
+    #[test]
+    fn unknown_mode_lists_valid_modes() {
+        let err = GfxMode::from_str("nvidia").unwrap_err();
+        assert!(matches!(&err, GfxError::ParseMode(mode) if mode == "nvidia"));
+        let text = err.to_string();
+        assert!(text.starts_with("Unknown mode nvidia, valid modes are: Hybrid, Integrated, NvidiaNoModeset, Vfio, AsusEgpu, AsusMuxDgpu"));
+        assert!(text.contains("igpu, hybrid, dgpu, vfio, egpu and compute"));
+
+        // `None` is never a valid choice
+        assert!(GfxMode::from_str("none").is_err());
+        assert!(GfxMode::from_str("Unknown").is_err());
+        assert!(GfxMode::from_str("").is_err());
+    }
+
+    #[test]
+    fn cmdline_mode_in_any_case() {
+        let host = FakeHost::nvidia_laptop();
+        for (cmdline, mode) in [
+            ("quiet supergfxd.mode=integrated\n", GfxMode::Integrated),
+            ("supergfxd.mode=VFIO quiet\n", GfxMode::Vfio),
+            ("quiet supergfxd.mode=igpu\n", GfxMode::Integrated),
+            ("quiet supergfxd.mode=compute splash\n", GfxMode::Hybrid),
+        ] {
+            host.write("/proc/cmdline", cmdline);
+            assert_eq!(
+                get_kernel_cmdline_mode(&host.paths()).unwrap(),
+                Some(mode),
+                "{cmdline}"
+            );
+        }
+
+        host.write("/proc/cmdline", "quiet supergfxd.mode=nvidia\n");
+        assert!(matches!(
+            get_kernel_cmdline_mode(&host.paths()),
+            Err(GfxError::ParseMode(mode)) if mode == "nvidia"
+        ));
+    }
+
+    #[tokio::test]
+    async fn reload_uses_lowercase_cmdline_mode() {
+        let host = FakeHost::nvidia_laptop();
+        host.write("/proc/cmdline", "quiet supergfxd.mode=integrated\n");
+        let mut ctrl = host.controller(
+            host.config(GfxMode::Hybrid),
+            Arc::new(RecordingCommandRunner::new()),
+        );
+
+        ctrl.reload().await.unwrap();
+        assert_eq!(ctrl.config.lock().await.mode, GfxMode::Integrated);
+    }
+
+    #[tokio::test]
+    async fn reload_ignores_bad_cmdline_mode() {
+        let host = FakeHost::nvidia_laptop();
+        host.write("/proc/cmdline", "quiet supergfxd.mode=nvidia\n");
+        let mut ctrl = host.controller(
+            host.config(GfxMode::Hybrid),
+            Arc::new(RecordingCommandRunner::new()),
+        );
+
+        ctrl.reload().await.unwrap();
+        assert_eq!(ctrl.config.lock().await.mode, GfxMode::Hybrid);
+    }
+}
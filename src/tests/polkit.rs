@@ -0,0 +1,95 @@
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use futures_util::lock::Mutex;
+
+    use crate::{
+        command::RecordingCommandRunner,
+        controller::CtrlGraphics,
+        error::GfxError,
+        pci_device::GfxMode,
+        polkit::{
+            actions_for_config, actions_for_mode, Caller, MockAuthority, ACTION_ENABLE_VFIO,
+            ACTION_SET_CONFIG, ACTION_SET_MODE,
+        },
+        tests::fake_host::FakeHost,
+    };
+
+    fn caller() -> Caller {
+        Caller {
+            bus_name: ":1.42".to_string(),
+            uid: Some(1000),
+        }
+    }
+
+    fn controller(host: &FakeHost, authority: Arc<MockAuthority>) -> CtrlGraphics {
+        CtrlGraphics::new(
+            Arc::new(Mutex::new(host.config(GfxMode::Hybrid))),
+            host.store(),
+            host.paths(),
+            Arc::new(RecordingCommandRunner::new()),
+            authority,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn distinct_actions() {
+        assert_eq!(actions_for_mode(GfxMode::Integrated), [ACTION_SET_MODE]);
+        assert_eq!(
+            actions_for_mode(GfxMode::Vfio),
+            [ACTION_SET_MODE, ACTION_ENABLE_VFIO]
+        );
+        assert_eq!(actions_for_config(false), [ACTION_SET_CONFIG]);
+        assert_eq!(
+            actions_for_config(true),
+            [ACTION_SET_CONFIG, ACTION_ENABLE_VFIO]
+        );
+    }
+
+    #[tokio::test]
+    async fn denied_without_polkit_grant() {
+        let host = FakeHost::nvidia_laptop();
+        let authority = Arc::new(MockAuthority::new());
+        authority.allow(ACTION_SET_MODE);
+        let ctrl = controller(&host, authority.clone());
+
+        ctrl.authorize(&caller(), &actions_for_mode(GfxMode::Integrated))
+            .await
+            .unwrap();
+        let err = ctrl
+            .authorize(&caller(), &actions_for_mode(GfxMode::Vfio))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, GfxError::NotAuthorized(id) if id == ACTION_ENABLE_VFIO));
+        let err = ctrl
+            .authorize(&caller(), &actions_for_config(false))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, GfxError::NotAuthorized(id) if id == ACTION_SET_CONFIG));
+
+        assert_eq!(
+            authority.checks(),
+            [
+                ACTION_SET_MODE,
+                ACTION_SET_MODE,
+                ACTION_ENABLE_VFIO,
+                ACTION_SET_CONFIG
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn stops_at_first_denial() {
+        let host = FakeHost::nvidia_laptop();
+        let authority = Arc::new(MockAuthority::new());
+        let ctrl = controller(&host, authority.clone());
+
+        assert!(ctrl
+            .authorize(&caller(), &actions_for_config(true))
+            .await
+            .is_err());
+        assert_eq!(authority.checks(), [ACTION_SET_CONFIG]);
+    }
+}
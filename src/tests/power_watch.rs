@@ -0,0 +1,62 @@
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use futures_util::lock::Mutex;
+
+    use crate::{
+        command::RecordingCommandRunner,
+        pci_device::GfxPower,
+        power_watch::{next_poll_period, watch_power_status, MAX_POLL_PERIOD, MIN_POLL_PERIOD},
+        tests::fake_host::FakeHost,
+    };
+
+    #[test]
+    fn poll_period_backs_off() {
+        let mut period = MIN_POLL_PERIOD;
+        let mut periods = Vec::new();
+        for _ in 0..6 {
+            period = next_poll_period(period, false);
+            periods.push(period.as_secs());
+        }
+        assert_eq!(periods, [2, 4, 8, 16, 16, 16]);
+        assert_eq!(period, MAX_POLL_PERIOD);
+        assert_eq!(next_poll_period(period, true), MIN_POLL_PERIOD);
+    }
+
+    #[tokio::test]
+    async fn status_change_is_sent_on_poll() {
+        let host = FakeHost::nvidia_laptop();
+        let dgpu = Arc::new(Mutex::new(
+            host.dgpu(Arc::new(RecordingCommandRunner::new())),
+        ));
+        let mut status = watch_power_status(dgpu);
+
+        let first = tokio::time::timeout(Duration::from_secs(2), status.recv())
+            .await
+            .unwrap();
+        assert_eq!(first, Some(GfxPower::Suspended));
+
+        // Let the poll period back off, the change is still seen within MAX_POLL_PERIOD
+        tokio::time::sleep(Duration::from_millis(2500)).await;
+        host.write(
+            "/sys/bus/pci/devices/0000:01:00.0/power/runtime_status",
+            "active\n",
+        );
+        let next = tokio::time::timeout(MAX_POLL_PERIOD * 2, status.recv())
+            .await
+            .unwrap();
+        assert_eq!(next, Some(GfxPower::Active));
+
+        // No transition, nothing sent
+        host.write(
+            "/sys/bus/pci/devices/0000:01:00.0/power/runtime_status",
+            "active\n",
+        );
+        assert!(
+            tokio::time::timeout(Duration::from_millis(300), status.recv())
+                .await
+                .is_err()
+        );
+    }
+}
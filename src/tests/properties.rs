@@ -0,0 +1,83 @@
+#[cfg(test)]
+mod tests {
+    use zbus::zvariant::{serialized::Context, to_bytes, OwnedValue, Type, Value, LE};
+
+    use crate::{
+        actions::UserActionRequired,
+        pci_device::{GfxMode, GfxPower, HotplugType},
+    };
+
+    /// Property values must be the same `u` as the enum sent as a method arg
+    fn same_as_serialized<T>(all: &[T])
+    where
+        T: Copy + PartialEq + std::fmt::Debug + serde::Serialize + Type,
+        T: Into<Value<'static>> + TryFrom<OwnedValue>,
+    {
+        for (i, v) in all.iter().enumerate() {
+            let ctxt = Context::new_dbus(LE, 0);
+            let encoded = to_bytes(ctxt, v).unwrap();
+            let (index, _): (u32, _) = encoded.deserialize().unwrap();
+            assert_eq!(index, i as u32, "{v:?}");
+
+            let value: Value<'static> = (*v).into();
+            assert_eq!(value, Value::U32(i as u32), "{v:?}");
+            let owned = OwnedValue::try_from(value).unwrap();
+            assert!(T::try_from(owned).ok() == Some(*v), "{v:?}");
+        }
+    }
+
+    #[test]
+    fn property_values_match_method_args() {
+        same_as_serialized(&[
+            GfxMode::Hybrid,
+            GfxMode::Integrated,
+            GfxMode::NvidiaNoModeset,
+            GfxMode::Vfio,
+            GfxMode::AsusEgpu,
+            GfxMode::AsusMuxDgpu,
+            GfxMode::None,
+        ]);
+        same_as_serialized(&[
+            GfxPower::Active,
+            GfxPower::Suspended,
+            GfxPower::Off,
+            GfxPower::AsusDisabled,
+            GfxPower::AsusMuxDiscreet,
+            GfxPower::Unknown,
+        ]);
+        same_as_serialized(&[HotplugType::Std, HotplugType::Asus, HotplugType::None]);
+    }
+
+    #[test]
+    fn user_action_values() {
+        for (i, action) in [
+            UserActionRequired::Logout,
+            UserActionRequired::Reboot,
+            UserActionRequired::SwitchToIntegrated,
+            UserActionRequired::AsusEgpuDisable,
+            UserActionRequired::Nothing,
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            assert_eq!(Value::from(action), Value::U32(i as u32));
+        }
+    }
+
+    /// `supergfxctl --json` prints the serde names
+    #[test]
+    fn json_names() {
+        assert_eq!(
+            serde_json::to_string(&[GfxMode::Hybrid, GfxMode::AsusMuxDgpu, GfxMode::None]).unwrap(),
+            r#"["Hybrid","AsusMuxDgpu","None"]"#
+        );
+        assert_eq!(
+            serde_json::to_string(&GfxPower::AsusDisabled).unwrap(),
+            r#""AsusDisabled""#
+        );
+        assert_eq!(
+            serde_json::to_string(&UserActionRequired::SwitchToIntegrated).unwrap(),
+            r#""SwitchToIntegrated""#
+        );
+    }
+}
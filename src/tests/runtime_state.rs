@@ -0,0 +1,135 @@
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use crate::{
+        actions::{SwitchEvent, SwitchOutcome, UserActionRequired},
+        command::RecordingCommandRunner,
+        pci_device::GfxMode,
+        runtime_state::{GfxRuntimeState, RUNTIME_STATE_PATH},
+        tests::fake_host::FakeHost,
+    };
+
+    fn saved_state(host: &FakeHost) -> GfxRuntimeState {
+        serde_json::from_str(&host.read(RUNTIME_STATE_PATH)).unwrap()
+    }
+
+    #[test]
+    fn saved_state_is_loaded_back() {
+        let host = FakeHost::nvidia_laptop();
+        let path = host.path(RUNTIME_STATE_PATH);
+        assert!(GfxRuntimeState::load(&path).pending_mode.is_none());
+
+        let state = GfxRuntimeState {
+            tmp_mode: Some(GfxMode::Vfio),
+            pending_mode: Some(GfxMode::Integrated),
+            pending_action: Some(UserActionRequired::Logout),
+            transaction_id: Some(42),
+        };
+        state.save(&path);
+        let loaded = GfxRuntimeState::load(&path);
+        assert_eq!(loaded.tmp_mode, Some(GfxMode::Vfio));
+        assert_eq!(loaded.pending_mode, Some(GfxMode::Integrated));
+        assert!(matches!(
+            loaded.pending_action,
+            Some(UserActionRequired::Logout)
+        ));
+        assert_eq!(loaded.transaction_id, Some(42));
+
+        host.write(RUNTIME_STATE_PATH, "{\"tmp_mode\":");
+        assert!(GfxRuntimeState::load(&path).tmp_mode.is_none());
+    }
+
+    #[tokio::test]
+    async fn state_is_saved_while_a_switch_runs() {
+        let host = FakeHost::nvidia_laptop();
+        let mut ctrl = host.controller(
+            host.config(GfxMode::Hybrid),
+            Arc::new(RecordingCommandRunner::new()),
+        );
+        let mut rx = ctrl.subscribe_switch_events();
+
+        ctrl.set_gfx_mode(GfxMode::Integrated, None).await.unwrap();
+        // The switch task hasn't run yet on this single threaded runtime
+        let state = saved_state(&host);
+        assert_eq!(state.pending_mode, Some(GfxMode::Integrated));
+        assert!(state.transaction_id.is_some());
+
+        loop {
+            let event = tokio::time::timeout(Duration::from_secs(10), rx.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            if matches!(event, SwitchEvent::Result { .. }) {
+                break;
+            }
+        }
+        let state = saved_state(&host);
+        assert_eq!(state.pending_mode, None);
+        assert_eq!(state.transaction_id, None);
+        // The settings file only has the mode once the switch is committed
+        assert!(host.read("/etc/supergfxd.conf").contains("Integrated"));
+    }
+
+    #[tokio::test]
+    async fn interrupted_switch_is_recovered() {
+        let host = FakeHost::nvidia_laptop();
+        GfxRuntimeState {
+            pending_mode: Some(GfxMode::Integrated),
+            pending_action: Some(UserActionRequired::Nothing),
+            transaction_id: Some(7),
+            ..Default::default()
+        }
+        .save(&host.path(RUNTIME_STATE_PATH));
+        let ctrl = host.controller(
+            host.config(GfxMode::Hybrid),
+            Arc::new(RecordingCommandRunner::new()),
+        );
+        assert_eq!(ctrl.get_pending_mode().await, GfxMode::Integrated);
+
+        ctrl.recover_interrupted_switch().await;
+        assert_eq!(ctrl.get_pending_mode().await, GfxMode::None);
+        assert_eq!(ctrl.get_switch_outcome().await, SwitchOutcome::Interrupted);
+        assert_eq!(ctrl.config.lock().await.mode, GfxMode::Hybrid);
+        let state = saved_state(&host);
+        assert_eq!(state.pending_mode, None);
+        assert_eq!(state.transaction_id, None);
+
+        let history = ctrl.get_history(0).unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].from, GfxMode::Hybrid);
+        assert_eq!(history[0].to, GfxMode::Integrated);
+        assert_eq!(history[0].outcome, SwitchOutcome::Interrupted);
+    }
+
+    #[tokio::test]
+    async fn stale_pending_state_is_cleared_on_restart() {
+        let host = FakeHost::nvidia_laptop();
+        // A switch that only needs a reboot has no steps in flight
+        GfxRuntimeState {
+            pending_mode: Some(GfxMode::AsusMuxDgpu),
+            pending_action: Some(UserActionRequired::Reboot),
+            ..Default::default()
+        }
+        .save(&host.path(RUNTIME_STATE_PATH));
+        let ctrl = host.controller(
+            host.config(GfxMode::Hybrid),
+            Arc::new(RecordingCommandRunner::new()),
+        );
+
+        assert_eq!(ctrl.get_pending_mode().await, GfxMode::AsusMuxDgpu);
+
+        ctrl.recover_interrupted_switch().await;
+        assert_eq!(ctrl.get_pending_mode().await, GfxMode::None);
+        assert!(matches!(
+            ctrl.get_pending_user_action().await,
+            UserActionRequired::Nothing
+        ));
+        let state = saved_state(&host);
+        assert_eq!(state.pending_mode, None);
+        assert!(state.pending_action.is_none());
+        // Nothing was in flight, so there is nothing to record as interrupted
+        assert_eq!(ctrl.get_switch_outcome().await, SwitchOutcome::None);
+        assert!(ctrl.get_history(0).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,212 @@
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use crate::{
+        actions::{StagedAction, SwitchEvent, SwitchOutcome, SwitchStepStatus, SwitchTransaction},
+        command::{CommandOutput, RecordingCommandRunner},
+        controller::CtrlGraphics,
+        pci_device::GfxMode,
+        tests::fake_host::FakeHost,
+    };
+
+    const ALL: [StagedAction; 31] = [
+        StagedAction::WaitLogout,
+        StagedAction::StopDisplayManager,
+        StagedAction::StartDisplayManager,
+        StagedAction::NoLogind,
+        StagedAction::SendDetachEvent,
+        StagedAction::LoadGpuDrivers,
+        StagedAction::UnloadGpuDrivers,
+        StagedAction::KillNvidia,
+        StagedAction::KillAmd,
+        StagedAction::EnableNvidiaPersistenced,
+        StagedAction::DisableNvidiaPersistenced,
+        StagedAction::EnableNvidiaPowerd,
+        StagedAction::DisableNvidiaPowerd,
+        StagedAction::LoadVfioDrivers,
+        StagedAction::UnloadVfioDrivers,
+        StagedAction::DevTreeManaged,
+        StagedAction::RescanPci,
+        StagedAction::UnbindRemoveGpu,
+        StagedAction::UnbindGpu,
+        StagedAction::HotplugUnplug,
+        StagedAction::HotplugPlug,
+        StagedAction::AsusDgpuDisable,
+        StagedAction::AsusDgpuEnable,
+        StagedAction::AsusEgpuDisable,
+        StagedAction::AsusEgpuEnable,
+        StagedAction::AsusMuxIgpu,
+        StagedAction::AsusMuxDgpu,
+        StagedAction::WriteModprobeConf,
+        StagedAction::CheckVulkanIcd,
+        StagedAction::NotNvidia,
+        StagedAction::None,
+    ];
+
+    #[test]
+    fn inverse_pairs() {
+        for action in ALL {
+            if let Some(inverse) = action.inverse() {
+                // Removal is undone by a rescan, but a rescan isn't undone
+                if action == StagedAction::UnbindRemoveGpu {
+                    assert_eq!(inverse, StagedAction::RescanPci);
+                    continue;
+                }
+                assert_eq!(
+                    inverse.inverse(),
+                    Some(action),
+                    "{action:?} and {inverse:?} should undo each other"
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn rollback_undoes_completed_steps_in_reverse() {
+        let host = FakeHost::nvidia_laptop();
+        let runner = Arc::new(RecordingCommandRunner::new());
+        let mut dgpu = host.dgpu(runner.clone());
+        let ctx = host.context(GfxMode::Hybrid);
+
+        let mut transaction = SwitchTransaction::new(GfxMode::Integrated, GfxMode::Hybrid);
+        for action in [
+            StagedAction::WriteModprobeConf,
+            StagedAction::EnableNvidiaPersistenced,
+            StagedAction::EnableNvidiaPowerd,
+        ] {
+            transaction.perform(action, &mut dgpu, &ctx).await.unwrap();
+        }
+        assert!(host
+            .read("/etc/modprobe.d/supergfxd.conf")
+            .contains("alias nouveau off"));
+        runner.clear_calls();
+
+        assert_eq!(
+            transaction.rollback(&mut dgpu, &ctx).await,
+            SwitchOutcome::RolledBack
+        );
+        assert_eq!(
+            runner.calls(),
+            [
+                "systemctl stop nvidia-powerd.service",
+                "systemctl stop nvidia-persistenced.service",
+            ]
+        );
+        // The conf is rewritten for the mode switched from
+        assert!(host
+            .read("/etc/modprobe.d/supergfxd.conf")
+            .contains("blacklist nvidia"));
+    }
+
+    /// Collect events until the switch result
+    async fn switch_events(ctrl: &mut CtrlGraphics, mode: GfxMode) -> Vec<SwitchEvent> {
+        let mut rx = ctrl.subscribe_switch_events();
+        ctrl.set_gfx_mode(mode, None).await.unwrap();
+        let mut events = Vec::new();
+        loop {
+            let event = tokio::time::timeout(Duration::from_secs(10), rx.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            let done = matches!(event, SwitchEvent::Result { .. });
+            events.push(event);
+            if done {
+                return events;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_switch_is_rolled_back() {
+        let host = FakeHost::nvidia_laptop();
+        let runner = Arc::new(RecordingCommandRunner::new());
+        runner.reply(
+            "rmmod nvidia_uvm",
+            CommandOutput::failed(1, "rmmod: ERROR: Module nvidia_uvm is in use\n"),
+        );
+        let mut ctrl = host.controller(host.config(GfxMode::Hybrid), runner.clone());
+
+        let events = switch_events(&mut ctrl, GfxMode::Integrated).await;
+        assert_eq!(
+            events[events.len() - 2..],
+            [
+                SwitchEvent::Progress {
+                    step_index: 4,
+                    total_steps: 9,
+                    action: StagedAction::UnloadGpuDrivers,
+                    status: SwitchStepStatus::Failed,
+                },
+                SwitchEvent::Result {
+                    success: false,
+                    mode: GfxMode::Hybrid,
+                    error_text: "Modprobe error: rmmod nvidia_uvm failed: \"rmmod: ERROR: Module nvidia_uvm is in use\\n\"".to_string(),
+                    outcome: SwitchOutcome::RolledBack,
+                },
+            ]
+        );
+
+        assert_eq!(ctrl.get_switch_outcome().await, SwitchOutcome::RolledBack);
+        assert_eq!(ctrl.get_pending_mode().await, GfxMode::None);
+        assert_eq!(ctrl.config.lock().await.mode, GfxMode::Hybrid);
+
+        let calls = runner.calls();
+        let undo = calls
+            .iter()
+            .skip_while(|c| c.starts_with("systemctl stop") || c.starts_with("rmmod"))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            undo,
+            [
+                "systemctl start nvidia-powerd.service",
+                "systemctl start nvidia-persistenced.service",
+            ]
+        );
+        // The dGPU was never removed
+        assert!(host
+            .read("/sys/bus/pci/devices/0000:01:00.0/remove")
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn successful_switch_is_committed() {
+        let host = FakeHost::nvidia_laptop();
+        let runner = Arc::new(RecordingCommandRunner::new());
+        let mut ctrl = host.controller(host.config(GfxMode::Hybrid), runner.clone());
+
+        let events = switch_events(&mut ctrl, GfxMode::Integrated).await;
+        // A start and finish for each step, then the result
+        assert_eq!(events.len(), 9 * 2 + 1);
+        assert_eq!(
+            events[0],
+            SwitchEvent::Progress {
+                step_index: 0,
+                total_steps: 9,
+                action: StagedAction::DisableNvidiaPersistenced,
+                status: SwitchStepStatus::Started,
+            }
+        );
+        assert_eq!(
+            events[17],
+            SwitchEvent::Progress {
+                step_index: 8,
+                total_steps: 9,
+                action: StagedAction::DevTreeManaged,
+                status: SwitchStepStatus::Finished,
+            }
+        );
+        assert_eq!(
+            events[18],
+            SwitchEvent::Result {
+                success: true,
+                mode: GfxMode::Integrated,
+                error_text: String::new(),
+                outcome: SwitchOutcome::Committed,
+            }
+        );
+
+        assert_eq!(ctrl.get_switch_outcome().await, SwitchOutcome::Committed);
+        assert_eq!(ctrl.config.lock().await.mode, GfxMode::Integrated);
+        assert!(host.read("/etc/supergfxd.conf").contains("Integrated"));
+    }
+}
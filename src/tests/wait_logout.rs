@@ -0,0 +1,152 @@
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::{Duration, Instant},
+    };
+
+    use futures_util::{future::BoxFuture, FutureExt};
+    use tokio::sync::mpsc;
+
+    use crate::{
+        actions::{wait_for_sessions, BlockingSession, SessionWatch, StagedAction, SwitchEvent},
+        command::RecordingCommandRunner,
+        error::GfxError,
+        pci_device::GfxMode,
+        tests::fake_host::FakeHost,
+    };
+
+    fn session(id: &str) -> BlockingSession {
+        BlockingSession {
+            id: id.to_string(),
+            user: "luke".to_string(),
+            seat: "seat0".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn no_logind_does_not_wait() {
+        let host = FakeHost::nvidia_laptop();
+        let mut dgpu = host.dgpu(Arc::new(RecordingCommandRunner::new()));
+        let mut ctx = host.context(GfxMode::Hybrid);
+        ctx.config.no_logind = true;
+
+        // Would fail trying to reach logind if it didn't return early
+        StagedAction::WaitLogout
+            .perform(GfxMode::Integrated, &mut dgpu, &ctx)
+            .await
+            .unwrap();
+    }
+
+    /// Sessions change only when the test sends the new list
+    struct FakeSessions {
+        current: Vec<BlockingSession>,
+        changes: mpsc::UnboundedReceiver<Vec<BlockingSession>>,
+        evaluations: Arc<AtomicUsize>,
+    }
+
+    impl FakeSessions {
+        fn new(
+            current: Vec<BlockingSession>,
+        ) -> (
+            Self,
+            mpsc::UnboundedSender<Vec<BlockingSession>>,
+            Arc<AtomicUsize>,
+        ) {
+            let (tx, changes) = mpsc::unbounded_channel();
+            let evaluations = Arc::new(AtomicUsize::new(0));
+            let watch = Self {
+                current,
+                changes,
+                evaluations: evaluations.clone(),
+            };
+            (watch, tx, evaluations)
+        }
+    }
+
+    impl SessionWatch for FakeSessions {
+        fn blocking_sessions(&mut self) -> BoxFuture<'_, Result<Vec<BlockingSession>, GfxError>> {
+            self.evaluations.fetch_add(1, Ordering::SeqCst);
+            let current = self.current.clone();
+            async move { Ok(current) }.boxed()
+        }
+
+        fn changed(&mut self) -> BoxFuture<'_, ()> {
+            async move {
+                match self.changes.recv().await {
+                    Some(sessions) => self.current = sessions,
+                    None => std::future::pending().await,
+                }
+            }
+            .boxed()
+        }
+    }
+
+    #[tokio::test]
+    async fn timeout_is_honoured() {
+        let host = FakeHost::nvidia_laptop();
+        let mut ctx = host.context(GfxMode::Hybrid);
+        ctx.config.logout_timeout_s = 1;
+        let mut rx = ctx.events.subscribe();
+        let (mut watch, _tx, evaluations) = FakeSessions::new(vec![session("c2")]);
+
+        let start = Instant::now();
+        let res = wait_for_sessions(&ctx, &mut watch).await;
+        assert!(matches!(res, Err(GfxError::LogoutTimeout(1))));
+        assert!(start.elapsed() >= Duration::from_secs(1));
+        assert!(start.elapsed() < Duration::from_secs(3));
+        // Nothing changed so the sessions are never listed again
+        assert_eq!(evaluations.load(Ordering::SeqCst), 1);
+
+        assert_eq!(
+            rx.try_recv().unwrap(),
+            SwitchEvent::WaitingForLogout {
+                sessions: vec![session("c2")]
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn infinite_timeout_waits_for_logout() {
+        let host = FakeHost::nvidia_laptop();
+        let mut ctx = host.context(GfxMode::Hybrid);
+        ctx.config.logout_timeout_s = 0;
+        let (mut watch, tx, evaluations) = FakeSessions::new(vec![session("c2"), session("c3")]);
+
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(1500)).await;
+            tx.send(vec![session("c3")]).unwrap();
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            tx.send(Vec::new()).unwrap();
+            // Keep the channel open until the wait is over
+            tokio::time::sleep(Duration::from_secs(5)).await;
+        });
+        tokio::time::timeout(Duration::from_secs(5), wait_for_sessions(&ctx, &mut watch))
+            .await
+            .unwrap()
+            .unwrap();
+        // Once at the start and once per change
+        assert_eq!(evaluations.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn stop_ends_wait() {
+        let host = FakeHost::nvidia_laptop();
+        let mut ctx = host.context(GfxMode::Hybrid);
+        ctx.config.logout_timeout_s = 0;
+        let (mut watch, _tx, _) = FakeSessions::new(vec![session("c2")]);
+
+        let stop = ctx.stop.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            stop.stop();
+        });
+        let res = tokio::time::timeout(Duration::from_secs(5), wait_for_sessions(&ctx, &mut watch))
+            .await
+            .unwrap();
+        assert!(matches!(res, Err(GfxError::SwitchStopped)));
+    }
+}
@@ -1,11 +1,15 @@
 use ::zbus::interface;
 use log::{error, info, warn};
-use zbus::{object_server::SignalEmitter, zvariant::ObjectPath};
+use zbus::{message::Header, object_server::SignalEmitter, zvariant::ObjectPath, Connection};
 
 use crate::{
-    actions::UserActionRequired,
-    config::GfxConfigDbus,
-    pci_device::{GfxMode, GfxPower},
+    actions::{BlockingSession, StagedAction, SwitchOutcome, SwitchStepStatus, UserActionRequired},
+    config::{ConfigSource, GfxConfigDbus},
+    diagnostics::Diagnostics,
+    error::GfxError,
+    history::HistoryEntry,
+    pci_device::{GfxMode, GfxPower, HotplugType},
+    polkit::{actions_for_config, actions_for_mode, Caller, ACTION_SET_MODE},
     special_asus::{asus_gpu_mux_mode, AsusGpuMuxMode},
     DBUS_IFACE_PATH, VERSION,
 };
@@ -41,23 +45,24 @@ impl CtrlGraphics {
     /// # assert_eq!(pci_device::GfxMode::None as u8, GfxMode::None as u8);
     /// ```
     async fn mode(&self) -> zbus::fdo::Result<GfxMode> {
-        if let Ok(state) = asus_gpu_mux_mode() {
+        if let Ok(state) = asus_gpu_mux_mode(&self.paths) {
             if state == AsusGpuMuxMode::Discreet {
                 return Ok(GfxMode::AsusMuxDgpu);
             }
         }
         let config = self.config.lock().await;
-        self.get_gfx_mode(&config).map_err(|err| {
-            error!("{}", err);
-            zbus::fdo::Error::Failed(format!("GFX fail: {}", err))
-        })
+        Ok(Self::get_gfx_mode(&config, &*self.runtime.lock().await))
     }
 
     /// Get list of supported modes
     async fn supported(&self) -> zbus::fdo::Result<Vec<GfxMode>> {
-        if let Ok(state) = asus_gpu_mux_mode() {
+        if let Ok(state) = asus_gpu_mux_mode(&self.paths) {
             if state == AsusGpuMuxMode::Discreet {
-                return Ok(vec![GfxMode::AsusMuxDgpu, GfxMode::Integrated, GfxMode::Hybrid]);
+                return Ok(vec![
+                    GfxMode::AsusMuxDgpu,
+                    GfxMode::Integrated,
+                    GfxMode::Hybrid,
+                ]);
             }
         }
         Ok(self.get_supported_modes().await)
@@ -77,7 +82,7 @@ impl CtrlGraphics {
     ///     Unknown,
     /// }
     async fn power(&self) -> zbus::fdo::Result<GfxPower> {
-        if let Ok(state) = asus_gpu_mux_mode() {
+        if let Ok(state) = asus_gpu_mux_mode(&self.paths) {
             if state == AsusGpuMuxMode::Discreet {
                 return Ok(GfxPower::AsusMuxDiscreet);
             }
@@ -131,23 +136,113 @@ impl CtrlGraphics {
     async fn set_mode(
         &mut self,
         #[zbus(signal_context)] ctxt: SignalEmitter<'_>,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: Header<'_>,
         mode: GfxMode,
     ) -> zbus::fdo::Result<UserActionRequired> {
-        info!("Switching gfx mode to {mode}");
-        let msg = self.set_gfx_mode(mode).await.map_err(|err| {
+        let caller = caller(connection, &header).await;
+        self.authorize(&caller, &actions_for_mode(mode))
+            .await
+            .map_err(auth_error)?;
+        info!(
+            "Switching gfx mode to {mode}, requested by uid {:?}",
+            caller.uid
+        );
+        let msg = self.set_gfx_mode(mode, caller.uid).await.map_err(|err| {
             error!("{}", err);
             zbus::fdo::Error::Failed(format!("GFX fail: {}", err))
         })?;
 
+        // `NotifyGfx` and the `Mode` property are sent once the switch has actually completed
+        self.pending_properties_changed(&ctxt)
+            .await
+            .unwrap_or_else(|err| warn!("{}", err));
         Self::notify_action(&ctxt, &msg)
             .await
             .unwrap_or_else(|err| warn!("{}", err));
 
-        Self::notify_gfx(&ctxt, &mode)
+        Ok(msg)
+    }
+
+    /// Preview a mode change without doing it. Returns the action the user would be
+    /// required to take and every step the switch would perform, in order. The step
+    /// list is empty if the switch can't be done directly (see the user action).
+    async fn plan_mode(
+        &self,
+        mode: GfxMode,
+    ) -> zbus::fdo::Result<(UserActionRequired, Vec<StagedAction>)> {
+        self.plan_gfx_mode(mode).await.map_err(|err| {
+            error!("{}", err);
+            zbus::fdo::Error::Failed(format!("GFX fail: {}", err))
+        })
+    }
+
+    /// Get how the last mode switch ended:
+    /// ```rust
+    /// enum SwitchOutcome {
+    ///     Committed,
+    ///     RolledBack,
+    ///     RollbackFailed,
+    ///     Cancelled,
+    ///     None,
+    ///     Interrupted,
+    /// }
+    /// # use supergfxctl::actions;
+    /// # assert_eq!(actions::SwitchOutcome::None as u8, 4);
+    /// # assert_eq!(actions::SwitchOutcome::Committed as u8, SwitchOutcome::Committed as u8);
+    /// # assert_eq!(actions::SwitchOutcome::RolledBack as u8, SwitchOutcome::RolledBack as u8);
+    /// # assert_eq!(actions::SwitchOutcome::RollbackFailed as u8, SwitchOutcome::RollbackFailed as u8);
+    /// # assert_eq!(actions::SwitchOutcome::Cancelled as u8, SwitchOutcome::Cancelled as u8);
+    /// # assert_eq!(actions::SwitchOutcome::Interrupted as u8, SwitchOutcome::Interrupted as u8);
+    /// ```
+    async fn last_switch_outcome(&self) -> zbus::fdo::Result<SwitchOutcome> {
+        Ok(self.get_switch_outcome().await)
+    }
+
+    /// Cancel the pending mode change. This only works while the switch is waiting (e.g,
+    /// for logout) and has not yet started changing the system. Returns the mode that
+    /// was cancelled.
+    async fn cancel_pending_mode(
+        &self,
+        #[zbus(signal_context)] ctxt: SignalEmitter<'_>,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: Header<'_>,
+    ) -> zbus::fdo::Result<GfxMode> {
+        let caller = caller(connection, &header).await;
+        self.authorize(&caller, &[ACTION_SET_MODE])
+            .await
+            .map_err(auth_error)?;
+        let mode = self.cancel_gfx_mode().await.map_err(|err| {
+            warn!("{}", err);
+            zbus::fdo::Error::Failed(format!("GFX fail: {}", err))
+        })?;
+        info!("Cancelled switch to {mode}");
+
+        self.pending_properties_changed(&ctxt)
             .await
             .unwrap_or_else(|err| warn!("{}", err));
 
-        Ok(msg)
+        Self::notify_switch_cancelled(&ctxt, &mode)
+            .await
+            .unwrap_or_else(|err| warn!("{}", err));
+
+        Ok(mode)
+    }
+
+    /// Get up to `limit` of the most recent mode switches, newest first. A `limit` of 0
+    /// gets the whole history. Each entry is:
+    /// `(timestamp, from, to, uid, user_action, outcome, [(action, success, error, duration_ms)])`
+    async fn history(&self, limit: u32) -> zbus::fdo::Result<Vec<HistoryEntry>> {
+        self.get_history(limit as usize).map_err(|err| {
+            error!("{}", err);
+            zbus::fdo::Error::Failed(format!("GFX fail: {}", err))
+        })
+    }
+
+    /// Get the detected devices, ASUS and kernel cmdline switches, modprobe and Vulkan
+    /// ICD state, loaded GPU modules and config, for a bug report
+    async fn diagnostics(&self) -> zbus::fdo::Result<Diagnostics> {
+        Ok(self.get_diagnostics().await)
     }
 
     /// Get the `String` name of the pending mode change if any
@@ -160,51 +255,163 @@ impl CtrlGraphics {
         Ok(self.get_pending_user_action().await)
     }
 
+    /// The current graphics mode, as for `Mode()`
+    #[zbus(property, name = "Mode")]
+    async fn mode_property(&self) -> zbus::fdo::Result<GfxMode> {
+        self.mode().await
+    }
+
+    /// The mode of the in-progress switch, `None` if there is no switch
+    #[zbus(property, name = "PendingMode")]
+    async fn pending_mode_property(&self) -> GfxMode {
+        self.get_pending_mode().await
+    }
+
+    /// The action the user must take for the in-progress switch to complete
+    #[zbus(property, name = "PendingUserAction")]
+    async fn pending_user_action_property(&self) -> UserActionRequired {
+        self.get_pending_user_action().await
+    }
+
+    /// The dGPU power status, as for `Power()`
+    #[zbus(property, name = "Power")]
+    async fn power_property(&self) -> zbus::fdo::Result<GfxPower> {
+        self.power().await
+    }
+
+    #[zbus(property)]
+    async fn vfio_enable(&self) -> bool {
+        self.config.lock().await.vfio_enable
+    }
+
+    /// Setting a config property is checked and saved as with `SetConfig`
+    #[zbus(property)]
+    async fn set_vfio_enable(
+        &mut self,
+        #[zbus(signal_context)] ctxt: SignalEmitter<'_>,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: Option<Header<'_>>,
+        value: bool,
+    ) -> zbus::fdo::Result<()> {
+        self.update_config(&ctxt, connection, header, |c| c.vfio_enable = value)
+            .await
+    }
+
+    #[zbus(property)]
+    async fn always_reboot(&self) -> bool {
+        self.config.lock().await.always_reboot
+    }
+
+    #[zbus(property)]
+    async fn set_always_reboot(
+        &mut self,
+        #[zbus(signal_context)] ctxt: SignalEmitter<'_>,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: Option<Header<'_>>,
+        value: bool,
+    ) -> zbus::fdo::Result<()> {
+        self.update_config(&ctxt, connection, header, |c| c.always_reboot = value)
+            .await
+    }
+
+    #[zbus(property)]
+    async fn no_logind(&self) -> bool {
+        self.config.lock().await.no_logind
+    }
+
+    #[zbus(property)]
+    async fn set_no_logind(
+        &mut self,
+        #[zbus(signal_context)] ctxt: SignalEmitter<'_>,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: Option<Header<'_>>,
+        value: bool,
+    ) -> zbus::fdo::Result<()> {
+        self.update_config(&ctxt, connection, header, |c| c.no_logind = value)
+            .await
+    }
+
+    /// Seconds to wait for graphical sessions to log out, 0 is infinite
+    #[zbus(property)]
+    async fn logout_timeout(&self) -> u64 {
+        self.config.lock().await.logout_timeout_s
+    }
+
+    #[zbus(property)]
+    async fn set_logout_timeout(
+        &mut self,
+        #[zbus(signal_context)] ctxt: SignalEmitter<'_>,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: Option<Header<'_>>,
+        value: u64,
+    ) -> zbus::fdo::Result<()> {
+        self.update_config(&ctxt, connection, header, |c| c.logout_timeout_s = value)
+            .await
+    }
+
+    #[zbus(property)]
+    async fn hotplug_type(&self) -> HotplugType {
+        self.config.lock().await.hotplug_type
+    }
+
+    #[zbus(property)]
+    async fn set_hotplug_type(
+        &mut self,
+        #[zbus(signal_context)] ctxt: SignalEmitter<'_>,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: Option<Header<'_>>,
+        value: HotplugType,
+    ) -> zbus::fdo::Result<()> {
+        self.update_config(&ctxt, connection, header, |c| c.hotplug_type = value)
+            .await
+    }
+
     /// Get the base config, args in order are:
-    /// pub mode: GfxMode,
+    /// mode: GfxMode,
     /// vfio_enable: bool,
     /// vfio_save: bool,
-    /// compute_save: bool,
     /// always_reboot: bool,
     /// no_logind: bool,
     /// logout_timeout_s: u64,
+    /// hotplug_type: HotplugType,
     async fn config(&self) -> zbus::fdo::Result<GfxConfigDbus> {
         let cfg = self.config.lock().await;
         let cfg = GfxConfigDbus::from(&*cfg);
         Ok(cfg)
     }
 
-    /// Set the base config, args in order are:
-    /// pub mode: GfxMode,
-    /// vfio_enable: bool,
-    /// vfio_save: bool,
-    /// compute_save: bool,
-    /// always_reboot: bool,
-    /// no_logind: bool,
-    /// logout_timeout_s: u64,
+    /// Get each config value as (key, value, origin). The origin is the path of the
+    /// state file or drop-in file that set the value, or `default`.
+    async fn config_sources(&self) -> zbus::fdo::Result<Vec<ConfigSource>> {
+        Ok(self.get_config_sources().await)
+    }
+
+    /// Set the base config, args are as for `Config`. The config is checked first,
+    /// e.g `Vfio` mode requires `vfio_enable`, and `Asus` hotplug requires the ASUS
+    /// `dgpu_disable`. If the mode differs a switch to it is started.
     async fn set_config(
         &mut self,
         #[zbus(signal_context)] ctxt: SignalEmitter<'_>,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: Header<'_>,
         config: GfxConfigDbus,
     ) -> zbus::fdo::Result<()> {
-        let do_mode_change;
-        let mode;
-
-        {
-            let mut cfg = self.config.lock().await;
-
-            do_mode_change = cfg.mode == config.mode;
-            mode = cfg.mode;
-
-            cfg.vfio_enable = config.vfio_enable;
-            cfg.vfio_save = config.vfio_save;
-            cfg.always_reboot = config.always_reboot;
-            cfg.no_logind = config.no_logind;
-            cfg.logout_timeout_s = config.logout_timeout_s;
-        }
+        let before = GfxConfigDbus::from(&*self.config.lock().await);
+        let action = self
+            .apply_config(&ctxt, connection, &header, config)
+            .await?;
 
-        if do_mode_change {
-            self.set_mode(ctxt, mode).await.ok();
+        let after = GfxConfigDbus::from(&*self.config.lock().await);
+        self.config_properties_changed(&ctxt, &before, &after)
+            .await
+            .unwrap_or_else(|err| warn!("{}", err));
+        if let Some(action) = action {
+            self.pending_properties_changed(&ctxt)
+                .await
+                .unwrap_or_else(|err| warn!("{}", err));
+            Self::notify_action(&ctxt, &action)
+                .await
+                .unwrap_or_else(|err| warn!("{}", err));
         }
 
         Ok(())
@@ -228,7 +435,80 @@ impl CtrlGraphics {
 
     /// Recieve a notification if the graphics mode changes and to which mode
     #[zbus(signal)]
-    async fn notify_gfx(signal_ctxt: &SignalEmitter<'_>, vendor: &GfxMode) -> zbus::Result<()> {}
+    pub async fn notify_gfx(signal_ctxt: &SignalEmitter<'_>, vendor: &GfxMode) -> zbus::Result<()> {
+    }
+
+    /// Recieve a notification when a mode switch ends, with how it ended (see
+    /// `LastSwitchOutcome`), whether it succeeded, the mode now in use and the error if
+    /// it failed
+    #[zbus(signal)]
+    pub async fn notify_switch_outcome(
+        signal_ctxt: &SignalEmitter<'_>,
+        outcome: &SwitchOutcome,
+        success: bool,
+        mode: &GfxMode,
+        error_text: &str,
+    ) -> zbus::Result<()> {
+    }
+
+    /// Recieve a notification when a pending mode change is cancelled, with the mode
+    /// that was cancelled
+    #[zbus(signal)]
+    async fn notify_switch_cancelled(
+        signal_ctxt: &SignalEmitter<'_>,
+        mode: &GfxMode,
+    ) -> zbus::Result<()> {
+    }
+
+    /// Recieve a notification as each step of a mode switch starts and ends. The step
+    /// index counts from 0, the status is:
+    /// ```rust
+    /// enum SwitchStepStatus {
+    ///     Started,
+    ///     Finished,
+    ///     Failed,
+    /// }
+    /// # use supergfxctl::actions;
+    /// # assert_eq!(actions::SwitchStepStatus::Started as u8, SwitchStepStatus::Started as u8);
+    /// # assert_eq!(actions::SwitchStepStatus::Finished as u8, SwitchStepStatus::Finished as u8);
+    /// # assert_eq!(actions::SwitchStepStatus::Failed as u8, SwitchStepStatus::Failed as u8);
+    /// ```
+    #[zbus(signal)]
+    pub async fn notify_switch_progress(
+        signal_ctxt: &SignalEmitter<'_>,
+        step_index: u32,
+        total_steps: u32,
+        action: &StagedAction,
+        status: &SwitchStepStatus,
+    ) -> zbus::Result<()> {
+    }
+
+    /// Recieve a notification every few seconds while a mode switch is waiting for
+    /// graphical sessions to log out, listing the sessions as (id, user, seat)
+    #[zbus(signal)]
+    pub async fn notify_waiting_for_logout(
+        signal_ctxt: &SignalEmitter<'_>,
+        sessions: &[BlockingSession],
+    ) -> zbus::Result<()> {
+    }
+
+    /// Recieve a notification when the config is changed with `SetConfig` or by
+    /// editing the config file, with the new config
+    #[zbus(signal)]
+    pub async fn notify_config(
+        signal_ctxt: &SignalEmitter<'_>,
+        config: &GfxConfigDbus,
+    ) -> zbus::Result<()> {
+    }
+
+    /// Recieve a notification when the config file was corrupt on start, and was
+    /// restored from the backup of the last good config or reset to the defaults
+    #[zbus(signal)]
+    pub async fn notify_config_warning(
+        signal_ctxt: &SignalEmitter<'_>,
+        warning: &str,
+    ) -> zbus::Result<()> {
+    }
 
     /// Recieve a notification on required action if mode changes
     #[zbus(signal)]
@@ -240,6 +520,129 @@ impl CtrlGraphics {
 }
 
 impl CtrlGraphics {
+    /// Check the caller may make the change, then validate, save and apply `config`,
+    /// sending `NotifyConfig`. Returns the user action if a mode switch was started.
+    async fn apply_config(
+        &mut self,
+        ctxt: &SignalEmitter<'_>,
+        connection: &Connection,
+        header: &Header<'_>,
+        config: GfxConfigDbus,
+    ) -> zbus::fdo::Result<Option<UserActionRequired>> {
+        let caller = caller(connection, header).await;
+        let (enabling_vfio, mode_change) = {
+            let cfg = self.config.lock().await;
+            (
+                config.vfio_enable && !cfg.vfio_enable,
+                config.mode != cfg.mode,
+            )
+        };
+        let mut actions = actions_for_config(enabling_vfio);
+        if mode_change {
+            actions.extend(actions_for_mode(config.mode));
+        }
+        self.authorize(&caller, &actions)
+            .await
+            .map_err(auth_error)?;
+
+        let action = self
+            .set_gfx_config(config, caller.uid)
+            .await
+            .map_err(|err| {
+                error!("{}", err);
+                zbus::fdo::Error::Failed(format!("GFX fail: {}", err))
+            })?;
+
+        let config = GfxConfigDbus::from(&*self.config.lock().await);
+        Self::notify_config(ctxt, &config)
+            .await
+            .unwrap_or_else(|err| warn!("{}", err));
+        Ok(action)
+    }
+
+    /// Change one field of the config for a property setter. The setter itself sends
+    /// `PropertiesChanged`.
+    async fn update_config(
+        &mut self,
+        ctxt: &SignalEmitter<'_>,
+        connection: &Connection,
+        header: Option<Header<'_>>,
+        update: impl FnOnce(&mut GfxConfigDbus),
+    ) -> zbus::fdo::Result<()> {
+        let header = header.ok_or_else(|| {
+            zbus::fdo::Error::AccessDenied("Config properties can only be set over dbus".into())
+        })?;
+        let mut config = GfxConfigDbus::from(&*self.config.lock().await);
+        update(&mut config);
+        self.apply_config(ctxt, connection, &header, config)
+            .await
+            .map(|_| ())
+    }
+
+    /// Send `PropertiesChanged` for each config property that differs
+    async fn config_properties_changed(
+        &self,
+        ctxt: &SignalEmitter<'_>,
+        before: &GfxConfigDbus,
+        after: &GfxConfigDbus,
+    ) -> zbus::Result<()> {
+        if before.vfio_enable != after.vfio_enable {
+            self.vfio_enable_changed(ctxt).await?;
+        }
+        if before.always_reboot != after.always_reboot {
+            self.always_reboot_changed(ctxt).await?;
+        }
+        if before.no_logind != after.no_logind {
+            self.no_logind_changed(ctxt).await?;
+        }
+        if before.logout_timeout_s != after.logout_timeout_s {
+            self.logout_timeout_changed(ctxt).await?;
+        }
+        if before.hotplug_type != after.hotplug_type {
+            self.hotplug_type_changed(ctxt).await?;
+        }
+        Ok(())
+    }
+
+    /// Reload the config file after it was edited or on SIGHUP, sending `NotifyConfig`
+    /// and `PropertiesChanged` if anything changed. An invalid config is logged and
+    /// ignored.
+    pub async fn config_file_changed(&mut self, ctxt: &SignalEmitter<'_>) {
+        let before = GfxConfigDbus::from(&*self.config.lock().await);
+        let after = match self.reload_config().await {
+            Ok(Some(after)) => after,
+            Ok(None) => return,
+            Err(err) => {
+                error!("Not reloading the edited config: {err}");
+                return;
+            }
+        };
+        self.config_properties_changed(ctxt, &before, &after)
+            .await
+            .unwrap_or_else(|err| warn!("{}", err));
+        Self::notify_config(ctxt, &after)
+            .await
+            .unwrap_or_else(|err| warn!("{}", err));
+    }
+
+    /// Send `PropertiesChanged` for `PendingMode` and `PendingUserAction`
+    pub async fn pending_properties_changed(&self, ctxt: &SignalEmitter<'_>) -> zbus::Result<()> {
+        self.pending_mode_changed(ctxt).await?;
+        self.pending_user_action_changed(ctxt).await
+    }
+
+    /// Send `PropertiesChanged` for `Mode`, `PendingMode` and `PendingUserAction` once
+    /// a switch has ended
+    pub async fn switch_properties_changed(&self, ctxt: &SignalEmitter<'_>) -> zbus::Result<()> {
+        self.mode_changed(ctxt).await?;
+        self.pending_properties_changed(ctxt).await
+    }
+
+    /// Send `PropertiesChanged` for `Power`
+    pub async fn power_properties_changed(&self, ctxt: &SignalEmitter<'_>) -> zbus::Result<()> {
+        self.power_changed(ctxt).await
+    }
+
     pub async fn add_to_server(self, server: &mut zbus::ObjectServer) {
         server
             .at(&ObjectPath::from_str_unchecked(DBUS_IFACE_PATH), self)
@@ -251,3 +654,34 @@ impl CtrlGraphics {
             .ok();
     }
 }
+
+/// Identify the process that sent a method call, asking the bus for its uid
+async fn caller(connection: &Connection, header: &Header<'_>) -> Caller {
+    let bus_name = header
+        .sender()
+        .map(|sender| sender.to_string())
+        .unwrap_or_default();
+    let uid = match zbus::fdo::DBusProxy::new(connection).await {
+        Ok(dbus) => match header.sender() {
+            Some(sender) => dbus
+                .get_connection_unix_user(sender.clone().into())
+                .await
+                .map_err(|err| warn!("Could not get the caller uid: {err}"))
+                .ok(),
+            None => None,
+        },
+        Err(err) => {
+            warn!("Could not get the caller uid: {err}");
+            None
+        }
+    };
+    Caller { bus_name, uid }
+}
+
+fn auth_error(err: GfxError) -> zbus::fdo::Error {
+    warn!("{}", err);
+    match err {
+        GfxError::NotAuthorized(_) => zbus::fdo::Error::AccessDenied(err.to_string()),
+        _ => zbus::fdo::Error::Failed(format!("GFX fail: {}", err)),
+    }
+}
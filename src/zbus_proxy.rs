@@ -24,8 +24,11 @@
 use zbus::proxy;
 
 use crate::{
-    actions::UserActionRequired,
-    pci_device::{GfxMode, GfxPower},
+    actions::{BlockingSession, StagedAction, SwitchOutcome, SwitchStepStatus, UserActionRequired},
+    config::{ConfigSource, GfxConfigDbus},
+    diagnostics::Diagnostics,
+    history::HistoryEntry,
+    pci_device::{GfxMode, GfxPower, HotplugType},
 };
 
 #[proxy(
@@ -37,28 +40,14 @@ pub trait Daemon {
     /// Version method
     fn version(&self) -> zbus::Result<String>;
 
-    /// Get the base config, args in order are:
-    /// pub mode: GfxMode,
-    /// vfio_enable: bool,
-    /// vfio_save: bool,
-    /// compute_save: bool,
-    /// always_reboot: bool,
-    /// no_logind: bool,
-    /// logout_timeout_s: u64,
-    fn config(&self) -> zbus::Result<(u32, bool, bool, bool, bool, bool, u64, bool)>;
-
-    /// Set the base config, args in order are:
-    /// pub mode: GfxMode,
-    /// vfio_enable: bool,
-    /// vfio_save: bool,
-    /// compute_save: bool,
-    /// always_reboot: bool,
-    /// no_logind: bool,
-    /// logout_timeout_s: u64,
-    fn set_config(
-        &self,
-        config: &(u32, bool, bool, bool, bool, bool, u64, bool),
-    ) -> zbus::Result<()>;
+    /// Get the base config
+    fn config(&self) -> zbus::Result<GfxConfigDbus>;
+
+    /// Get each config value and the file it came from
+    fn config_sources(&self) -> zbus::Result<Vec<ConfigSource>>;
+
+    /// Set the base config, starting a mode switch if the mode differs
+    fn set_config(&self, config: &GfxConfigDbus) -> zbus::Result<()>;
 
     /// Get the current power status
     fn power(&self) -> zbus::Result<GfxPower>;
@@ -66,6 +55,22 @@ pub trait Daemon {
     /// Set the graphics mode. Returns action required.
     fn set_mode(&self, mode: &GfxMode) -> zbus::Result<UserActionRequired>;
 
+    /// Preview a mode change. Returns the action required and the steps it would perform.
+    fn plan_mode(&self, mode: &GfxMode) -> zbus::Result<(UserActionRequired, Vec<StagedAction>)>;
+
+    /// Get how the last mode switch ended
+    fn last_switch_outcome(&self) -> zbus::Result<SwitchOutcome>;
+
+    /// Cancel the pending mode change if it has not started changing the system.
+    /// Returns the mode that was cancelled.
+    fn cancel_pending_mode(&self) -> zbus::Result<GfxMode>;
+
+    /// Get up to `limit` of the most recent mode switches, newest first. 0 gets all.
+    fn history(&self, limit: u32) -> zbus::Result<Vec<HistoryEntry>>;
+
+    /// Get the device, system and config state for a bug report
+    fn diagnostics(&self) -> zbus::Result<Diagnostics>;
+
     /// Get the `String` name of the pending mode change if any
     fn pending_mode(&self) -> zbus::Result<GfxMode>;
 
@@ -81,10 +86,88 @@ pub trait Daemon {
     /// Get the vendor name of the dGPU
     fn vendor(&self) -> zbus::Result<String>;
 
+    /// The current graphics mode
+    #[zbus(property, name = "Mode")]
+    fn mode_property(&self) -> zbus::Result<GfxMode>;
+
+    /// The mode of the in-progress switch, `None` if there is no switch
+    #[zbus(property, name = "PendingMode")]
+    fn pending_mode_property(&self) -> zbus::Result<GfxMode>;
+
+    /// The action the user must take for the in-progress switch to complete
+    #[zbus(property, name = "PendingUserAction")]
+    fn pending_user_action_property(&self) -> zbus::Result<UserActionRequired>;
+
+    /// The dGPU power status
+    #[zbus(property, name = "Power")]
+    fn power_property(&self) -> zbus::Result<GfxPower>;
+
+    #[zbus(property)]
+    fn vfio_enable(&self) -> zbus::Result<bool>;
+    #[zbus(property)]
+    fn set_vfio_enable(&self, value: bool) -> zbus::Result<()>;
+
+    #[zbus(property)]
+    fn always_reboot(&self) -> zbus::Result<bool>;
+    #[zbus(property)]
+    fn set_always_reboot(&self, value: bool) -> zbus::Result<()>;
+
+    #[zbus(property)]
+    fn no_logind(&self) -> zbus::Result<bool>;
+    #[zbus(property)]
+    fn set_no_logind(&self, value: bool) -> zbus::Result<()>;
+
+    /// Seconds to wait for graphical sessions to log out, 0 is infinite
+    #[zbus(property)]
+    fn logout_timeout(&self) -> zbus::Result<u64>;
+    #[zbus(property)]
+    fn set_logout_timeout(&self, value: u64) -> zbus::Result<()>;
+
+    #[zbus(property)]
+    fn hotplug_type(&self) -> zbus::Result<HotplugType>;
+    #[zbus(property)]
+    fn set_hotplug_type(&self, value: HotplugType) -> zbus::Result<()>;
+
     /// Be notified when the dgpu status changes
     #[zbus(signal)]
     fn notify_gfx_status(&self, status: GfxPower) -> zbus::Result<()>;
 
+    /// Be notified when a mode switch ends
+    #[zbus(signal)]
+    fn notify_switch_outcome(
+        &self,
+        outcome: SwitchOutcome,
+        success: bool,
+        mode: GfxMode,
+        error_text: &str,
+    ) -> zbus::Result<()>;
+
+    /// Be notified when a pending mode change is cancelled
+    #[zbus(signal)]
+    fn notify_switch_cancelled(&self, mode: GfxMode) -> zbus::Result<()>;
+
+    /// Be notified as each step of a mode switch starts and ends
+    #[zbus(signal)]
+    fn notify_switch_progress(
+        &self,
+        step_index: u32,
+        total_steps: u32,
+        action: StagedAction,
+        status: SwitchStepStatus,
+    ) -> zbus::Result<()>;
+
+    /// Be notified while a mode switch waits for these sessions to log out
+    #[zbus(signal)]
+    fn notify_waiting_for_logout(&self, sessions: Vec<BlockingSession>) -> zbus::Result<()>;
+
+    /// Be notified when the config is changed
+    #[zbus(signal)]
+    fn notify_config(&self, config: GfxConfigDbus) -> zbus::Result<()>;
+
+    /// Be notified when the config file was corrupt and was restored or reset
+    #[zbus(signal)]
+    fn notify_config_warning(&self, warning: &str) -> zbus::Result<()>;
+
     /// NotifyAction signal
     #[zbus(signal)]
     fn notify_action(&self, action: UserActionRequired) -> zbus::Result<()>;